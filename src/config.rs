@@ -0,0 +1,262 @@
+//! Defaults for the command-line options from a `kobo-export.toml` file.
+//!
+//! The file is TOML with a top-level key per option, named after the option
+//! in snake case: `output_dir = "notes"` for `--output-dir notes`. Options
+//! given on the command line or through an environment variable take
+//! precedence over the file.
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgMatches, Command};
+use std::ffi::OsString;
+use toml::{Table, Value};
+
+/// Looked up in the working directory when `--config` isn't given.
+pub const CONFIG_FILE: &str = "kobo-export.toml";
+
+/// Parse a config file. Every option lives at the top level, so tables are
+/// rejected.
+pub fn parse(text: &str) -> Result<Table, String> {
+    let table: Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+    if let Some((key, _)) = table.iter().find(|(_, value)| value.is_table()) {
+        return Err(format!("`{key}` is a table, set options at the top level"));
+    }
+    Ok(table)
+}
+
+/// The command-line form of a value that isn't a switch or an array.
+fn to_arg(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(n) => Some(n.to_string()),
+        Value::Float(n) => Some(n.to_string()),
+        // Unquoted dates, e.g. `since = 2024-01-15`
+        Value::Datetime(date) => Some(date.to_string()),
+        Value::Boolean(_) | Value::Array(_) | Value::Table(_) => None,
+    }
+}
+
+/// Turn the config entries into command-line arguments to append to the real
+/// ones, leaving out options the command line or environment already set, or
+/// that conflict with one they set. Keys that aren't an option of `command`
+/// are an error.
+pub fn to_args(
+    command: &Command,
+    matches: &ArgMatches,
+    entries: &Table,
+) -> Result<Vec<OsString>, String> {
+    let set_by_user = |arg: &Arg| {
+        matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let mut args = Vec::new();
+    for (key, value) in entries {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_id() != "config")
+        else {
+            return Err(format!("unknown key `{key}`"));
+        };
+        // Conflicts can be declared on either of the two options
+        let conflicts_with_user = command
+            .get_arg_conflicts_with(arg)
+            .into_iter()
+            .any(set_by_user)
+            || command.get_arguments().any(|other| {
+                set_by_user(other)
+                    && command
+                        .get_arg_conflicts_with(other)
+                        .iter()
+                        .any(|c| c.get_id() == arg.get_id())
+            });
+        if set_by_user(arg) || conflicts_with_user {
+            continue;
+        }
+
+        let takes_value = arg.get_action().takes_values();
+        let flag = arg
+            .get_long()
+            .map(|long| OsString::from(format!("--{long}")));
+        let values = match value {
+            Value::Array(items) => items.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            match (&value, takes_value, &flag) {
+                (Value::Boolean(set), false, Some(flag)) => {
+                    if *set {
+                        args.push(flag.clone());
+                    }
+                }
+                (_, true, flag) => {
+                    let value = to_arg(&value)
+                        .ok_or_else(|| format!("`{key}` takes a string or number"))?;
+                    if let Some(flag) = flag {
+                        args.push(flag.clone());
+                    }
+                    args.push(value.into());
+                }
+                _ => return Err(format!("`{key}` takes true or false")),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ArgAction;
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("db_path"))
+            .arg(Arg::new("config").long("config"))
+            .arg(
+                Arg::new("output_dir")
+                    .long("output-dir")
+                    .default_value("out"),
+            )
+            .arg(Arg::new("stats").long("stats").action(ArgAction::SetTrue))
+            .arg(
+                Arg::new("verbose")
+                    .long("verbose")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("quiet")
+                    .long("quiet")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("verbose"),
+            )
+            .arg(
+                Arg::new("exclude_book")
+                    .long("exclude-book")
+                    .action(ArgAction::Append),
+            )
+    }
+
+    #[test]
+    fn parse_reads_strings_numbers_booleans_and_arrays() {
+        let entries = parse(
+            "# defaults\n\
+             output_dir = \"notes # not a comment\"  # a comment\n\
+             date_format = '%Y-%m-%d'\n\
+             min_highlights = 3\n\
+             stats = true\n\
+             exclude_book = [\n  \"Manual\",\n  'Sample, Preview',\n]\n",
+        )
+        .unwrap();
+        let keys: Vec<&str> = entries.keys().map(String::as_str).collect();
+        assert_eq!(
+            keys,
+            [
+                "output_dir",
+                "date_format",
+                "min_highlights",
+                "stats",
+                "exclude_book"
+            ]
+        );
+        assert_eq!(
+            entries["output_dir"].as_str(),
+            Some("notes # not a comment")
+        );
+        assert_eq!(entries["min_highlights"].as_integer(), Some(3));
+        assert_eq!(entries["stats"].as_bool(), Some(true));
+        assert_eq!(
+            entries["exclude_book"].as_array().unwrap()[1].as_str(),
+            Some("Sample, Preview")
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_toml_and_tables() {
+        assert!(parse("stats = true\noutput_dir = notes\n")
+            .unwrap_err()
+            .contains("line 2"));
+        assert_eq!(
+            parse("[export]\nstats = true\n").unwrap_err(),
+            "`export` is a table, set options at the top level"
+        );
+        assert!(parse("stats = true\nstats = false\n").is_err());
+    }
+
+    #[test]
+    fn to_args_skips_options_set_on_command_line() {
+        let command = command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["test", "--output-dir", "cli"])
+            .unwrap();
+        let entries = parse(
+            "output_dir = \"file\"\n\
+             stats = true\n\
+             exclude_book = [\"A\", \"B\"]\n\
+             db_path = \"/mnt/KoboReader.sqlite\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            to_args(&command, &matches, &entries).unwrap(),
+            [
+                "--stats",
+                "--exclude-book",
+                "A",
+                "--exclude-book",
+                "B",
+                "/mnt/KoboReader.sqlite"
+            ]
+        );
+    }
+
+    #[test]
+    fn to_args_skips_options_conflicting_with_command_line() {
+        let command = command();
+        let matches = command
+            .clone()
+            .try_get_matches_from(["test", "--verbose"])
+            .unwrap();
+        let entries = parse("quiet = true\nstats = true\n").unwrap();
+        assert_eq!(to_args(&command, &matches, &entries).unwrap(), ["--stats"]);
+
+        // The conflict is declared on quiet, not on verbose
+        let matches = command
+            .clone()
+            .try_get_matches_from(["test", "--quiet"])
+            .unwrap();
+        let entries = parse("verbose = true\n").unwrap();
+        assert!(to_args(&command, &matches, &entries).unwrap().is_empty());
+    }
+
+    #[test]
+    fn to_args_passes_dates_and_escapes_through() {
+        let command = command();
+        let matches = command.clone().try_get_matches_from(["test"]).unwrap();
+        let entries = parse("output_dir = \"caf\\u00e9\\tnotes\"\ndb_path = 2024-01-15\n").unwrap();
+        assert_eq!(
+            to_args(&command, &matches, &entries).unwrap(),
+            ["--output-dir", "café\tnotes", "2024-01-15"]
+        );
+    }
+
+    #[test]
+    fn to_args_rejects_unknown_keys_and_wrong_types() {
+        let command = command();
+        let matches = command.clone().try_get_matches_from(["test"]).unwrap();
+        let to_args = |text: &str| to_args(&command, &matches, &parse(text).unwrap());
+        assert_eq!(
+            to_args("outptu_dir = \"x\"").unwrap_err(),
+            "unknown key `outptu_dir`"
+        );
+        assert_eq!(
+            to_args("config = \"x\"").unwrap_err(),
+            "unknown key `config`"
+        );
+        assert_eq!(
+            to_args("stats = \"yes\"").unwrap_err(),
+            "`stats` takes true or false"
+        );
+        assert!(to_args("output_dir = true").is_err());
+    }
+}
@@ -0,0 +1,7052 @@
+//! Read highlights and annotations from a Kobo `KoboReader.sqlite` database
+//! and render them as Markdown, JSON, CSV, Org or HTML.
+//!
+//! ```no_run
+//! use kobo_highlights_exporter::*;
+//! use rusqlite::Connection;
+//!
+//! let conn = Connection::open("KoboReader.sqlite")?;
+//! for book in query_books(&conn)? {
+//!     let toc = query_toc(&conn, &book.content_id)?;
+//!     let highlights = query_highlights(&conn, &book.content_id, &HighlightFilter::default())?;
+//!     println!("{}", generate_markdown(&book, &toc, &highlights, &RenderOptions::default()));
+//! }
+//! # Ok::<(), rusqlite::Error>(())
+//! ```
+
+use clap::ValueEnum;
+use rusqlite::{Connection, Result as SqlResult};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Markdown,
+    Json,
+    Csv,
+    Org,
+    Html,
+    Jsonl,
+    Anki,
+    Sqlite,
+    Notion,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Reading order within the book
+    #[default]
+    Position,
+    /// The order the highlights were made in
+    Date,
+}
+
+impl SortOrder {
+    /// `ORDER BY` terms for `Bookmark`. `BookmarkID` breaks ties, so exports
+    /// of the same database always list highlights in the same order.
+    pub fn order_by(self) -> &'static str {
+        match self {
+            SortOrder::Position => "ContentID, ChapterProgress, BookmarkID",
+            SortOrder::Date => "DateCreated, ContentID, ChapterProgress, BookmarkID",
+        }
+    }
+}
+
+/// How `format_highlight` sets off the highlighted passage.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum QuoteStyle {
+    /// A `>` blockquote
+    #[default]
+    Blockquote,
+    /// An Obsidian `> [!quote]` callout, with the note and date inside it
+    Callout,
+    /// Indented by four spaces
+    Indent,
+}
+
+/// What `generate_markdown` puts the highlights under headings by.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum GroupBy {
+    /// The TOC chapter they are in
+    #[default]
+    Chapter,
+    /// The day they were made, oldest first
+    Date,
+}
+
+/// Which way `normalize_punctuation` converts quotes and dashes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Punctuation {
+    /// Plain ASCII: `"`, `'`, `-` and `--`
+    Straight,
+    /// Typographic: curly quotes, en and em dashes
+    Smart,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Markdown => "md",
+            OutputFormat::Json => "json",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Org => "org",
+            OutputFormat::Html => "html",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Anki => "tsv",
+            OutputFormat::Sqlite => "sqlite",
+            OutputFormat::Notion => "md",
+        }
+    }
+
+    /// Whether several books can be combined into one `--single-file` output.
+    pub fn supports_single_file(self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Markdown | OutputFormat::Csv | OutputFormat::Jsonl | OutputFormat::Anki
+        )
+    }
+}
+
+/// A book (`ContentType = 6`, see `BOOK_CONTENT_TYPE`) in the Kobo library.
+#[derive(Debug, Clone, Default)]
+pub struct Book {
+    pub content_id: String,
+    pub title: String,
+    pub author: Option<String>,
+    /// Reading progress from `content.___PercentRead` (0–100), if the schema has it.
+    pub percent_read: Option<i64>,
+    /// When the book was last opened (`DateLastRead`, or `___SyncTime` on
+    /// schemas without it).
+    pub last_read: Option<String>,
+    /// `content.PublicationDate`, if the schema has it and the book sets it.
+    pub publication_date: Option<String>,
+    /// When the book was added to the library (`DateAdded`, or `___SyncTime`
+    /// on schemas without it).
+    pub date_added: Option<String>,
+    /// `content.Language`, a language tag such as "en" or "ar-EG", if the
+    /// schema has it and the book sets it.
+    pub language: Option<String>,
+}
+
+/// A table of contents entry (`ContentType = 899`) of a book.
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub title: String,
+    /// ContentID with the trailing "-N" suffix stripped, used for matching bookmarks.
+    pub match_id: String,
+    /// TOC depth level extracted from the trailing "-N" suffix (1 = top, 2 = part, 3 = section, etc.)
+    pub depth: u32,
+}
+
+/// A highlight or annotation from the `Bookmark` table.
+#[derive(Debug, Clone, Default)]
+pub struct Highlight {
+    /// `Bookmark.BookmarkID`, stable across syncs.
+    pub id: String,
+    pub text: String,
+    pub annotation: Option<String>,
+    pub chapter_content_id: String,
+    pub date_created: Option<String>,
+    /// Position within the chapter, from 0.0 (start) to 1.0 (end).
+    pub chapter_progress: Option<f64>,
+    /// Raw `Bookmark.Color` code, see `color_name`.
+    pub color: Option<i64>,
+    /// Raw `Bookmark.StyleID`, see `is_underline`.
+    pub style: Option<i64>,
+    /// `Bookmark.StartContainerPath`, the EPUB position the highlight starts
+    /// at, on firmware that records it.
+    pub location: Option<String>,
+}
+
+/// Restrictions and ordering applied when querying a book's highlights.
+#[derive(Default)]
+pub struct HighlightFilter {
+    /// Inclusive lower bound on the date portion of `DateCreated`.
+    pub since: Option<String>,
+    /// Inclusive upper bound on the date portion of `DateCreated`.
+    pub until: Option<String>,
+    pub sort: SortOrder,
+    /// Keep bookmarks with an annotation but no highlighted text.
+    pub include_notes_only: bool,
+    /// Leave out underlines, keeping only highlights.
+    pub highlights_only: bool,
+    /// Keep only highlights with a non-empty annotation.
+    pub annotated_only: bool,
+    /// Keep only highlights with this `Bookmark.Color` code.
+    pub color: Option<i64>,
+}
+
+/// Rendering switches for the generated output, derived from the CLI flags.
+#[derive(Clone)]
+pub struct RenderOptions {
+    /// Tag each highlight with its color.
+    pub color: bool,
+    /// Show the highlight's position within its chapter as a percentage.
+    pub progress: bool,
+    /// Prepend YAML frontmatter stamped with this export date.
+    pub frontmatter: Option<String>,
+    /// Tags for the frontmatter, or for a `#tag` line without frontmatter.
+    pub tags: Vec<String>,
+    /// Group highlights by color within each chapter.
+    pub group_by_color: bool,
+    /// Put highlights under a heading per chapter, or per day.
+    pub group_by: GroupBy,
+    /// Added to every Markdown heading level.
+    pub heading_offset: usize,
+    /// Append a statistics footer.
+    pub stats: bool,
+    /// strftime pattern for highlight dates; `None` shows them as stored.
+    pub date_format: Option<String>,
+    /// Show highlight dates as "3 months ago", relative to this time (UTC).
+    pub relative_to: Option<chrono::NaiveDateTime>,
+    /// Strip invisible characters and extra whitespace from highlight text.
+    pub clean: bool,
+    /// Remove whitespace and blank lines before and after highlight text.
+    pub trim: bool,
+    /// Emit every chapter with highlights at the same heading level, without
+    /// its ancestors.
+    pub flatten: bool,
+    /// Backslash-escape Markdown syntax in highlight text.
+    pub escape_markdown: bool,
+    /// Contents of a `--template` file replacing the built-in layout.
+    pub template: Option<String>,
+    /// `--chapter-template` for the chapter headings, replacing the built-in
+    /// `## Title` ones.
+    pub chapter_template: Option<String>,
+    /// Heading for highlights that match no TOC entry; empty for none.
+    pub uncategorized_label: String,
+    /// Prepend a BibTeX `@book` entry to Markdown output.
+    pub bibtex: bool,
+    /// Assign highlights to the deepest of several TOC entries sharing a
+    /// match_id, see `assign_highlights`.
+    pub prefer_deepest: bool,
+    /// How the highlighted passage is set off in Markdown.
+    pub quote_style: QuoteStyle,
+    /// Append the number of highlights to each Markdown chapter heading.
+    pub chapter_counts: bool,
+    /// List the chapters with highlights below the Markdown header, linked
+    /// to their headings.
+    pub toc: bool,
+    /// Show each highlight's `location` below it.
+    pub locations: bool,
+    /// List chapters in reverse TOC order, to go with highlights sorted
+    /// newest first.
+    pub newest_first: bool,
+    /// Write annotations as unchecked Markdown task items.
+    pub notes_as_tasks: bool,
+    /// Decode HTML entities stored literally in highlight text.
+    pub decode_entities: bool,
+    /// Remove simple inline HTML tags stored literally in highlight text.
+    pub strip_tags: bool,
+    /// Convert quotes and dashes in highlight text to one style.
+    pub punctuation: Option<Punctuation>,
+    /// File highlights no TOC entry matches under the nearest preceding chapter.
+    pub flatten_uncategorized: bool,
+    /// Wrap each line of highlight text in Unicode right-to-left isolates.
+    /// `generate_markdown` and `generate_org` turn it on for books in a
+    /// right-to-left language.
+    pub rtl: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            color: true,
+            progress: true,
+            frontmatter: None,
+            tags: Vec::new(),
+            group_by_color: false,
+            group_by: GroupBy::Chapter,
+            heading_offset: 0,
+            stats: false,
+            date_format: None,
+            relative_to: None,
+            clean: true,
+            trim: true,
+            flatten: false,
+            escape_markdown: false,
+            template: None,
+            chapter_template: None,
+            uncategorized_label: UNCATEGORIZED.to_string(),
+            bibtex: false,
+            prefer_deepest: false,
+            quote_style: QuoteStyle::Blockquote,
+            chapter_counts: false,
+            toc: false,
+            locations: false,
+            newest_first: false,
+            notes_as_tasks: false,
+            decode_entities: false,
+            strip_tags: false,
+            punctuation: None,
+            flatten_uncategorized: false,
+            rtl: false,
+        }
+    }
+}
+
+/// Counts shown by `--stats`, per book and aggregated over the whole export.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HighlightStats {
+    pub highlights: usize,
+    pub words: usize,
+    pub annotated: usize,
+}
+
+impl HighlightStats {
+    pub fn from_highlights(highlights: &[Highlight]) -> Self {
+        HighlightStats {
+            highlights: highlights.len(),
+            words: highlights.iter().map(|h| count_words(&h.text)).sum(),
+            annotated: highlights.iter().filter(|h| has_annotation(h)).count(),
+        }
+    }
+
+    pub fn add(&mut self, other: HighlightStats) {
+        self.highlights += other.highlights;
+        self.words += other.words;
+        self.annotated += other.annotated;
+    }
+}
+
+/// Characters EPUBs use for hyphenation and line-break hints. They are
+/// invisible on the device but break searching the exported text.
+const INVISIBLE_CHARS: [char; 6] = [
+    '\u{00AD}', // soft hyphen
+    '\u{200B}', // zero-width space
+    '\u{200C}', // zero-width non-joiner
+    '\u{200D}', // zero-width joiner
+    '\u{2060}', // word joiner
+    '\u{FEFF}', // zero-width no-break space
+];
+
+/// Remove invisible characters and collapse runs of whitespace within each
+/// line to a single space. Line breaks are kept.
+pub fn clean_text(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            line.split(|c: char| c.is_whitespace())
+                .map(|word| word.replace(INVISIBLE_CHARS, ""))
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Named entities `decode_entities` knows, besides numeric ones.
+const HTML_ENTITIES: [(&str, &str); 14] = [
+    ("amp", "&"),
+    ("lt", "<"),
+    ("gt", ">"),
+    ("quot", "\""),
+    ("apos", "'"),
+    ("nbsp", " "),
+    ("shy", "\u{00AD}"),
+    ("ndash", "–"),
+    ("mdash", "—"),
+    ("hellip", "…"),
+    ("lsquo", "‘"),
+    ("rsquo", "’"),
+    ("ldquo", "“"),
+    ("rdquo", "”"),
+];
+
+/// Replace HTML entities such as `&amp;` or `&#8217;` with the characters
+/// they stand for. `&nbsp;` becomes a plain space. Unknown entities are kept.
+pub fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..].find(';').and_then(|end| {
+            let name = &rest[1..=end];
+            let c = if let Some(code) = name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                u32::from_str_radix(code, 16).ok().and_then(char::from_u32)
+            } else if let Some(code) = name.strip_prefix('#') {
+                code.parse().ok().and_then(char::from_u32)
+            } else {
+                None
+            };
+            let value = match c {
+                Some(c) => c.to_string(),
+                None => HTML_ENTITIES
+                    .iter()
+                    .find(|(entity, _)| *entity == name)
+                    .map(|(_, value)| value.to_string())?,
+            };
+            Some((value, end + 2))
+        });
+        match decoded {
+            Some((value, len)) => {
+                out.push_str(&value);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Inline tags `strip_tags` removes; anything else that looks like a tag is
+/// left alone.
+const INLINE_TAGS: [&str; 12] = [
+    "a", "b", "cite", "em", "i", "small", "span", "strong", "sub", "sup", "u", "mark",
+];
+
+/// Remove simple inline tags such as `<i>` or `</span>`, keeping their text.
+pub fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let tag_len = rest.find('>').filter(|&end| {
+            let tag = rest[1..end].trim_start_matches('/');
+            let name = tag
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or("");
+            INLINE_TAGS.contains(&name.to_ascii_lowercase().as_str())
+        });
+        match tag_len {
+            Some(end) => rest = &rest[end + 1..],
+            None => {
+                out.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Convert quotes, dashes and ellipses to one style. Going to `Smart`, a
+/// straight quote opens after a space, bracket or dash and closes otherwise,
+/// `--` becomes an em dash and a spaced ` - ` an en dash.
+pub fn normalize_punctuation(text: &str, style: Punctuation) -> String {
+    let mut out = String::with_capacity(text.len());
+    match style {
+        Punctuation::Straight => {
+            for c in text.chars() {
+                match c {
+                    '‘' | '’' | '‚' | '‛' => out.push('\''),
+                    '“' | '”' | '„' | '‟' => out.push('"'),
+                    '–' => out.push('-'),
+                    '—' => out.push_str("--"),
+                    '…' => out.push_str("..."),
+                    c => out.push(c),
+                }
+            }
+        }
+        Punctuation::Smart => {
+            let text = text
+                .replace("...", "…")
+                .replace("--", "—")
+                .replace(" - ", " – ");
+            let mut prev: Option<char> = None;
+            for c in text.chars() {
+                let opens = prev.is_none_or(|p| p.is_whitespace() || "([{—–-".contains(p));
+                match c {
+                    '"' if opens => out.push('“'),
+                    '"' => out.push('”'),
+                    '\'' if opens => out.push('‘'),
+                    '\'' => out.push('’'),
+                    c => out.push(c),
+                }
+                prev = Some(c);
+            }
+        }
+    }
+    out
+}
+
+/// The highlight text as it should be rendered: with inline tags and
+/// entities removed and punctuation normalized if asked for, and cleaned
+/// unless `--no-clean`.
+fn highlight_text<'a>(h: &'a Highlight, opts: &RenderOptions) -> std::borrow::Cow<'a, str> {
+    let mut text: std::borrow::Cow<'a, str> = h.text.as_str().into();
+    // Tags first, so decoded `&lt;i&gt;` stays text
+    if opts.strip_tags {
+        text = strip_tags(&text).into();
+    }
+    if opts.decode_entities {
+        text = decode_entities(&text).into();
+    }
+    if let Some(style) = opts.punctuation {
+        text = normalize_punctuation(&text, style).into();
+    }
+    if opts.clean {
+        text = clean_text(&text).into();
+    }
+    // Last, as decoded entities can leave spaces at the ends
+    if opts.trim && text.trim().len() != text.len() {
+        text = text.trim().to_string().into();
+    }
+    if opts.rtl {
+        text = isolate_rtl(&text).into();
+    }
+    text
+}
+
+/// Wrap each non-empty line in RIGHT-TO-LEFT ISOLATE … POP DIRECTIONAL
+/// ISOLATE, so punctuation at the ends of the line is laid out right to left
+/// in viewers that would otherwise take the direction from the document.
+fn isolate_rtl(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.to_string()
+            } else {
+                format!("\u{2067}{line}\u{2069}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `opts` with `rtl` turned on if the book is in a right-to-left language.
+fn book_opts<'a>(book: &Book, opts: &'a RenderOptions) -> std::borrow::Cow<'a, RenderOptions> {
+    if !opts.rtl && book_is_rtl(book) {
+        std::borrow::Cow::Owned(RenderOptions {
+            rtl: true,
+            ..opts.clone()
+        })
+    } else {
+        std::borrow::Cow::Borrowed(opts)
+    }
+}
+
+/// Characters `escape_markdown` prefixes with a backslash. The backslash
+/// itself is included so existing backslashes don't escape what follows.
+const MARKDOWN_SPECIAL_CHARS: [char; 8] = ['*', '_', '[', ']', '(', ')', '#', '\\'];
+
+/// Backslash-escape characters that Markdown would otherwise read as
+/// emphasis, links or headings.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_SPECIAL_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn count_words(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Markdown only has six heading levels.
+const MAX_HEADING_LEVEL: usize = 6;
+
+/// The `#` prefix for a heading at `level`, shifted by the heading offset.
+fn heading_hashes(level: usize, opts: &RenderOptions) -> String {
+    "#".repeat((level + opts.heading_offset).min(MAX_HEADING_LEVEL))
+}
+
+/// Title used when neither the database nor the ContentID yields one.
+pub const UNTITLED: &str = "Untitled";
+
+/// Default heading for highlights that match no TOC entry.
+pub const UNCATEGORIZED: &str = "Uncategorized";
+
+/// Subdirectory used by `--by-author` for books without an author.
+pub const UNKNOWN_AUTHOR: &str = "Unknown Author";
+
+/// Derive a title for a book whose `Title` is NULL or empty. Sideloaded books
+/// have their file path as ContentID (e.g. "file:///mnt/onboard/My Book.epub"),
+/// so the file name without extension is the best guess.
+fn fallback_title(content_id: &str) -> String {
+    let Some((_, file_name)) = content_id.rsplit_once(['/', '\\']) else {
+        return UNTITLED.to_string();
+    };
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => file_name,
+    };
+    let stem = stem.trim();
+    if stem.is_empty() {
+        UNTITLED.to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+/// Languages written right to left, by their ISO 639 code.
+const RTL_LANGUAGES: [&str; 14] = [
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ji", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+
+/// Whether a language tag such as "ar" or "he-IL" names a language written
+/// right to left. Unknown and malformed tags are taken as left to right.
+pub fn is_rtl_language(language: &str) -> bool {
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    RTL_LANGUAGES
+        .iter()
+        .any(|code| code.eq_ignore_ascii_case(primary.trim()))
+}
+
+/// Whether the book's language is written right to left.
+fn book_is_rtl(book: &Book) -> bool {
+    book.language.as_deref().is_some_and(is_rtl_language)
+}
+
+/// `content.ContentType` of books, as opposed to their chapters and TOC
+/// entries.
+pub const BOOK_CONTENT_TYPE: i64 = 6;
+
+/// All books in the library, in title order.
+pub fn query_books(conn: &Connection) -> SqlResult<Vec<Book>> {
+    query_books_of_types(conn, &[BOOK_CONTENT_TYPE])
+}
+
+/// `query_books` for libraries where other `ContentType` codes are books too,
+/// such as some sideloaded content.
+pub fn query_books_of_types(conn: &Connection, content_types: &[i64]) -> SqlResult<Vec<Book>> {
+    // Older database schemas don't track reading progress
+    let percent_read_column = if has_column(conn, "content", "___PercentRead")? {
+        "___PercentRead"
+    } else {
+        "NULL"
+    };
+    let last_read_column = first_content_column(conn, &["DateLastRead", "___SyncTime"])?;
+    let date_added_column = first_content_column(conn, &["DateAdded", "___SyncTime"])?;
+    let publication_date_column = if has_column(conn, "content", "PublicationDate")? {
+        "PublicationDate"
+    } else {
+        "NULL"
+    };
+    let language_column = if has_column(conn, "content", "Language")? {
+        "Language"
+    } else {
+        "NULL"
+    };
+    // Integers, so they can go into the SQL as they are
+    let content_types = content_types
+        .iter()
+        .map(i64::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT ContentID, Title, Attribution, {percent_read_column}, {last_read_column},
+                {publication_date_column}, {date_added_column}, {language_column}
+         FROM content
+         WHERE BookID IS NULL AND ContentType IN ({content_types})
+         ORDER BY Title"
+    ))?;
+
+    let books = stmt
+        .query_map([], |row| {
+            let content_id: String = row.get(0)?;
+            let title = row
+                .get::<_, Option<String>>(1)?
+                .filter(|t| !t.trim().is_empty())
+                .unwrap_or_else(|| fallback_title(&content_id));
+            Ok(Book {
+                content_id,
+                title,
+                author: row.get(2)?,
+                percent_read: row.get(3)?,
+                last_read: row.get(4)?,
+                publication_date: row.get(5)?,
+                date_added: row.get(6)?,
+                language: row
+                    .get::<_, Option<String>>(7)?
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty()),
+            })
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(books)
+}
+
+/// An SQL expression for the first non-NULL of those `content` columns this
+/// schema has, or `NULL` if it has none of them.
+fn first_content_column(conn: &Connection, columns: &[&str]) -> SqlResult<String> {
+    let mut present = Vec::new();
+    for &column in columns {
+        if has_column(conn, "content", column)? {
+            present.push(column);
+        }
+    }
+    Ok(match present.as_slice() {
+        [] => "NULL".to_string(),
+        [column] => column.to_string(),
+        columns => format!("COALESCE({})", columns.join(", ")),
+    })
+}
+
+/// Strip the trailing "-N" (digits) suffix from a ContentID.
+/// E.g. "...xhtml#chapter01_4-2" → "...xhtml#chapter01_4"
+///       "...Cover.xhtml-1"      → "...Cover.xhtml"
+fn strip_suffix(content_id: &str) -> String {
+    if let Some(pos) = content_id.rfind('-') {
+        let after = &content_id[pos + 1..];
+        if !after.is_empty() && after.chars().all(|c| c.is_ascii_digit()) {
+            return content_id[..pos].to_string();
+        }
+    }
+    content_id.to_string()
+}
+
+/// Extract the depth level from the trailing "-N" suffix of a ContentID.
+/// E.g. "...xhtml#chapter01_4-2" → 2, "...Cover.xhtml-1" → 1
+/// Returns 1 if no suffix is found (treat as top-level).
+fn extract_depth(content_id: &str) -> u32 {
+    if let Some(pos) = content_id.rfind('-') {
+        let after = &content_id[pos + 1..];
+        if !after.is_empty() && after.chars().all(|c| c.is_ascii_digit()) {
+            return after.parse().unwrap_or(1);
+        }
+    }
+    1
+}
+
+/// Fetch only ContentType=899 entries (the real TOC) ordered by VolumeIndex.
+/// Many books have several entries sharing a VolumeIndex, so ContentID breaks
+/// ties to keep the order (and the exported files) stable between runs.
+/// The trailing "-N" suffix on the ContentID encodes the TOC depth level.
+pub fn query_toc(conn: &Connection, book_content_id: &str) -> SqlResult<Vec<TocEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT ContentID, Title
+         FROM content
+         WHERE BookID = ?1
+           AND ContentType = 899
+         ORDER BY VolumeIndex, ContentID",
+    )?;
+
+    let entries: Vec<TocEntry> = stmt
+        .query_map([book_content_id], |row| {
+            let content_id: String = row.get(0)?;
+            let title: String = row.get(1)?;
+            Ok((content_id, title))
+        })?
+        .collect::<SqlResult<Vec<_>>>()?
+        .into_iter()
+        .map(|(content_id, title)| {
+            let match_id = strip_suffix(&content_id);
+            let depth = extract_depth(&content_id);
+            TocEntry {
+                title,
+                match_id,
+                depth,
+            }
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+/// Renumber TOC depths so they don't skip levels, for `--normalize-depth`:
+/// each entry ends up one level below the entry it is nested in, so a depth-3
+/// section directly under a depth-1 chapter becomes depth 2.
+pub fn normalize_depths(mut toc: Vec<TocEntry>) -> Vec<TocEntry> {
+    // (original depth, new depth) of the entries enclosing the current one
+    let mut ancestors: Vec<(u32, u32)> = Vec::new();
+    for entry in &mut toc {
+        while ancestors
+            .last()
+            .is_some_and(|&(depth, _)| depth >= entry.depth)
+        {
+            ancestors.pop();
+        }
+        let depth = ancestors.last().map_or(1, |&(_, new)| new + 1);
+        ancestors.push((entry.depth, depth));
+        entry.depth = depth;
+    }
+    toc
+}
+
+/// Tables every KoboReader database has and the exporter reads from.
+pub const REQUIRED_TABLES: [&str; 2] = ["content", "Bookmark"];
+
+/// The `REQUIRED_TABLES` the database lacks, to recognize files that aren't
+/// a KoboReader database before querying them.
+pub fn missing_tables(conn: &Connection) -> SqlResult<Vec<&'static str>> {
+    let mut missing = Vec::new();
+    for table in REQUIRED_TABLES {
+        if !has_table(conn, table)? {
+            missing.push(table);
+        }
+    }
+    Ok(missing)
+}
+
+fn has_table(conn: &Connection, table: &str) -> SqlResult<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master
+                        WHERE type = 'table' AND name = ?1 COLLATE NOCASE)",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+/// Columns only some firmware versions have, as (table, column). Queries
+/// read NULL in their place when they are missing.
+pub const OPTIONAL_COLUMNS: [(&str, &str); 9] = [
+    ("content", "___PercentRead"),
+    ("content", "DateLastRead"),
+    ("content", "DateAdded"),
+    ("content", "___SyncTime"),
+    ("content", "PublicationDate"),
+    ("content", "Language"),
+    ("Bookmark", "Color"),
+    ("Bookmark", "StyleID"),
+    ("Bookmark", "StartContainerPath"),
+];
+
+/// The schema version from the `DbVersion` table, which the firmware bumps
+/// with every schema change, or the `user_version` pragma on databases
+/// without one. `None` if neither is set.
+///
+/// Which version added which column isn't documented, so the optional
+/// columns are detected from the schema itself; the version is reported to
+/// help tell firmware apart.
+pub fn schema_version(conn: &Connection) -> SqlResult<Option<i64>> {
+    if has_table(conn, "DbVersion")? {
+        let version: Option<i64> = conn
+            .query_row("SELECT version FROM DbVersion LIMIT 1", [], |row| {
+                row.get(0)
+            })
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+        if version.is_some() {
+            return Ok(version);
+        }
+    }
+    let user_version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(Some(user_version).filter(|&v| v != 0))
+}
+
+/// The `OPTIONAL_COLUMNS` this database has.
+pub fn available_columns(conn: &Connection) -> SqlResult<Vec<(&'static str, &'static str)>> {
+    let mut available = Vec::new();
+    for (table, column) in OPTIONAL_COLUMNS {
+        if has_column(conn, table, column)? {
+            available.push((table, column));
+        }
+    }
+    Ok(available)
+}
+
+/// Check whether `table` has a column named `column`. Optional columns are
+/// missing on databases written by older firmware.
+fn has_column(conn: &Connection, table: &str, column: &str) -> SqlResult<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<SqlResult<Vec<_>>>()?;
+    Ok(names.iter().any(|name| name.eq_ignore_ascii_case(column)))
+}
+
+/// `Bookmark.Color`, or NULL on schemas without it.
+fn color_column(conn: &Connection) -> SqlResult<&'static str> {
+    Ok(if has_column(conn, "Bookmark", "Color")? {
+        "Color"
+    } else {
+        "NULL"
+    })
+}
+
+/// `Bookmark.StyleID`, or NULL on schemas without it.
+fn style_column(conn: &Connection) -> SqlResult<&'static str> {
+    Ok(if has_column(conn, "Bookmark", "StyleID")? {
+        "StyleID"
+    } else {
+        "NULL"
+    })
+}
+
+/// The `WHERE` conditions shared by `query_highlights` and the counting
+/// queries, bound with `highlight_params`. `volume` selects the book(s) by
+/// `VolumeID` using parameter ?1.
+fn highlight_conditions(volume: &str, color_column: &str, style_column: &str) -> String {
+    format!(
+        "{volume}
+           AND ((Text IS NOT NULL AND Text != '')
+                OR (?4 AND Annotation IS NOT NULL AND Annotation != ''))
+           AND (Hidden IS NULL OR Hidden = 'false' OR Hidden = 0)
+           AND (?2 IS NULL OR DateCreated >= ?2)
+           AND (?3 IS NULL OR DateCreated IS NULL OR substr(DateCreated, 1, 10) <= ?3)
+           AND (NOT ?5 OR {style_column} IS NULL OR {style_column} != {UNDERLINE_STYLE_ID})
+           AND (NOT ?6 OR (Annotation IS NOT NULL AND Annotation != ''))
+           AND (?7 IS NULL OR {color_column} = ?7)"
+    )
+}
+
+/// The parameters of `highlight_conditions`, with the book(s) as ?1.
+fn highlight_params<'a>(
+    book_content_id: &'a str,
+    filter: &'a HighlightFilter,
+) -> impl rusqlite::Params + 'a {
+    (
+        book_content_id,
+        &filter.since,
+        &filter.until,
+        filter.include_notes_only,
+        filter.highlights_only,
+        filter.annotated_only,
+        filter.color,
+    )
+}
+
+/// Number of highlights `query_highlights` would return for a book, before
+/// duplicates are collapsed.
+pub fn count_highlights(
+    conn: &Connection,
+    book_content_id: &str,
+    filter: &HighlightFilter,
+) -> SqlResult<usize> {
+    let sql = format!(
+        "SELECT COUNT(*) FROM Bookmark WHERE {}",
+        highlight_conditions("VolumeID = ?1", color_column(conn)?, style_column(conn)?)
+    );
+    conn.query_row(&sql, highlight_params(book_content_id, filter), |row| {
+        row.get(0)
+    })
+}
+
+/// Total number of highlights of the given books, counting only books with at
+/// least `min_highlights`, in one query. Like `count_highlights`, duplicates
+/// are counted.
+pub fn count_library_highlights(
+    conn: &Connection,
+    book_content_ids: &[&str],
+    filter: &HighlightFilter,
+    min_highlights: usize,
+) -> SqlResult<usize> {
+    let sql = format!(
+        "SELECT COALESCE(SUM(n), 0) FROM (
+             SELECT COUNT(*) AS n FROM Bookmark
+             WHERE {}
+             GROUP BY VolumeID
+             HAVING n >= {min_highlights}
+         )",
+        highlight_conditions(
+            "VolumeID IN (SELECT value FROM json_each(?1))",
+            color_column(conn)?,
+            style_column(conn)?
+        )
+    );
+    // The book IDs go in as one JSON array parameter
+    let ids = serde_json::to_string(book_content_ids).expect("strings serialize");
+    conn.query_row(&sql, highlight_params(&ids, filter), |row| row.get(0))
+}
+
+/// Fetch the highlights of a book, skipping bookmarks without text (dogears,
+/// unless they carry an annotation and `include_notes_only` is set) and those
+/// the user deleted on the device. Depending on the firmware,
+/// `Hidden` is stored either as the string 'true'/'false' or as 1/0.
+///
+/// `DateCreated` is an ISO-8601 string, so the date filters compare it
+/// lexicographically. Highlights without a date can't satisfy `since` and are
+/// dropped when it is set, but are kept when only `until` is given.
+pub fn query_highlights(
+    conn: &Connection,
+    book_content_id: &str,
+    filter: &HighlightFilter,
+) -> SqlResult<Vec<Highlight>> {
+    let color_column = color_column(conn)?;
+    let style_column = style_column(conn)?;
+    let location_column = if has_column(conn, "Bookmark", "StartContainerPath")? {
+        "StartContainerPath"
+    } else {
+        "NULL"
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT Text, Annotation, ContentID, ChapterProgress, DateCreated, {color_column},
+                {style_column}, BookmarkID, {location_column}
+         FROM Bookmark
+         WHERE {conditions}
+         ORDER BY {order_by}",
+        conditions = highlight_conditions("VolumeID = ?1", color_column, style_column),
+        order_by = filter.sort.order_by()
+    ))?;
+
+    let highlights = stmt
+        .query_map(highlight_params(book_content_id, filter), |row| {
+            Ok(Highlight {
+                text: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                annotation: row.get(1)?,
+                chapter_content_id: row.get(2)?,
+                chapter_progress: row.get(3)?,
+                date_created: row.get(4)?,
+                color: row.get(5)?,
+                style: row.get(6)?,
+                id: row.get(7)?,
+                location: row.get(8)?,
+            })
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(highlights)
+}
+
+/// Dogear bookmarks of a book: bookmarks with neither highlighted text nor an
+/// annotation, in reading order. `query_highlights` leaves these out.
+pub fn query_bookmarks(conn: &Connection, book_content_id: &str) -> SqlResult<Vec<Highlight>> {
+    let mut stmt = conn.prepare(
+        "SELECT ContentID, ChapterProgress, DateCreated, BookmarkID
+         FROM Bookmark
+         WHERE VolumeID = ?1
+           AND (Text IS NULL OR Text = '')
+           AND (Annotation IS NULL OR Annotation = '')
+           AND (Hidden IS NULL OR Hidden = 'false' OR Hidden = 0)
+         ORDER BY ContentID, ChapterProgress, BookmarkID",
+    )?;
+
+    let bookmarks = stmt
+        .query_map([book_content_id], |row| {
+            Ok(Highlight {
+                chapter_content_id: row.get(0)?,
+                chapter_progress: row.get(1)?,
+                date_created: row.get(2)?,
+                id: row.get(3)?,
+                ..Default::default()
+            })
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    Ok(bookmarks)
+}
+
+/// `AnalyticsEvents.Type` values that some older firmware logs highlights
+/// under.
+pub const ANALYTICS_HIGHLIGHT_EVENTS: [&str; 2] = ["HighlightAdded", "AnnotationAdded"];
+
+/// A value of an event's `Attributes` object, by case-insensitive key, as
+/// firmware versions disagree on the casing. Numbers are read as strings too.
+fn event_attribute(
+    attributes: &serde_json::Map<String, serde_json::Value>,
+    keys: &[&str],
+) -> Option<String> {
+    keys.iter().find_map(|key| {
+        let (_, value) = attributes
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))?;
+        match value {
+            serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// Highlights of a book recovered from the `AnalyticsEvents` table, for
+/// firmware that logged them there instead of in `Bookmark`. The `Attributes`
+/// payload is a JSON object whose keys vary between versions; events that
+/// don't parse, or lack the book or the text, are skipped.
+///
+/// The events carry no color or style, and most carry no annotation, so a
+/// `color` filter matches nothing and `annotated_only` usually leaves little.
+/// Without a chapter the highlight goes under the book itself, which
+/// `assign_highlights` files as uncategorized.
+pub fn query_analytics_highlights(
+    conn: &Connection,
+    book_content_id: &str,
+    filter: &HighlightFilter,
+) -> SqlResult<Vec<Highlight>> {
+    if !has_table(conn, "AnalyticsEvents")? || filter.color.is_some() {
+        return Ok(Vec::new());
+    }
+    let types = serde_json::to_string(&ANALYTICS_HIGHLIGHT_EVENTS).expect("strings serialize");
+    let mut stmt = conn.prepare(
+        "SELECT Id, FirstOccurrence, Attributes
+         FROM AnalyticsEvents
+         WHERE Type IN (SELECT value FROM json_each(?1))",
+    )?;
+    let events = stmt
+        .query_map([types], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+
+    let mut highlights: Vec<Highlight> = events
+        .into_iter()
+        .filter_map(|(id, date, attributes)| {
+            let serde_json::Value::Object(attributes) = serde_json::from_str(&attributes?).ok()?
+            else {
+                return None;
+            };
+            let volume = event_attribute(&attributes, &["volumeid", "bookid"])?;
+            if volume != book_content_id {
+                return None;
+            }
+            let text = event_attribute(&attributes, &["text", "highlighttext", "highlight"])
+                .unwrap_or_default();
+            let annotation = event_attribute(&attributes, &["annotation", "note"]);
+            if text.trim().is_empty() && !(filter.include_notes_only && annotation.is_some()) {
+                return None;
+            }
+            Some(Highlight {
+                id: id.unwrap_or_default(),
+                text,
+                annotation,
+                chapter_content_id: event_attribute(&attributes, &["chapterid", "contentid"])
+                    .unwrap_or_else(|| book_content_id.to_string()),
+                date_created: date,
+                chapter_progress: event_attribute(&attributes, &["chapterprogress"])
+                    .and_then(|p| p.parse().ok()),
+                ..Default::default()
+            })
+        })
+        .filter(|h| {
+            let date = h.date_created.as_deref();
+            filter
+                .since
+                .as_deref()
+                .is_none_or(|since| date.is_some_and(|d| d >= since))
+                && filter
+                    .until
+                    .as_deref()
+                    .is_none_or(|until| date.is_none_or(|d| d.get(..10).unwrap_or(d) <= until))
+                && (!filter.annotated_only || has_annotation(h))
+        })
+        .collect();
+
+    let position = |h: &Highlight| {
+        (
+            h.chapter_content_id.clone(),
+            h.chapter_progress.unwrap_or(0.0),
+            h.id.clone(),
+        )
+    };
+    highlights.sort_by(|a, b| {
+        let by_date = match filter.sort {
+            SortOrder::Position => Ordering::Equal,
+            SortOrder::Date => a.date_created.cmp(&b.date_created),
+        };
+        by_date.then_with(|| {
+            let (a, b) = (position(a), position(b));
+            a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(a.2.cmp(&b.2))
+        })
+    });
+    Ok(highlights)
+}
+
+/// Split an `Attribution` value listing several authors, e.g.
+/// "Author One\nAuthor Two" or "A; B". Commas are left alone since they also
+/// appear in "Last, First" names.
+pub fn parse_authors(attribution: &str) -> Vec<String> {
+    attribution
+        .split(['\n', ';', '&'])
+        .map(str::trim)
+        .filter(|author| !author.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The book's authors with a matching "Author"/"Authors" label, or `None`
+/// if it has no attribution.
+fn author_line(book: &Book) -> Option<(&'static str, String)> {
+    let authors = parse_authors(book.author.as_deref().unwrap_or(""));
+    match authors.len() {
+        0 => None,
+        1 => Some(("Author", authors.join(", "))),
+        _ => Some(("Authors", authors.join(", "))),
+    }
+}
+
+/// Whether the book was opened on or after `since` (a `YYYY-MM-DD` date).
+/// Books without a last-read date have never been opened.
+pub fn modified_since(book: &Book, since: &str) -> bool {
+    book.last_read.as_deref().is_some_and(|d| d >= since)
+}
+
+/// Whether the book was added to the library on or after `since` (a
+/// `YYYY-MM-DD` date). Books without an added date never match.
+pub fn added_since(book: &Book, since: &str) -> bool {
+    book.date_added.as_deref().is_some_and(|d| d >= since)
+}
+
+/// Case-insensitive substring match, used by the book filters.
+pub fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+pub fn sanitize_filename(name: &str) -> String {
+    let sanitized = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-')
+        .collect::<String>()
+        .trim()
+        .to_string();
+    if sanitized.is_empty() {
+        UNTITLED.to_string()
+    } else {
+        sanitized
+    }
+}
+
+/// The author as a file or directory name, `UNKNOWN_AUTHOR` if the book has
+/// none or nothing of it survives sanitizing.
+pub fn author_file_stem(author: Option<&str>) -> String {
+    author
+        .map(sanitize_filename)
+        .filter(|a| a != UNTITLED)
+        .unwrap_or_else(|| UNKNOWN_AUTHOR.to_string())
+}
+
+/// Directory a book's file goes into: the output directory itself, or with
+/// `--by-author` a subdirectory named after the author.
+pub fn book_dir(output_dir: &Path, author: Option<&str>, by_author: bool) -> PathBuf {
+    if !by_author {
+        return output_dir.to_path_buf();
+    }
+    output_dir.join(author_file_stem(author))
+}
+
+/// Make a file stem unique within this export. A stem that is already taken
+/// gets the sanitized author appended, e.g. "Title (Author)", and if that is
+/// taken too (or there is no author) a number, e.g. "Title (2)". Comparison
+/// ignores case, since Windows and macOS file systems usually do.
+pub fn unique_file_stem(stem: &str, author: Option<&str>, used: &mut HashSet<String>) -> String {
+    let mut candidates = Vec::new();
+    candidates.push(stem.to_string());
+    if let Some(author) = author.map(sanitize_filename).filter(|a| a != UNTITLED) {
+        candidates.push(format!("{stem} ({author})"));
+    }
+
+    let unique = candidates
+        .into_iter()
+        .find(|c| !used.contains(&c.to_lowercase()))
+        .unwrap_or_else(|| {
+            (2..)
+                .map(|n| format!("{stem} ({n})"))
+                .find(|c| !used.contains(&c.to_lowercase()))
+                .expect("unbounded range always yields a free name")
+        });
+    used.insert(unique.to_lowercase());
+    unique
+}
+
+/// Map a `Bookmark.Color` code to the name of the highlighter color on the device.
+pub fn color_name(code: i64) -> &'static str {
+    match code {
+        0 => "yellow",
+        1 => "red",
+        2 => "blue",
+        3 => "green",
+        _ => "other",
+    }
+}
+
+/// Convert a 0.0–1.0 chapter progress into a whole percentage clamped to 0–100.
+fn progress_percent(progress: f64) -> u32 {
+    (progress * 100.0).round().clamp(0.0, 100.0) as u32
+}
+
+/// The `Bookmark.Color` code for a color name as shown by `color_name`, or
+/// for the code itself, e.g. "yellow", "Red" or "2".
+pub fn color_code(name: &str) -> Option<i64> {
+    let name = name.trim();
+    if let Ok(code) = name.parse() {
+        return Some(code);
+    }
+    (0..4).find(|&code| color_name(code).eq_ignore_ascii_case(name))
+}
+
+/// Sub-heading for a color group, e.g. "Yellow". Highlights without a color
+/// are grouped under "No color".
+fn color_group_label(code: Option<i64>) -> String {
+    match code {
+        Some(code) => {
+            let name = color_name(code);
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+        None => "No color".to_string(),
+    }
+}
+
+pub fn format_highlight(h: &Highlight, opts: &RenderOptions) -> String {
+    let mut out = String::new();
+
+    let mut text = highlight_text(h, opts);
+    if opts.escape_markdown {
+        text = escape_markdown(&text).into();
+    }
+    let paragraphs = paragraphs(&text);
+    // Underlines are set in italics instead of being quoted
+    let style = (!is_underline(h)).then_some(opts.quote_style);
+    let blocks: Vec<String> = paragraphs
+        .iter()
+        .map(|lines| match style {
+            None => format!("*{}*\n", lines.join(" ")),
+            Some(QuoteStyle::Blockquote | QuoteStyle::Callout) => {
+                lines.iter().map(|line| format!("> {line}\n")).collect()
+            }
+            Some(QuoteStyle::Indent) => lines.iter().map(|line| format!("    {line}\n")).collect(),
+        })
+        .collect();
+    // A bare `>` keeps quoted paragraphs in one blockquote
+    let separator = match style {
+        Some(QuoteStyle::Blockquote | QuoteStyle::Callout) => ">\n",
+        _ => "\n",
+    };
+    let callout = style == Some(QuoteStyle::Callout) && !blocks.is_empty();
+    if callout {
+        out.push_str("> [!quote]\n");
+    }
+    out.push_str(&blocks.join(separator));
+
+    // An Obsidian block reference on its own line refers to the block above
+    // it: the passage, or the note when there is no passage. A callout is one
+    // block with its note and date, so the reference goes after all of it.
+    let block_ref = block_id(h).map(|id| format!("\n^{id}\n"));
+    if !out.is_empty() && !callout {
+        out.push_str(block_ref.as_deref().unwrap_or_default());
+    }
+
+    if let Some(ref note) = h.annotation {
+        if !note.is_empty() {
+            let note = format_note(note, opts);
+            // A standalone note has no quote above it to separate from
+            if out.is_empty() {
+                out.push_str(&format!("{note}\n"));
+                out.push_str(block_ref.as_deref().unwrap_or_default());
+            } else if callout {
+                let quoted: Vec<String> = note
+                    .lines()
+                    .map(|line| {
+                        if line.is_empty() {
+                            ">".into()
+                        } else {
+                            format!("> {line}")
+                        }
+                    })
+                    .collect();
+                out.push_str(&format!(">\n{}\n", quoted.join("\n")));
+            } else {
+                out.push_str(&format!("\n{note}\n"));
+            }
+        }
+    }
+
+    if let Some(meta) = format_metadata(h, opts, |date| format!("*{date}*")) {
+        if callout {
+            out.push_str(&format!(">\n> {meta}\n"));
+        } else {
+            out.push_str(&format!("\n{meta}\n"));
+        }
+    }
+    if let Some(location) = h.location.as_deref().filter(|_| opts.locations) {
+        if !location.is_empty() {
+            // A code span, as container paths are full of Markdown syntax
+            let line = format!("`loc: {location}`");
+            if callout {
+                out.push_str(&format!(">\n> {line}\n"));
+            } else {
+                out.push_str(&format!("\n{line}\n"));
+            }
+        }
+    }
+    if callout {
+        out.push_str(block_ref.as_deref().unwrap_or_default());
+    }
+
+    out
+}
+
+/// The "**Note:**" (or task item) for an annotation, without a trailing
+/// newline. A note of several lines goes below the label as a blockquote, or
+/// indented under the task item, so lines starting with `#` or `-` and blank
+/// lines stay part of the note.
+fn format_note(note: &str, opts: &RenderOptions) -> String {
+    let lines: Vec<&str> = note.trim_end().lines().map(str::trim_end).collect();
+    match (lines.as_slice(), opts.notes_as_tasks) {
+        ([line], false) => format!("**Note:** {line}"),
+        (lines, true) => {
+            let mut out = String::from("- [ ] Note:");
+            for (i, line) in lines.iter().enumerate() {
+                match i {
+                    0 => out.push(' '),
+                    _ if line.is_empty() => out.push('\n'),
+                    _ => out.push_str("\n  "),
+                }
+                out.push_str(line);
+            }
+            out
+        }
+        (lines, false) => {
+            let mut out = String::from("**Note:**");
+            for line in lines {
+                out.push_str(if line.is_empty() { "\n>" } else { "\n> " });
+                out.push_str(line);
+            }
+            out
+        }
+    }
+}
+
+/// Parse a Kobo `DateCreated` value. Depending on the firmware it looks like
+/// "2024-01-15T10:30:00.000", "2024-01-15T10:30:00Z" or "2024-01-15 10:30:00".
+pub fn parse_kobo_date(date: &str) -> Option<chrono::NaiveDateTime> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime};
+    if let Ok(dt) = DateTime::parse_from_rfc3339(date) {
+        return Some(dt.naive_utc());
+    }
+    for pattern in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(date, pattern) {
+            return Some(dt);
+        }
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+/// A `DateCreated` value as strict RFC 3339 in UTC, e.g.
+/// "2024-01-15T10:30:00Z", for the JSON and CSV output. Kobo stores UTC
+/// times without an offset, sometimes with a space instead of the `T` or
+/// without the time. Values that don't parse are returned unchanged.
+pub fn rfc3339_date(date: &str) -> String {
+    match parse_kobo_date(date) {
+        Some(dt) => dt
+            .and_utc()
+            .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        None => date.to_string(),
+    }
+}
+
+/// Render a `DateCreated` value for display, falling back to the raw string
+/// when no format is set or the value can't be parsed.
+pub fn display_date(date: &str, opts: &RenderOptions) -> String {
+    if let Some(now) = opts.relative_to {
+        if let Some(relative) = parse_kobo_date(date).and_then(|dt| relative_date(dt, now)) {
+            return relative;
+        }
+    }
+    match opts.date_format {
+        Some(ref pattern) => match parse_kobo_date(date) {
+            // Offset and time zone specifiers such as %z fail on a date
+            // without a zone, and `to_string` would panic on the error
+            Some(dt) => {
+                use std::fmt::Write;
+                let mut out = String::new();
+                match write!(out, "{}", dt.format(pattern)) {
+                    Ok(()) => out,
+                    Err(_) => date.to_string(),
+                }
+            }
+            None => date.to_string(),
+        },
+        None => date.to_string(),
+    }
+}
+
+/// How long before `now` the date is, e.g. "yesterday" or "3 months ago".
+/// `None` for dates after `now`.
+pub fn relative_date(date: chrono::NaiveDateTime, now: chrono::NaiveDateTime) -> Option<String> {
+    let elapsed = now.signed_duration_since(date);
+    if elapsed < chrono::TimeDelta::zero() {
+        return None;
+    }
+    let days = elapsed.num_days();
+    let (count, unit) = match days {
+        0 if elapsed.num_hours() > 0 => (elapsed.num_hours(), "hour"),
+        0 if elapsed.num_minutes() > 0 => (elapsed.num_minutes(), "minute"),
+        0 => return Some("just now".to_string()),
+        1 => return Some("yesterday".to_string()),
+        2..=29 => (days, "day"),
+        30..=364 => (days / 30, "month"),
+        _ => (days / 365, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    Some(format!("{count} {unit}{plural} ago"))
+}
+
+/// The line of details shown under a highlight: date (set in the output
+/// format's emphasis by `emphasize`), chapter progress and color.
+fn format_metadata(
+    h: &Highlight,
+    opts: &RenderOptions,
+    emphasize: impl Fn(&str) -> String,
+) -> Option<String> {
+    let mut meta = Vec::new();
+    if let Some(ref date) = h.date_created {
+        meta.push(emphasize(&display_date(date, opts)));
+    }
+    if opts.progress {
+        if let Some(progress) = h.chapter_progress {
+            meta.push(format!("({}%)", progress_percent(progress)));
+        }
+    }
+    if opts.color {
+        if let Some(code) = h.color {
+            meta.push(format!("[color: {}]", color_name(code)));
+        }
+    }
+    if meta.is_empty() {
+        None
+    } else {
+        Some(meta.join(" "))
+    }
+}
+
+/// `Bookmark.StyleID` of passages underlined rather than highlighted.
+const UNDERLINE_STYLE_ID: i64 = 1;
+
+/// Whether the passage was underlined rather than highlighted.
+pub fn is_underline(h: &Highlight) -> bool {
+    h.style == Some(UNDERLINE_STYLE_ID)
+}
+
+/// Obsidian block ID (`^kobo-…`) derived from the `BookmarkID`, so links to a
+/// highlight survive re-exports. Block IDs may only contain letters, digits
+/// and dashes; `None` if the highlight has no ID.
+pub fn block_id(h: &Highlight) -> Option<String> {
+    let id: String =
+        h.id.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect::<String>()
+            .to_lowercase();
+    (!id.is_empty()).then(|| format!("kobo-{id}"))
+}
+
+/// Split text into paragraphs at blank lines, dropping empty ones.
+fn paragraphs(text: &str) -> Vec<Vec<&str>> {
+    let mut paragraphs = vec![Vec::new()];
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            paragraphs.push(Vec::new());
+        } else if let Some(paragraph) = paragraphs.last_mut() {
+            paragraph.push(line);
+        }
+    }
+    paragraphs.retain(|p| !p.is_empty());
+    paragraphs
+}
+
+fn has_annotation(h: &Highlight) -> bool {
+    h.annotation.as_deref().is_some_and(|a| !a.is_empty())
+}
+
+/// Drop the creation dates of the highlights, for `--no-dates`: every output
+/// format then leaves the date out (JSON and CSV keep the field, empty).
+pub fn without_dates(mut highlights: Vec<Highlight>) -> Vec<Highlight> {
+    for h in &mut highlights {
+        h.date_created = None;
+    }
+    highlights
+}
+
+/// The book without when it was last read and added to the library, for
+/// `--no-dates`. Only the SQLite export writes these.
+pub fn book_without_dates(book: &Book) -> Book {
+    Book {
+        last_read: None,
+        date_added: None,
+        ..book.clone()
+    }
+}
+
+/// Drop the annotations of the highlights, for `--strip-annotations`. Notes
+/// without a highlighted passage have nothing left and are removed.
+pub fn without_annotations(mut highlights: Vec<Highlight>) -> Vec<Highlight> {
+    highlights.retain(|h| !h.text.is_empty());
+    for h in &mut highlights {
+        h.annotation = None;
+    }
+    highlights
+}
+
+/// How close (as a fraction of the chapter) two highlights' `ChapterProgress`
+/// must be for `merge_adjacent_highlights` to treat them as one passage.
+pub const ADJACENT_PROGRESS_EPSILON: f64 = 0.01;
+
+/// Merge consecutive highlights in the same chapter whose positions are within
+/// `ADJACENT_PROGRESS_EPSILON` of each other, as left behind when a long
+/// passage is highlighted in several swipes. The texts are joined with a
+/// space and the annotations with a line break; the first highlight's date,
+/// color and ID are kept. Highlights without a position or text are never
+/// merged.
+pub fn merge_adjacent_highlights(highlights: Vec<Highlight>) -> Vec<Highlight> {
+    let mut merged: Vec<Highlight> = Vec::with_capacity(highlights.len());
+    for h in highlights {
+        if let Some(prev) = merged.last_mut() {
+            let adjacent = match (prev.chapter_progress, h.chapter_progress) {
+                (Some(a), Some(b)) => (b - a).abs() <= ADJACENT_PROGRESS_EPSILON,
+                _ => false,
+            };
+            if adjacent
+                && prev.chapter_content_id == h.chapter_content_id
+                && !prev.text.is_empty()
+                && !h.text.is_empty()
+            {
+                prev.text = format!("{} {}", prev.text.trim_end(), h.text.trim_start());
+                prev.chapter_progress = h.chapter_progress;
+                if has_annotation(&h) {
+                    prev.annotation =
+                        Some(match prev.annotation.take().filter(|a| !a.is_empty()) {
+                            Some(first) => format!("{first}\n{}", h.annotation.unwrap_or_default()),
+                            None => h.annotation.unwrap_or_default(),
+                        });
+                }
+                continue;
+            }
+        }
+        merged.push(h);
+    }
+    merged
+}
+
+/// Split highlights for `--separate-notes`: the highlighted passages with
+/// their annotations removed, and the annotated highlights, which keep their
+/// passage for context. Standalone notes only appear in the second list.
+pub fn split_annotations(highlights: &[Highlight]) -> (Vec<Highlight>, Vec<Highlight>) {
+    let quotes = highlights
+        .iter()
+        .filter(|h| !h.text.trim().is_empty())
+        .map(|h| Highlight {
+            annotation: None,
+            ..h.clone()
+        })
+        .collect();
+    let annotated = highlights
+        .iter()
+        .filter(|h| has_annotation(h))
+        .cloned()
+        .collect();
+    (quotes, annotated)
+}
+
+/// Collapse highlights with identical text and ContentID, which Kobo leaves
+/// behind when a highlight's boundaries are adjusted. The first occurrence
+/// keeps its position; if a later duplicate has an annotation and the first
+/// doesn't, the annotated one takes its place. Standalone notes (no text) are
+/// never collapsed.
+pub fn dedup_highlights(highlights: Vec<Highlight>) -> Vec<Highlight> {
+    let mut seen: HashMap<(String, String), usize> = HashMap::new();
+    let mut unique: Vec<Highlight> = Vec::with_capacity(highlights.len());
+
+    for h in highlights {
+        if h.text.is_empty() {
+            unique.push(h);
+            continue;
+        }
+        let key = (h.text.clone(), h.chapter_content_id.clone());
+        match seen.get(&key) {
+            Some(&idx) => {
+                if !has_annotation(&unique[idx]) && has_annotation(&h) {
+                    unique[idx] = h;
+                }
+            }
+            None => {
+                seen.insert(key, unique.len());
+                unique.push(h);
+            }
+        }
+    }
+
+    unique
+}
+
+/// Use `/` as the path separator throughout. Some sideloaded books have
+/// Windows-style `\` separators in their ContentIDs, and the TOC and the
+/// bookmarks of such a book don't always agree on which one they use.
+fn normalize_separators(content_id: &str) -> String {
+    content_id.replace('\\', "/")
+}
+
+/// Decode `%XX` escapes, e.g. "Caf%C3%A9%20Noir" → "Café Noir". Escapes that
+/// don't decode to UTF-8 are kept as they are.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| text.to_string())
+}
+
+/// The form of a ContentID that matching compares: with `/` separators and
+/// percent-escapes decoded, as the TOC and the bookmarks of a book can differ
+/// in both.
+fn match_key(content_id: &str) -> String {
+    percent_decode(&normalize_separators(content_id))
+}
+
+/// The file-path portion of a ContentID, i.e. everything before the `#fragment`.
+/// E.g. "...Chapter01.xhtml#chapter01_4" → "...Chapter01.xhtml"
+fn file_path(content_id: &str) -> &str {
+    content_id
+        .split_once('#')
+        .map_or(content_id, |(path, _)| path)
+}
+
+/// Lookup of the TOC entry a ContentID belongs to, built once per book.
+struct TocIndex {
+    /// match_id → TOC entry index
+    by_match_id: HashMap<String, usize>,
+    /// File path → first TOC entry in that file
+    by_file: HashMap<String, usize>,
+}
+
+impl TocIndex {
+    fn new(toc: &[TocEntry], prefer_deepest: bool) -> Self {
+        let mut by_match_id: HashMap<String, usize> = HashMap::new();
+        let mut by_file: HashMap<String, usize> = HashMap::new();
+        for (i, entry) in toc.iter().enumerate() {
+            let match_id = match_key(&entry.match_id);
+            by_file.entry(file_path(&match_id).to_string()).or_insert(i);
+            let best = by_match_id.entry(match_id).or_insert(i);
+            if prefer_deepest && entry.depth > toc[*best].depth {
+                *best = i;
+            }
+        }
+        TocIndex {
+            by_match_id,
+            by_file,
+        }
+    }
+
+    /// Index of the TOC entry for a ContentID: the exact match, else the
+    /// first entry in the same file.
+    fn resolve(&self, content_id: &str) -> Option<usize> {
+        let content_id = match_key(content_id);
+        self.by_match_id
+            .get(&content_id)
+            .or_else(|| self.by_file.get(file_path(&content_id)))
+            .copied()
+    }
+}
+
+/// The title of the TOC entry a bookmark's ContentID belongs to, matched the
+/// way `assign_highlights` does. `None` if no entry matches or the matching
+/// entry has no title.
+pub fn resolve_chapter_title<'a>(toc: &'a [TocEntry], content_id: &str) -> Option<&'a str> {
+    TocIndex::new(toc, false)
+        .resolve(content_id)
+        .map(|i| toc[i].title.as_str())
+        .filter(|title| !title.is_empty())
+}
+
+/// Assign highlights to TOC entries.
+///
+/// Matching strategy: the bookmark's ContentID equals a TOC entry's match_id
+/// (which is the 899 ContentID with the trailing "-N" suffix stripped).
+///
+/// Example:
+///   Bookmark:  ...Chapter01.xhtml#chapter01_4
+///   TOC entry: ...Chapter01.xhtml#chapter01_4-2  →  match_id: ...Chapter01.xhtml#chapter01_4
+///   → MATCH
+///
+/// If there is no exact match, the `#fragment` is ignored and the highlight
+/// goes to the first TOC entry in the same file, the nearest heading known to
+/// precede it. Only highlights whose file has no TOC entry are uncategorized.
+///
+/// Several TOC entries can share a match_id, e.g. a chapter and its first
+/// section both pointing at the top of the same file. The first of them in
+/// VolumeIndex order wins, unless `prefer_deepest` is set: then the entry with
+/// the greatest depth wins, and the first one among those equally deep.
+///
+/// Every list, the uncategorized one included, keeps the order of
+/// `highlights`. Callers walk the map by TOC index rather than iterating it,
+/// so the output never depends on hash order.
+pub fn assign_highlights<'a>(
+    toc: &[TocEntry],
+    highlights: &'a [Highlight],
+    prefer_deepest: bool,
+) -> (HashMap<usize, Vec<&'a Highlight>>, Vec<&'a Highlight>) {
+    let index = TocIndex::new(toc, prefer_deepest);
+    let mut assigned: HashMap<usize, Vec<&'a Highlight>> = HashMap::new();
+    let mut uncategorized: Vec<&'a Highlight> = Vec::new();
+
+    for h in highlights {
+        if let Some(idx) = index.resolve(&h.chapter_content_id) {
+            assigned.entry(idx).or_default().push(h);
+        } else {
+            uncategorized.push(h);
+        }
+    }
+
+    (assigned, uncategorized)
+}
+
+/// Compare strings with runs of digits as numbers, so "ch9" sorts before
+/// "ch10".
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let a_end = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let b_end = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (m, n) = (
+                a[..a_end].trim_start_matches('0'),
+                b[..b_end].trim_start_matches('0'),
+            );
+            let ord = m.len().cmp(&n.len()).then_with(|| m.cmp(n));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            (a, b) = (&a[a_end..], &b[b_end..]);
+        } else if x != y {
+            return x.cmp(&y);
+        } else {
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+        }
+    }
+}
+
+/// Move uncategorized highlights under the TOC entry that most closely
+/// precedes them: the last entry of the nearest file whose path sorts before
+/// the highlight's, comparing numbers in paths by value. Highlights before
+/// the first chapter stay uncategorized and are returned. Moved highlights
+/// come after the ones the entry matched, as they lie later in the book.
+pub fn assign_to_preceding<'a>(
+    toc: &[TocEntry],
+    assigned: &mut HashMap<usize, Vec<&'a Highlight>>,
+    uncategorized: Vec<&'a Highlight>,
+) -> Vec<&'a Highlight> {
+    let files: Vec<(String, usize)> = toc
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.title.is_empty())
+        .map(|(i, entry)| {
+            let match_id = match_key(&entry.match_id);
+            (file_path(&match_id).to_string(), i)
+        })
+        .collect();
+
+    let mut remaining = Vec::new();
+    for h in uncategorized {
+        let content_id = match_key(&h.chapter_content_id);
+        let path = file_path(&content_id);
+        let preceding = files
+            .iter()
+            .filter(|(file, _)| natural_cmp(file, path) == Ordering::Less)
+            .max_by(|(a, i), (b, j)| natural_cmp(a, b).then(i.cmp(j)));
+        match preceding {
+            Some(&(_, i)) => assigned.entry(i).or_default().push(h),
+            None => remaining.push(h),
+        }
+    }
+    remaining
+}
+
+/// `assign_highlights` with the rendering options applied.
+fn assign_for_render<'a>(
+    toc: &[TocEntry],
+    highlights: &'a [Highlight],
+    opts: &RenderOptions,
+) -> (HashMap<usize, Vec<&'a Highlight>>, Vec<&'a Highlight>) {
+    let (mut assigned, uncategorized) = assign_highlights(toc, highlights, opts.prefer_deepest);
+    if !opts.flatten_uncategorized {
+        return (assigned, uncategorized);
+    }
+    let uncategorized = assign_to_preceding(toc, &mut assigned, uncategorized);
+    (assigned, uncategorized)
+}
+
+/// Quote a value as a YAML double-quoted scalar, so titles containing colons,
+/// quotes or a leading `-` can't change the meaning of the frontmatter.
+fn yaml_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Turn text into a tag Obsidian accepts: spaces become `-`, a leading `#`
+/// and characters other than letters, digits, `_`, `-` and `/` are dropped.
+/// `None` if nothing is left, or only digits, which Obsidian doesn't take as
+/// a tag.
+pub fn sanitize_tag(tag: &str) -> Option<String> {
+    let tag: String = tag
+        .trim()
+        .trim_start_matches('#')
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .filter(|&c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/'))
+        .collect();
+    (!tag.chars().all(|c| c.is_ascii_digit())).then_some(tag)
+}
+
+fn sanitized_tags(tags: &[String]) -> Vec<String> {
+    tags.iter().filter_map(|t| sanitize_tag(t)).collect()
+}
+
+pub fn generate_frontmatter(
+    book: &Book,
+    highlight_count: usize,
+    exported: &str,
+    tags: &[String],
+) -> String {
+    let mut fm = String::from("---\n");
+    fm.push_str(&format!("title: {}\n", yaml_string(&book.title)));
+    if let Some(ref author) = book.author {
+        if !author.is_empty() {
+            fm.push_str(&format!("author: {}\n", yaml_string(author)));
+        }
+    }
+    if let Some(ref language) = book.language {
+        fm.push_str(&format!("language: {}\n", yaml_string(language)));
+    }
+    fm.push_str(&format!("highlight_count: {highlight_count}\n"));
+    fm.push_str(&format!("exported: {exported}\n"));
+    let tags = sanitized_tags(tags);
+    if !tags.is_empty() {
+        fm.push_str(&format!("tags: [{}]\n", tags.join(", ")));
+    }
+    fm.push_str("---\n");
+    fm
+}
+
+/// The four-digit year at the start of a date such as "2019-05-01T00:00:00".
+fn publication_year(date: &str) -> Option<&str> {
+    let year = date.trim().get(..4)?;
+    year.chars().all(|c| c.is_ascii_digit()).then_some(year)
+}
+
+/// Remove braces, which would unbalance a BibTeX field value.
+fn bibtex_value(value: &str) -> String {
+    value.replace(['{', '}'], "")
+}
+
+/// Cite key from the first author's surname and the year, e.g. "herbert1965".
+/// Falls back to the first word of the title for books without an author.
+fn cite_key(book: &Book, year: Option<&str>) -> String {
+    let authors = book
+        .author
+        .as_deref()
+        .map(parse_authors)
+        .unwrap_or_default();
+    let name = match authors.first() {
+        // "Herbert, Frank" lists the surname first
+        Some(author) => match author.split_once(',') {
+            Some((surname, _)) => surname.to_string(),
+            None => author.split_whitespace().last().unwrap_or("").to_string(),
+        },
+        None => book
+            .title
+            .split_whitespace()
+            .next()
+            .unwrap_or(UNTITLED)
+            .to_string(),
+    };
+    let mut key: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    if key.is_empty() {
+        key = UNTITLED.to_lowercase();
+    }
+    if let Some(year) = year {
+        key.push_str(year);
+    }
+    key
+}
+
+/// A fenced BibTeX `@book` entry for the book. Fields the database doesn't
+/// have are left out rather than emitted empty.
+pub fn generate_bibtex(book: &Book) -> String {
+    let year = book.publication_date.as_deref().and_then(publication_year);
+    let authors = book
+        .author
+        .as_deref()
+        .map(parse_authors)
+        .unwrap_or_default();
+
+    let mut fields = vec![("title", bibtex_value(display_title(book)))];
+    if !authors.is_empty() {
+        fields.push(("author", bibtex_value(&authors.join(" and "))));
+    }
+    if let Some(year) = year {
+        fields.push(("year", year.to_string()));
+    }
+
+    let fields = fields
+        .iter()
+        .map(|(name, value)| format!("  {name} = {{{value}}}"))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!(
+        "```bibtex\n@book{{{},\n{fields}\n}}\n```\n\n",
+        cite_key(book, year)
+    )
+}
+
+/// The TOC `match_id` sharing the longest prefix with a ContentID, to show
+/// which entry an uncategorized highlight came closest to matching.
+pub fn nearest_match_id<'a>(toc: &'a [TocEntry], content_id: &str) -> Option<&'a str> {
+    let content_id = match_key(content_id);
+    toc.iter()
+        .map(|entry| {
+            let common = match_key(&entry.match_id)
+                .chars()
+                .zip(content_id.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            (common, entry.match_id.as_str())
+        })
+        .max_by_key(|&(common, _)| common)
+        .map(|(_, match_id)| match_id)
+}
+
+/// Determine which TOC headings need to be emitted: every entry with assigned
+/// highlights, plus all of its ancestors (entries at shallower depth that
+/// precede it) so the hierarchy stays intact.
+fn headings_needed(toc: &[TocEntry], assigned: &HashMap<usize, Vec<&Highlight>>) -> HashSet<usize> {
+    let mut heading_needed = HashSet::new();
+    for (i, _entry) in toc.iter().enumerate() {
+        if assigned.contains_key(&i) {
+            heading_needed.insert(i);
+            // Walk backwards to find and mark all ancestor headings
+            let current_depth = toc[i].depth;
+            let mut need_depth = current_depth;
+            for j in (0..i).rev() {
+                if toc[j].depth < need_depth {
+                    heading_needed.insert(j);
+                    need_depth = toc[j].depth;
+                    if need_depth <= 1 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    heading_needed
+}
+
+/// Append the highlights of one section. With `group_by_color`, they are split
+/// into color groups (ordered by color code, uncolored last) under headings one
+/// level below the section's `level`.
+fn push_markdown_highlights(
+    md: &mut String,
+    highlights: &[&Highlight],
+    level: usize,
+    opts: &RenderOptions,
+) {
+    if !opts.group_by_color {
+        for h in highlights {
+            md.push_str(&format_highlight(h, opts));
+            md.push('\n');
+        }
+        return;
+    }
+
+    // The group heading already names the color
+    let item_opts = RenderOptions {
+        color: false,
+        ..opts.clone()
+    };
+    let mut colors: Vec<Option<i64>> = highlights.iter().map(|h| h.color).collect();
+    colors.sort_by_key(|c| (c.is_none(), *c));
+    colors.dedup();
+
+    let hashes = heading_hashes(level + 1, opts);
+    for color in colors {
+        md.push_str(&format!("{hashes} {}\n\n", color_group_label(color)));
+        for h in highlights.iter().filter(|h| h.color == color) {
+            md.push_str(&format_highlight(h, &item_opts));
+            md.push('\n');
+        }
+    }
+}
+
+/// The book's title, or "Untitled" if it is empty.
+fn display_title(book: &Book) -> &str {
+    if book.title.is_empty() {
+        UNTITLED
+    } else {
+        &book.title
+    }
+}
+
+/// Render a book as Markdown, placing each highlight under its TOC heading,
+/// or through `opts.template` when one is set.
+pub fn generate_markdown(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    let opts = &*book_opts(book, opts);
+    let mut md = String::new();
+
+    if let Some(ref exported) = opts.frontmatter {
+        md.push_str(&generate_frontmatter(
+            book,
+            highlights.len(),
+            exported,
+            &opts.tags,
+        ));
+    } else {
+        let tags = sanitized_tags(&opts.tags);
+        if !tags.is_empty() {
+            let line: Vec<String> = tags.iter().map(|t| format!("#{t}")).collect();
+            md.push_str(&format!("{}\n\n", line.join(" ")));
+        }
+    }
+    if opts.bibtex {
+        md.push_str(&generate_bibtex(book));
+    }
+
+    if let Some(ref template) = opts.template {
+        md.push_str(&render_template(template, book, toc, highlights, opts));
+        return md;
+    }
+
+    // Header
+    md.push_str(&format!(
+        "{} {}\n\n",
+        heading_hashes(1, opts),
+        display_title(book)
+    ));
+    if let Some((label, authors)) = author_line(book) {
+        md.push_str(&format!("**{label}:** {authors}\n\n"));
+    }
+    if let Some(percent) = book.percent_read {
+        md.push_str(&format!("**Progress:** {percent}%\n\n"));
+    }
+    let mut headings = Vec::new();
+    let chapters = markdown_chapters(toc, highlights, opts, &mut headings);
+    if opts.toc && !headings.is_empty() {
+        md.push_str(&markdown_toc(&md, &chapters, &headings));
+    }
+    md.push_str("---\n\n");
+    md.push_str(&chapters);
+
+    if opts.stats {
+        let stats = HighlightStats::from_highlights(highlights);
+        md.push_str(&format!(
+            "---\n\n**Highlights:** {} · **Words:** {} · **Annotated:** {}\n",
+            stats.highlights, stats.words, stats.annotated
+        ));
+    }
+
+    md
+}
+
+/// Indices of `toc` in the order its entries are written: TOC order, or with
+/// `reverse` the siblings at every level in reverse, so each heading still
+/// comes before its sections.
+fn toc_order(toc: &[TocEntry], reverse: bool) -> Vec<usize> {
+    fn push_reversed(toc: &[TocEntry], start: usize, end: usize, order: &mut Vec<usize>) {
+        // Each subtree is an entry followed by the deeper entries below it
+        let mut subtrees = Vec::new();
+        let mut i = start;
+        while i < end {
+            let mut j = i + 1;
+            while j < end && toc[j].depth > toc[i].depth {
+                j += 1;
+            }
+            subtrees.push((i, j));
+            i = j;
+        }
+        for (root, subtree_end) in subtrees.into_iter().rev() {
+            order.push(root);
+            push_reversed(toc, root + 1, subtree_end, order);
+        }
+    }
+
+    if !reverse {
+        return (0..toc.len()).collect();
+    }
+    let mut order = Vec::with_capacity(toc.len());
+    push_reversed(toc, 0, toc.len(), &mut order);
+    order
+}
+
+/// " (N)" after a heading with `--chapter-counts`. Headings emitted only as
+/// context for their subsections get no count.
+fn heading_count(highlights: Option<&Vec<&Highlight>>, opts: &RenderOptions) -> String {
+    match highlights {
+        Some(hl) if opts.chapter_counts => format!(" ({})", hl.len()),
+        _ => String::new(),
+    }
+}
+
+/// A chapter heading `markdown_chapters` wrote, for the `--toc` list.
+struct EmittedHeading {
+    title: String,
+    depth: u32,
+    /// The heading as written, a bold line when it is too deep for one.
+    line: String,
+}
+
+/// GitHub's anchor for a heading: lowercased, with spaces turned into `-`
+/// and punctuation other than `-` and `_` dropped.
+pub fn heading_anchor(text: &str) -> String {
+    text.trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The text of a Markdown heading line, `None` for other lines.
+fn heading_text(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+    ((1..=MAX_HEADING_LEVEL).contains(&level) && text.starts_with(' ')).then(|| text.trim())
+}
+
+/// The `--toc` list of the emitted chapter headings, indented by depth. The
+/// anchors are found by walking every heading of the document in order, so
+/// repeated headings get the `-1`, `-2` suffixes renderers give them.
+/// Headings too deep to be written as headings are listed without a link.
+fn markdown_toc(header: &str, chapters: &str, headings: &[EmittedHeading]) -> String {
+    let mut used: HashMap<String, usize> = HashMap::new();
+    let mut anchors = Vec::with_capacity(headings.len());
+    let mut pending = headings.iter().peekable();
+    for line in header.lines().chain(chapters.lines()) {
+        while pending
+            .next_if(|h| heading_text(&h.line).is_none())
+            .is_some()
+        {
+            anchors.push(None);
+        }
+        let Some(text) = heading_text(line) else {
+            continue;
+        };
+        let base = heading_anchor(text);
+        let seen = used.entry(base.clone()).or_insert(0);
+        let anchor = match *seen {
+            0 => base,
+            n => format!("{base}-{n}"),
+        };
+        *seen += 1;
+        if pending.next_if(|h| h.line.trim_end() == line).is_some() {
+            anchors.push(Some(anchor));
+        }
+    }
+    anchors.extend(pending.map(|_| None));
+
+    let min_depth = headings.iter().map(|h| h.depth).min().unwrap_or(1);
+    let mut md = String::new();
+    for (heading, anchor) in headings.iter().zip(anchors) {
+        let indent = "  ".repeat((heading.depth - min_depth) as usize);
+        let title = heading.title.replace('[', "\\[").replace(']', "\\]");
+        match anchor {
+            Some(anchor) => md.push_str(&format!("{indent}- [{title}](#{anchor})\n")),
+            None => md.push_str(&format!("{indent}- {title}\n")),
+        }
+    }
+    md.push('\n');
+    md
+}
+
+/// The chapter headings and their highlights, followed by the uncategorized
+/// ones: everything `generate_markdown` writes below the book header. Each
+/// chapter heading written is added to `headings`.
+fn markdown_chapters(
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+    headings: &mut Vec<EmittedHeading>,
+) -> String {
+    if opts.group_by == GroupBy::Date {
+        return markdown_days(toc, highlights, opts);
+    }
+    let mut md = String::new();
+    let (assigned, uncategorized) = assign_for_render(toc, highlights, opts);
+    let heading_needed = if opts.flatten {
+        assigned.keys().copied().collect()
+    } else {
+        headings_needed(toc, &assigned)
+    };
+
+    // Walk TOC in VolumeIndex order (siblings reversed for newest_first)
+    for i in toc_order(toc, opts.newest_first) {
+        let entry = &toc[i];
+        if !heading_needed.contains(&i) || entry.title.is_empty() {
+            continue;
+        }
+
+        // depth 1 → ## (2 hashes), depth 2 → ### (3 hashes), etc.
+        // # is reserved for the book title, so heading level = depth + 1
+        let level = if opts.flatten {
+            2
+        } else {
+            (entry.depth + 1) as usize
+        };
+        let heading = chapter_heading(&entry.title, level, entry.depth, assigned.get(&i), opts);
+        md.push_str(&heading);
+        headings.push(EmittedHeading {
+            title: entry.title.clone(),
+            depth: if opts.flatten { 1 } else { entry.depth },
+            line: heading,
+        });
+
+        if let Some(hl) = assigned.get(&i) {
+            push_markdown_highlights(&mut md, hl, level, opts);
+        }
+    }
+
+    if !uncategorized.is_empty() {
+        if !opts.uncategorized_label.is_empty() {
+            md.push_str(&chapter_heading(
+                &opts.uncategorized_label,
+                2,
+                1,
+                Some(&uncategorized),
+                opts,
+            ));
+        }
+        push_markdown_highlights(&mut md, &uncategorized, 2, opts);
+    }
+
+    md
+}
+
+/// Heading for highlights without a readable date in `--group-by date`.
+pub const UNDATED: &str = "Undated";
+
+/// The highlights under a `## YYYY-MM-DD` heading per day they were made,
+/// oldest day first (newest with `newest_first`), each followed by the
+/// chapter it is in. Highlights without a readable date come last.
+fn markdown_days(toc: &[TocEntry], highlights: &[Highlight], opts: &RenderOptions) -> String {
+    let (assigned, uncategorized) = assign_for_render(toc, highlights, opts);
+    let mut items: Vec<(Option<&str>, &Highlight)> = Vec::with_capacity(highlights.len());
+    for (i, entry) in toc.iter().enumerate() {
+        if let Some(hl) = assigned.get(&i) {
+            let chapter = Some(entry.title.as_str()).filter(|t| !t.is_empty());
+            items.extend(hl.iter().map(|h| (chapter, *h)));
+        }
+    }
+    items.extend(uncategorized.into_iter().map(|h| (None, h)));
+
+    let mut dated: Vec<_> = items
+        .into_iter()
+        .map(|(chapter, h)| {
+            let created = h.date_created.as_deref().and_then(parse_kobo_date);
+            (created, chapter, h)
+        })
+        .collect();
+    dated.sort_by_key(|(created, _, _)| (created.is_none(), *created));
+    if opts.newest_first {
+        let undated = dated
+            .iter()
+            .filter(|(created, _, _)| created.is_none())
+            .count();
+        let len = dated.len();
+        dated[..len - undated].reverse();
+    }
+
+    let mut md = String::new();
+    let mut rest = dated.as_slice();
+    while let Some((created, _, _)) = rest.first() {
+        let day = created.map(|dt| dt.date());
+        let len = rest
+            .iter()
+            .take_while(|(other, _, _)| other.map(|dt| dt.date()) == day)
+            .count();
+        let (group, after) = rest.split_at(len);
+        let label = day.map_or(UNDATED.to_string(), |d| d.format("%Y-%m-%d").to_string());
+        let hl: Vec<&Highlight> = group.iter().map(|(_, _, h)| *h).collect();
+        md.push_str(&format!(
+            "{} {label}{}\n\n",
+            heading_hashes(2, opts),
+            heading_count(Some(&hl), opts)
+        ));
+        for (_, chapter, h) in group {
+            md.push_str(&format_highlight(h, opts));
+            if let Some(chapter) = chapter {
+                md.push_str(&format!("\n**Chapter:** {chapter}\n"));
+            }
+            md.push('\n');
+        }
+        rest = after;
+    }
+    md
+}
+
+/// Placeholders a `--chapter-template` can use.
+pub const CHAPTER_PLACEHOLDERS: [&str; 4] = ["depth_marker", "depth", "title", "count"];
+
+/// Check that a `--chapter-template` only uses `CHAPTER_PLACEHOLDERS`.
+pub fn validate_chapter_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start + 1..].find('}') else {
+            return Err("`{` without a closing `}`".to_string());
+        };
+        let name = &rest[start + 1..start + 1 + len];
+        if !CHAPTER_PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "unknown placeholder `{{{name}}}`, expected one of {}",
+                CHAPTER_PLACEHOLDERS.map(|p| format!("{{{p}}}")).join(", ")
+            ));
+        }
+        rest = &rest[start + len + 2..];
+    }
+    Ok(())
+}
+
+/// A chapter heading line and the blank line after it. `level` is the
+/// Markdown heading level before the offset, `depth` the TOC depth.
+fn chapter_heading(
+    title: &str,
+    level: usize,
+    depth: u32,
+    highlights: Option<&Vec<&Highlight>>,
+    opts: &RenderOptions,
+) -> String {
+    // Too deep for a heading, set in bold like a run-in heading
+    let too_deep = level + opts.heading_offset > MAX_HEADING_LEVEL;
+    let Some(ref template) = opts.chapter_template else {
+        let title = format!("{title}{}", heading_count(highlights, opts));
+        return if too_deep {
+            format!("**{title}**\n\n")
+        } else {
+            format!("{} {title}\n\n", heading_hashes(level, opts))
+        };
+    };
+
+    let mut line = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start + 1..].find('}') else {
+            break;
+        };
+        line.push_str(&rest[..start]);
+        match &rest[start + 1..start + 1 + len] {
+            "depth_marker" if !too_deep => line.push_str(&heading_hashes(level, opts)),
+            "depth_marker" => {}
+            "depth" => line.push_str(&depth.to_string()),
+            "title" => line.push_str(title),
+            "count" => line.push_str(&highlights.map_or(0, |hl| hl.len()).to_string()),
+            other => line.push_str(&format!("{{{other}}}")),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    line.push_str(rest);
+    let line = line.trim();
+    if too_deep {
+        format!("**{line}**\n\n")
+    } else {
+        format!("{line}\n\n")
+    }
+}
+
+/// A `## Bookmarks` section listing each dogear with its chapter and position
+/// in the chapter, for `--include-bookmarks`. Empty if there are none.
+pub fn markdown_bookmarks(
+    toc: &[TocEntry],
+    bookmarks: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    if bookmarks.is_empty() {
+        return String::new();
+    }
+
+    let mut md = format!("{} Bookmarks\n\n", heading_hashes(2, opts));
+    for (chapter, bookmark) in highlights_with_chapters(toc, bookmarks, opts.prefer_deepest) {
+        let chapter = chapter.unwrap_or(if opts.uncategorized_label.is_empty() {
+            UNCATEGORIZED
+        } else {
+            &opts.uncategorized_label
+        });
+        md.push_str(&format!("- {chapter}"));
+        if let Some(progress) = bookmark.chapter_progress {
+            md.push_str(&format!(" — {}%", progress_percent(progress)));
+        }
+        md.push('\n');
+    }
+    md.push('\n');
+    md
+}
+
+/// Render a book through a user template, replacing `{{title}}`,
+/// `{{author}}`, `{{progress}}`, `{{highlight_count}}` and `{{highlights}}`
+/// (the chapter headings with their highlights). Unknown placeholders are
+/// left as they are. Substitution is a single pass, so placeholders inside a
+/// title or highlight are not expanded.
+pub fn render_template(
+    template: &str,
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    let value = |name: &str| -> Option<String> {
+        match name {
+            "title" => Some(display_title(book).to_string()),
+            "author" => Some(author_line(book).map(|(_, a)| a).unwrap_or_default()),
+            "progress" => Some(
+                book.percent_read
+                    .map(|p| format!("{p}%"))
+                    .unwrap_or_default(),
+            ),
+            "highlight_count" => Some(highlights.len().to_string()),
+            "highlights" => Some(markdown_chapters(toc, highlights, opts, &mut Vec::new())),
+            _ => None,
+        }
+    };
+
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let placeholder = &rest[start..start + 2 + len + 2];
+        out.push_str(&rest[..start]);
+        match value(placeholder[2..placeholder.len() - 2].trim()) {
+            Some(v) => out.push_str(&v),
+            None => out.push_str(placeholder),
+        }
+        rest = &rest[start + placeholder.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn format_org_highlight(h: &Highlight, opts: &RenderOptions) -> String {
+    let mut out = String::new();
+    if !h.text.is_empty() {
+        out.push_str("#+begin_quote\n");
+        for line in highlight_text(h, opts).lines() {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str("#+end_quote\n");
+    }
+
+    if let Some(ref note) = h.annotation {
+        if !note.is_empty() {
+            out.push_str(&format!("- Note :: {note}\n"));
+        }
+    }
+
+    if let Some(meta) = format_metadata(h, opts, |date| format!("/{date}/")) {
+        out.push_str(&format!("\n{meta}\n"));
+    }
+
+    out
+}
+
+/// Org-mode counterpart of `generate_markdown`: the book becomes `#+TITLE:`,
+/// so TOC depth 1 maps to a `*` heading, depth 2 to `**`, and so on.
+pub fn generate_org(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    let opts = &*book_opts(book, opts);
+    let mut org = format!("#+TITLE: {}\n", display_title(book));
+    if let Some(ref language) = book.language {
+        org.push_str(&format!("#+LANGUAGE: {language}\n"));
+    }
+    if let Some((_, authors)) = author_line(book) {
+        org.push_str(&format!("#+AUTHOR: {authors}\n"));
+    }
+    org.push('\n');
+
+    let (assigned, uncategorized) = assign_for_render(toc, highlights, opts);
+    let heading_needed = headings_needed(toc, &assigned);
+
+    for (i, entry) in toc.iter().enumerate() {
+        if !heading_needed.contains(&i) || entry.title.is_empty() {
+            continue;
+        }
+
+        let stars = "*".repeat(entry.depth.max(1) as usize);
+        org.push_str(&format!("{stars} {}\n\n", entry.title));
+
+        if let Some(hl) = assigned.get(&i) {
+            for h in hl {
+                org.push_str(&format_org_highlight(h, opts));
+                org.push('\n');
+            }
+        }
+    }
+
+    if !uncategorized.is_empty() {
+        if !opts.uncategorized_label.is_empty() {
+            org.push_str(&format!("* {}\n\n", opts.uncategorized_label));
+        }
+        for h in &uncategorized {
+            org.push_str(&format_org_highlight(h, opts));
+            org.push('\n');
+        }
+    }
+
+    org
+}
+
+/// Escape text for use in HTML element content and attribute values.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+const HTML_STYLE: &str = "body { font-family: Georgia, serif; max-width: 42em; margin: 2em auto; padding: 0 1em; line-height: 1.5; color: #222; }
+details { margin: 0.5em 0 0.5em 1em; }
+summary { cursor: pointer; font-weight: bold; font-size: 1.1em; margin: 0.5em 0; }
+blockquote { margin: 1em 0; padding: 0.5em 1em; border-left: 4px solid #c9a227; background: #faf8f0; }
+.note { margin: 0.25em 0 0.25em 1em; }
+.meta { margin: 0.25em 0 1em 1em; color: #777; font-size: 0.9em; }";
+
+fn format_html_highlight(h: &Highlight, opts: &RenderOptions) -> String {
+    let mut out = String::new();
+    if !h.text.is_empty() {
+        let lines: Vec<String> = highlight_text(h, opts).lines().map(escape_html).collect();
+        out.push_str(&format!(
+            "<blockquote>{}</blockquote>\n",
+            lines.join("<br>\n")
+        ));
+    }
+
+    if let Some(ref note) = h.annotation {
+        if !note.is_empty() {
+            out.push_str(&format!(
+                "<p class=\"note\"><strong>Note:</strong> {}</p>\n",
+                escape_html(note)
+            ));
+        }
+    }
+
+    if let Some(meta) = format_metadata(h, opts, |date| format!("<em>{}</em>", escape_html(date))) {
+        out.push_str(&format!("<p class=\"meta\">{meta}</p>\n"));
+    }
+
+    out
+}
+
+/// Standalone HTML document for a book. Each emitted TOC heading becomes a
+/// collapsible `<details>` section, nested according to its depth. The
+/// book's language sets `lang` and, for right-to-left languages, `dir`.
+pub fn generate_html(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    let title = escape_html(display_title(book));
+    let mut html = String::from("<!DOCTYPE html>\n<html");
+    if let Some(ref language) = book.language {
+        html.push_str(&format!(" lang=\"{}\"", escape_html(language)));
+    }
+    if book_is_rtl(book) {
+        html.push_str(" dir=\"rtl\"");
+    }
+    html.push_str(">\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{title}</title>\n"));
+    html.push_str(&format!("<style>\n{HTML_STYLE}\n</style>\n"));
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>{title}</h1>\n"));
+    if let Some((label, authors)) = author_line(book) {
+        html.push_str(&format!(
+            "<p><strong>{label}:</strong> {}</p>\n",
+            escape_html(&authors)
+        ));
+    }
+    if let Some(percent) = book.percent_read {
+        html.push_str(&format!("<p><strong>Progress:</strong> {percent}%</p>\n"));
+    }
+
+    let (assigned, uncategorized) = assign_for_render(toc, highlights, opts);
+    let heading_needed = headings_needed(toc, &assigned);
+
+    // Depths of the currently open <details> sections
+    let mut open: Vec<u32> = Vec::new();
+    for (i, entry) in toc.iter().enumerate() {
+        if !heading_needed.contains(&i) || entry.title.is_empty() {
+            continue;
+        }
+
+        while open.last().is_some_and(|&depth| depth >= entry.depth) {
+            html.push_str("</details>\n");
+            open.pop();
+        }
+        html.push_str(&format!(
+            "<details open>\n<summary>{}</summary>\n",
+            escape_html(&entry.title)
+        ));
+        open.push(entry.depth);
+
+        if let Some(hl) = assigned.get(&i) {
+            for h in hl {
+                html.push_str(&format_html_highlight(h, opts));
+            }
+        }
+    }
+    for _ in open {
+        html.push_str("</details>\n");
+    }
+
+    if !uncategorized.is_empty() {
+        let label = &opts.uncategorized_label;
+        if !label.is_empty() {
+            html.push_str(&format!(
+                "<details open>\n<summary>{}</summary>\n",
+                escape_html(label)
+            ));
+        }
+        for h in &uncategorized {
+            html.push_str(&format_html_highlight(h, opts));
+        }
+        if !label.is_empty() {
+            html.push_str("</details>\n");
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Titles of the TOC entry at `index` and the entries it is nested in,
+/// outermost first. Untitled entries are left out.
+fn toc_path(toc: &[TocEntry], index: usize) -> Vec<&str> {
+    let mut path = vec![toc[index].title.as_str()];
+    let mut depth = toc[index].depth;
+    for entry in toc[..index].iter().rev() {
+        if entry.depth < depth {
+            path.push(&entry.title);
+            depth = entry.depth;
+        }
+    }
+    path.retain(|title| !title.is_empty());
+    path.reverse();
+    path
+}
+
+fn format_notion_highlight(h: &Highlight, opts: &RenderOptions) -> String {
+    let mut out = String::new();
+    let mut text = highlight_text(h, opts);
+    if opts.escape_markdown {
+        text = escape_markdown(&text).into();
+    }
+    // Each paragraph is a quote of its own, as Notion splits quotes with
+    // blank lines inside them into nested blocks
+    let blocks: Vec<String> = paragraphs(&text)
+        .iter()
+        .map(|lines| {
+            if is_underline(h) {
+                format!("*{}*\n", lines.join(" "))
+            } else {
+                lines.iter().map(|line| format!("> {line}\n")).collect()
+            }
+        })
+        .collect();
+    out.push_str(&blocks.join("\n"));
+
+    // Notion imports <aside> blocks as callouts
+    if let Some(note) = h.annotation.as_deref().filter(|n| !n.is_empty()) {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!("<aside>\n💬 {}\n</aside>\n", note.trim()));
+    }
+
+    if let Some(meta) = format_metadata(h, opts, |date| format!("*{date}*")) {
+        out.push_str(&format!("\n{meta}\n"));
+    }
+    out
+}
+
+/// Markdown laid out for Notion's importer, which mangles nested structure.
+/// Every chapter with highlights gets a `##` heading naming its place in the
+/// TOC, e.g. "Part One › Chapter 2", passages are single-level quotes and
+/// notes are `<aside>` blocks, which Notion turns into callouts.
+pub fn generate_notion(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    opts: &RenderOptions,
+) -> String {
+    let opts = &*book_opts(book, opts);
+    let mut md = format!("# {}\n\n", display_title(book));
+    if let Some((label, authors)) = author_line(book) {
+        md.push_str(&format!("**{label}:** {authors}\n\n"));
+    }
+
+    let (assigned, uncategorized) = assign_for_render(toc, highlights, opts);
+    for i in toc_order(toc, opts.newest_first) {
+        let Some(hl) = assigned.get(&i) else {
+            continue;
+        };
+        let path = toc_path(toc, i);
+        if !path.is_empty() {
+            md.push_str(&format!("## {}\n\n", path.join(" › ")));
+        }
+        for h in hl {
+            md.push_str(&format_notion_highlight(h, opts));
+            md.push('\n');
+        }
+    }
+
+    if !uncategorized.is_empty() {
+        if !opts.uncategorized_label.is_empty() {
+            md.push_str(&format!("## {}\n\n", opts.uncategorized_label));
+        }
+        for h in &uncategorized {
+            md.push_str(&format_notion_highlight(h, opts));
+            md.push('\n');
+        }
+    }
+    md
+}
+
+#[derive(Serialize)]
+struct JsonBook<'a> {
+    title: &'a str,
+    author: Option<&'a str>,
+    language: Option<&'a str>,
+    highlights: Vec<JsonHighlight<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonHighlight<'a> {
+    text: &'a str,
+    annotation: Option<&'a str>,
+    date_created: Option<String>,
+    /// Title of the TOC entry the highlight was assigned to, `None` if uncategorized.
+    chapter: Option<&'a str>,
+}
+
+impl<'a> JsonHighlight<'a> {
+    fn new(h: &'a Highlight, chapter: Option<&'a str>) -> Self {
+        JsonHighlight {
+            text: &h.text,
+            annotation: h.annotation.as_deref().filter(|a| !a.is_empty()),
+            date_created: h.date_created.as_deref().map(rfc3339_date),
+            chapter,
+        }
+    }
+}
+
+/// Pair each highlight with the title of the TOC entry it was assigned to
+/// (`None` if uncategorized), in the same order as the Markdown output: by TOC
+/// entry in VolumeIndex order, followed by the uncategorized ones.
+fn highlights_with_chapters<'a>(
+    toc: &'a [TocEntry],
+    highlights: &'a [Highlight],
+    prefer_deepest: bool,
+) -> Vec<(Option<&'a str>, &'a Highlight)> {
+    let (assigned, uncategorized) = assign_highlights(toc, highlights, prefer_deepest);
+
+    let mut items = Vec::with_capacity(highlights.len());
+    for (i, entry) in toc.iter().enumerate() {
+        if let Some(hl) = assigned.get(&i) {
+            let chapter = Some(entry.title.as_str()).filter(|t| !t.is_empty());
+            items.extend(hl.iter().map(|h| (chapter, *h)));
+        }
+    }
+    items.extend(uncategorized.into_iter().map(|h| (None, h)));
+    items
+}
+
+/// Serialize a book and its highlights as a single JSON object.
+pub fn generate_json(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    prefer_deepest: bool,
+) -> serde_json::Result<String> {
+    let items = highlights_with_chapters(toc, highlights, prefer_deepest)
+        .into_iter()
+        .map(|(chapter, h)| JsonHighlight::new(h, chapter))
+        .collect();
+
+    let json_book = JsonBook {
+        title: &book.title,
+        author: book.author.as_deref().filter(|a| !a.is_empty()),
+        language: book.language.as_deref(),
+        highlights: items,
+    };
+
+    let mut json = serde_json::to_string_pretty(&json_book)?;
+    json.push('\n');
+    Ok(json)
+}
+
+/// One line of `--format jsonl` output: a highlight with its book inlined.
+#[derive(Serialize)]
+struct JsonlHighlight<'a> {
+    book_title: &'a str,
+    author: Option<&'a str>,
+    chapter: Option<&'a str>,
+    text: &'a str,
+    annotation: Option<&'a str>,
+    date: Option<String>,
+}
+
+/// Serialize each highlight as a flat JSON object on its own line, so the
+/// output of several books can simply be concatenated.
+pub fn generate_jsonl(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    prefer_deepest: bool,
+) -> serde_json::Result<String> {
+    let mut out = String::new();
+    for (chapter, h) in highlights_with_chapters(toc, highlights, prefer_deepest) {
+        let line = JsonlHighlight {
+            book_title: &book.title,
+            author: book.author.as_deref().filter(|a| !a.is_empty()),
+            chapter,
+            text: &h.text,
+            annotation: h.annotation.as_deref().filter(|a| !a.is_empty()),
+            date: h.date_created.as_deref().map(rfc3339_date),
+        };
+        out.push_str(&serde_json::to_string(&line)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Tables of the `--format sqlite` output database. Dates are RFC 3339 as in
+/// the JSON output, and `position` is the highlight's place in the export
+/// order of its book.
+const SQLITE_SCHEMA: &str = "CREATE TABLE books (
+    id INTEGER PRIMARY KEY,
+    content_id TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    author TEXT,
+    language TEXT,
+    percent_read INTEGER,
+    last_read TEXT,
+    publication_date TEXT,
+    date_added TEXT
+);
+CREATE TABLE highlights (
+    id INTEGER PRIMARY KEY,
+    book_id INTEGER NOT NULL REFERENCES books (id),
+    bookmark_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    chapter TEXT,
+    text TEXT NOT NULL,
+    annotation TEXT,
+    date_created TEXT,
+    chapter_progress REAL,
+    color TEXT
+);
+CREATE INDEX highlights_book_id ON highlights (book_id);";
+
+/// Create the `books` and `highlights` tables of the `--format sqlite` output
+/// in an empty database.
+pub fn create_sqlite_export(conn: &Connection) -> SqlResult<()> {
+    conn.execute_batch(SQLITE_SCHEMA)
+}
+
+/// Add a book and its highlights to a database set up by
+/// `create_sqlite_export`, with each highlight's chapter title resolved as in
+/// the JSON output (`NULL` for uncategorized highlights).
+pub fn insert_sqlite_book(
+    conn: &Connection,
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    prefer_deepest: bool,
+) -> SqlResult<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO books (content_id, title, author, language, percent_read, last_read,
+                            publication_date, date_added)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        rusqlite::params![
+            book.content_id,
+            book.title,
+            book.author.as_deref().filter(|a| !a.is_empty()),
+            book.language,
+            book.percent_read,
+            book.last_read.as_deref().map(rfc3339_date),
+            book.publication_date.as_deref().map(rfc3339_date),
+            book.date_added.as_deref().map(rfc3339_date),
+        ],
+    )?;
+    let book_id = tx.last_insert_rowid();
+
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO highlights (book_id, bookmark_id, position, chapter, text, annotation,
+                                     date_created, chapter_progress, color)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        let items = highlights_with_chapters(toc, highlights, prefer_deepest);
+        for (position, (chapter, h)) in items.into_iter().enumerate() {
+            stmt.execute(rusqlite::params![
+                book_id,
+                h.id,
+                position as i64,
+                chapter,
+                h.text,
+                h.annotation.as_deref().filter(|a| !a.is_empty()),
+                h.date_created.as_deref().map(rfc3339_date),
+                h.chapter_progress,
+                h.color.map(color_name),
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Encode text as an Anki import field: HTML-escaped, with line breaks as
+/// `<br>` and tabs (the field separator) as spaces.
+fn anki_field(text: &str) -> String {
+    escape_html(text)
+        .lines()
+        .collect::<Vec<_>>()
+        .join("<br>")
+        .replace('\t', " ")
+}
+
+/// Tab-separated flashcards for importing into Anki, one per highlight: the
+/// highlighted text on the front and "Book — Chapter — Annotation" on the
+/// back. Standalone notes have nothing to put on the front and are skipped.
+pub fn generate_anki(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    prefer_deepest: bool,
+) -> String {
+    let mut out = String::new();
+    for (chapter, h) in highlights_with_chapters(toc, highlights, prefer_deepest) {
+        if h.text.trim().is_empty() {
+            continue;
+        }
+        let back: Vec<&str> = [
+            Some(display_title(book)),
+            chapter,
+            h.annotation.as_deref().filter(|a| !a.is_empty()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        out.push_str(&format!(
+            "{}\t{}\n",
+            anki_field(&h.text),
+            anki_field(&back.join(" — "))
+        ));
+    }
+    out
+}
+
+const CSV_HEADER: [&str; 6] = ["book", "author", "chapter", "text", "annotation", "date"];
+
+/// Write one CSV row per highlight. The header row is only emitted when
+/// `header` is set, so the rows of several books can be concatenated.
+pub fn generate_csv(
+    book: &Book,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    header: bool,
+    prefer_deepest: bool,
+) -> csv::Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    if header {
+        writer.write_record(CSV_HEADER)?;
+    }
+
+    let author = book.author.as_deref().unwrap_or("");
+    for (chapter, h) in highlights_with_chapters(toc, highlights, prefer_deepest) {
+        let date = h.date_created.as_deref().map(rfc3339_date);
+        writer.write_record([
+            book.title.as_str(),
+            author,
+            chapter.unwrap_or(""),
+            h.text.as_str(),
+            h.annotation.as_deref().unwrap_or(""),
+            date.as_deref().unwrap_or(""),
+        ])?;
+    }
+
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8(bytes).expect("CSV built from UTF-8 strings"))
+}
+
+/// Whether any highlight was created after `last_run`. Highlights without a
+/// `DateCreated` can't be placed in time and always count as new.
+pub fn has_new_highlights(highlights: &[Highlight], last_run: &str) -> bool {
+    highlights
+        .iter()
+        .any(|h| h.date_created.as_deref().is_none_or(|d| d > last_run))
+}
+
+/// Concatenate per-book Markdown documents, separating books with a `---` rule.
+/// Each document already starts with its own `# Book Title` heading.
+fn combine_markdown(documents: &[String]) -> String {
+    documents.join("---\n\n")
+}
+
+/// An exported book as listed by `--index`.
+pub struct IndexEntry {
+    pub title: String,
+    pub author: Option<String>,
+    /// Path of the exported file relative to the output directory, with `/`
+    /// separators.
+    pub link: String,
+    pub highlights: usize,
+}
+
+/// Markdown index of the exported books, grouped under one heading per
+/// author (alphabetically, books without an author last) and sorted by title
+/// within each group.
+pub fn generate_index(entries: &[IndexEntry]) -> String {
+    let mut by_author: Vec<(Option<&str>, Vec<&IndexEntry>)> = Vec::new();
+    for entry in entries {
+        let author = entry.author.as_deref().filter(|a| !a.trim().is_empty());
+        match by_author.iter_mut().find(|(a, _)| *a == author) {
+            Some((_, books)) => books.push(entry),
+            None => by_author.push((author, vec![entry])),
+        }
+    }
+    by_author.sort_by_key(|(author, _)| (author.is_none(), author.map(str::to_lowercase)));
+
+    let mut md = String::from("# Highlights\n\n");
+    for (author, mut books) in by_author {
+        books.sort_by_key(|b| b.title.to_lowercase());
+        md.push_str(&format!("## {}\n\n", author.unwrap_or(UNKNOWN_AUTHOR)));
+        for book in books {
+            md.push_str(&format!(
+                "- [{}](<{}>) ({} highlights)\n",
+                book.title, book.link, book.highlights
+            ));
+        }
+        md.push('\n');
+    }
+    md
+}
+
+/// Merge the per-book documents of a `--single-file` export.
+pub fn combine_documents(format: OutputFormat, documents: &[String]) -> String {
+    match format {
+        OutputFormat::Markdown => combine_markdown(documents),
+        // Only the first CSV book carries the header row, and JSON Lines
+        // documents are already one record per line
+        _ => documents.concat(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // --- strip_suffix ---
+
+    #[test]
+    fn strip_suffix_removes_trailing_digits() {
+        assert_eq!(
+            strip_suffix("book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4-2"),
+            "book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"
+        );
+    }
+
+    #[test]
+    fn strip_suffix_removes_single_digit() {
+        assert_eq!(
+            strip_suffix("book.epub!OPS!xhtml/Cover.xhtml-1"),
+            "book.epub!OPS!xhtml/Cover.xhtml"
+        );
+    }
+
+    #[test]
+    fn strip_suffix_no_suffix_unchanged() {
+        assert_eq!(
+            strip_suffix("book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"),
+            "book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"
+        );
+    }
+
+    #[test]
+    fn strip_suffix_dash_not_followed_by_digits() {
+        assert_eq!(
+            strip_suffix("some-path/file.xhtml#section-abc"),
+            "some-path/file.xhtml#section-abc"
+        );
+    }
+
+    #[test]
+    fn strip_suffix_empty_string() {
+        assert_eq!(strip_suffix(""), "");
+    }
+
+    // --- extract_depth ---
+
+    #[test]
+    fn extract_depth_single_digit() {
+        assert_eq!(extract_depth("book.epub!xhtml/Cover.xhtml-1"), 1);
+    }
+
+    #[test]
+    fn extract_depth_multi_digit() {
+        assert_eq!(extract_depth("book.epub!xhtml/Chapter01.xhtml#ch01_4-2"), 2);
+    }
+
+    #[test]
+    fn extract_depth_deep_level() {
+        assert_eq!(extract_depth("book.epub!Text/wahl.html#sigil_toc_id_6-4"), 4);
+    }
+
+    #[test]
+    fn extract_depth_no_suffix_defaults_to_1() {
+        assert_eq!(extract_depth("book.epub!xhtml/Chapter01.xhtml#ch01_4"), 1);
+    }
+
+    #[test]
+    fn extract_depth_dash_not_digits() {
+        assert_eq!(extract_depth("some-path/file.xhtml#section-abc"), 1);
+    }
+
+    // --- normalize_depths ---
+
+    #[test]
+    fn normalize_depths_closes_gaps() {
+        let toc = normalize_depths(make_toc(&[
+            ("Part", "p", 1),
+            ("Section", "s1", 3),
+            ("Subsection", "s1a", 5),
+            ("Section 2", "s2", 3),
+            ("Chapter", "c", 2),
+            ("Part 2", "p2", 1),
+            ("Deep", "d", 4),
+        ]));
+        let depths: Vec<u32> = toc.iter().map(|e| e.depth).collect();
+        assert_eq!(depths, [1, 2, 3, 2, 2, 1, 2]);
+    }
+
+    #[test]
+    fn generate_markdown_normalized_depth_jump() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Chapter", "book!ch01.xhtml", 1),
+            ("Section", "book!s1.xhtml", 3),
+        ]);
+        let highlights = vec![make_highlight("text", "book!s1.xhtml")];
+        let opts = RenderOptions::default();
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains("## Chapter\n\n#### Section\n"), "{md}");
+        let md = generate_markdown(&book, &normalize_depths(toc), &highlights, &opts);
+        assert!(md.contains("## Chapter\n\n### Section\n"), "{md}");
+    }
+
+    // --- sanitize_filename ---
+
+    #[test]
+    fn sanitize_filename_keeps_alphanumeric_spaces_dashes() {
+        assert_eq!(sanitize_filename("Hello World - 2024"), "Hello World - 2024");
+    }
+
+    #[test]
+    fn sanitize_filename_removes_special_chars() {
+        assert_eq!(sanitize_filename("Book: A «Story»!"), "Book A Story");
+    }
+
+    #[test]
+    fn sanitize_filename_trims_whitespace() {
+        assert_eq!(sanitize_filename("  Hello  "), "Hello");
+    }
+
+    #[test]
+    fn sanitize_filename_empty_falls_back_to_untitled() {
+        assert_eq!(sanitize_filename(""), "Untitled");
+        assert_eq!(sanitize_filename("«!?»"), "Untitled");
+    }
+
+    // --- contains_ignore_case ---
+
+    #[test]
+    fn contains_ignore_case_matches_any_case() {
+        assert!(contains_ignore_case("The Paper Orchard", "paper"));
+        assert!(contains_ignore_case("The Paper Orchard", "ORCHARD"));
+        assert!(contains_ignore_case("Ärger im Äther", "äRGER"));
+        assert!(!contains_ignore_case("The Paper Orchard", "lantern"));
+    }
+
+    // --- book_dir ---
+
+    #[test]
+    fn book_dir_nests_books_under_sanitized_author() {
+        let out = Path::new("highlights");
+        assert_eq!(
+            book_dir(out, Some("Jane: Doe"), true)
+                .join(format!("{}.md", sanitize_filename("Dune?"))),
+            Path::new("highlights").join("Jane Doe").join("Dune.md")
+        );
+        assert_eq!(book_dir(out, Some("Jane Doe"), false), out);
+    }
+
+    #[test]
+    fn book_dir_without_author_uses_unknown_author() {
+        let out = Path::new("highlights");
+        assert_eq!(book_dir(out, None, true), out.join("Unknown Author"));
+        assert_eq!(book_dir(out, Some("???"), true), out.join("Unknown Author"));
+    }
+
+    #[test]
+    fn author_file_stem_sanitizes_and_falls_back() {
+        assert_eq!(author_file_stem(Some("Jane: Doe")), "Jane Doe");
+        assert_eq!(author_file_stem(Some("???")), UNKNOWN_AUTHOR);
+        assert_eq!(author_file_stem(None), UNKNOWN_AUTHOR);
+    }
+
+    // --- unique_file_stem ---
+
+    #[test]
+    fn unique_file_stem_first_use_unchanged() {
+        let mut used = HashSet::new();
+        assert_eq!(
+            unique_file_stem("Title", Some("Author"), &mut used),
+            "Title"
+        );
+    }
+
+    #[test]
+    fn unique_file_stem_appends_author_then_number() {
+        let mut used = HashSet::new();
+        unique_file_stem("Title", Some("Ann"), &mut used);
+        assert_eq!(
+            unique_file_stem("Title", Some("Bob: B."), &mut used),
+            "Title (Bob B)"
+        );
+        assert_eq!(
+            unique_file_stem("Title", Some("Bob: B."), &mut used),
+            "Title (2)"
+        );
+        assert_eq!(unique_file_stem("Title", None, &mut used), "Title (3)");
+    }
+
+    #[test]
+    fn unique_file_stem_ignores_case() {
+        let mut used = HashSet::new();
+        unique_file_stem("Title", None, &mut used);
+        assert_eq!(unique_file_stem("TITLE", None, &mut used), "TITLE (2)");
+    }
+
+    // --- fallback_title ---
+
+    #[test]
+    fn fallback_title_uses_file_name_without_extension() {
+        assert_eq!(
+            fallback_title("file:///mnt/onboard/Books/My Book.epub"),
+            "My Book"
+        );
+        assert_eq!(fallback_title("C:\\Books\\Other.kepub.epub"), "Other.kepub");
+    }
+
+    #[test]
+    fn fallback_title_without_path_is_untitled() {
+        assert_eq!(
+            fallback_title("0a1b2c3d-4e5f-6789-abcd-ef0123456789"),
+            "Untitled"
+        );
+        assert_eq!(fallback_title("file:///mnt/onboard/"), "Untitled");
+    }
+
+    // --- format_highlight ---
+
+    #[test]
+    fn format_highlight_text_only() {
+        let h = Highlight {
+            text: "Some highlighted text".into(),
+            annotation: None,
+            chapter_content_id: String::new(),
+            date_created: None,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &RenderOptions::default()), "> Some highlighted text\n");
+    }
+
+    #[test]
+    fn format_highlight_with_annotation() {
+        let h = Highlight {
+            text: "Highlighted".into(),
+            annotation: Some("My note".into()),
+            chapter_content_id: String::new(),
+            date_created: None,
+            ..Default::default()
+        };
+        let result = format_highlight(&h, &RenderOptions::default());
+        assert!(result.contains("> Highlighted\n"));
+        assert!(result.contains("**Note:** My note"));
+    }
+
+    #[test]
+    fn format_highlight_with_date() {
+        let h = Highlight {
+            text: "Text".into(),
+            annotation: None,
+            chapter_content_id: String::new(),
+            date_created: Some("2024-01-15T10:30:00".into()),
+            ..Default::default()
+        };
+        let result = format_highlight(&h, &RenderOptions::default());
+        assert!(result.contains("*2024-01-15T10:30:00*"));
+    }
+
+    #[test]
+    fn format_highlight_multiline_text() {
+        let h = Highlight {
+            text: "Line one\nLine two".into(),
+            annotation: None,
+            chapter_content_id: String::new(),
+            date_created: None,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &RenderOptions::default()), "> Line one\n> Line two\n");
+    }
+
+    #[test]
+    fn format_highlight_separates_paragraphs() {
+        let h = make_highlight("First paragraph.\n\n\nSecond paragraph.\n", "id");
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> First paragraph.\n>\n> Second paragraph.\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_drops_leading_and_trailing_blank_lines() {
+        let h = make_highlight("\n  \nOnly paragraph.\n\n", "id");
+        let opts = RenderOptions {
+            clean: false,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "> Only paragraph.\n");
+    }
+
+    #[test]
+    fn format_highlight_underline_is_italic_not_quoted() {
+        let h = Highlight {
+            style: Some(UNDERLINE_STYLE_ID),
+            annotation: Some("note".into()),
+            ..make_highlight("Underlined\nsentence.\n\nMore.", "id")
+        };
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "*Underlined sentence.*\n\n*More.*\n\n**Note:** note\n"
+        );
+
+        let highlighted = Highlight {
+            style: Some(0),
+            ..make_highlight("Highlighted", "id")
+        };
+        assert_eq!(
+            format_highlight(&highlighted, &RenderOptions::default()),
+            "> Highlighted\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_block_id_follows_passage() {
+        let h = Highlight {
+            id: "3F2A9C1E-77B0-4D2A-9E0C-5A1B2C3D4E5F".into(),
+            annotation: Some("note".into()),
+            date_created: Some("2024-01-15".into()),
+            ..make_highlight("Text", "id")
+        };
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> Text\n\n^kobo-3f2a9c1e-77b0-4d2a-9e0c-5a1b2c3d4e5f\n\n**Note:** note\n\n*2024-01-15*\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_block_id_follows_standalone_note() {
+        let h = Highlight {
+            id: "bm1".into(),
+            annotation: Some("Just a thought".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "**Note:** Just a thought\n\n^kobo-bm1\n"
+        );
+    }
+
+    #[test]
+    fn block_id_is_stable_and_sanitized() {
+        let h = Highlight {
+            id: "file:///mnt/onboard/Book.epub_42".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            block_id(&h).as_deref(),
+            Some("kobo-filemntonboardbookepub42")
+        );
+        assert_eq!(block_id(&h), block_id(&h));
+        assert_eq!(block_id(&Highlight::default()), None);
+    }
+
+    #[test]
+    fn format_highlight_empty_annotation_skipped() {
+        let h = Highlight {
+            text: "Text".into(),
+            annotation: Some(String::new()),
+            chapter_content_id: String::new(),
+            date_created: None,
+            ..Default::default()
+        };
+        assert!(!format_highlight(&h, &RenderOptions::default()).contains("**Note:**"));
+    }
+
+    #[test]
+    fn format_highlight_with_color() {
+        let h = Highlight {
+            text: "Text".into(),
+            date_created: Some("2024-01-15".into()),
+            color: Some(2),
+            ..Default::default()
+        };
+        let result = format_highlight(&h, &RenderOptions::default());
+        assert!(result.contains("\n*2024-01-15* [color: blue]\n"));
+    }
+
+    #[test]
+    fn format_highlight_no_color_suppresses_tag() {
+        let h = Highlight {
+            text: "Text".into(),
+            color: Some(1),
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            color: false,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "> Text\n");
+    }
+
+    #[test]
+    fn format_highlight_with_progress_next_to_date() {
+        let h = Highlight {
+            text: "Text".into(),
+            date_created: Some("2024-01-15".into()),
+            chapter_progress: Some(0.4249),
+            ..Default::default()
+        };
+        let result = format_highlight(&h, &RenderOptions::default());
+        assert!(result.contains("\n*2024-01-15* (42%)\n"));
+    }
+
+    #[test]
+    fn format_highlight_no_progress_suppresses_percentage() {
+        let h = Highlight {
+            text: "Text".into(),
+            chapter_progress: Some(0.5),
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            progress: false,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "> Text\n");
+    }
+
+    #[test]
+    fn format_highlight_removes_soft_hyphens() {
+        let h = make_highlight("extra\u{00AD}ordi\u{00AD}nary  tale\u{200B}", "id");
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> extraordinary tale\n"
+        );
+
+        let opts = RenderOptions {
+            clean: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&h, &opts),
+            "> extra\u{00AD}ordi\u{00AD}nary  tale\u{200B}\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_escape_markdown_leaves_annotation_alone() {
+        let h = Highlight {
+            annotation: Some("*really*".into()),
+            ..make_highlight("# Not a heading, *not* [a](link)", "id")
+        };
+        let opts = RenderOptions {
+            escape_markdown: true,
+            ..Default::default()
+        };
+        let result = format_highlight(&h, &opts);
+        assert!(result.starts_with("> \\# Not a heading, \\*not\\* \\[a\\]\\(link\\)\n"));
+        assert!(result.contains("**Note:** *really*\n"));
+        assert!(format_highlight(&h, &RenderOptions::default()).starts_with("> # Not"));
+    }
+
+    // --- quote_style ---
+
+    fn make_noted_highlight() -> Highlight {
+        Highlight {
+            id: "B1".into(),
+            annotation: Some("Worth rereading".into()),
+            date_created: Some("2024-01-15".into()),
+            ..make_highlight("First line.\n\nSecond paragraph.", "id")
+        }
+    }
+
+    #[test]
+    fn format_highlight_blockquote_style() {
+        assert_eq!(
+            format_highlight(&make_noted_highlight(), &RenderOptions::default()),
+            "> First line.\n>\n> Second paragraph.\n\n^kobo-b1\n\n\
+             **Note:** Worth rereading\n\n*2024-01-15*\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_callout_style_keeps_note_and_date_inside() {
+        let opts = RenderOptions {
+            quote_style: QuoteStyle::Callout,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&make_noted_highlight(), &opts),
+            "> [!quote]\n> First line.\n>\n> Second paragraph.\n\
+             >\n> **Note:** Worth rereading\n>\n> *2024-01-15*\n\n^kobo-b1\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_indent_style() {
+        let opts = RenderOptions {
+            quote_style: QuoteStyle::Indent,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&make_noted_highlight(), &opts),
+            "    First line.\n\n    Second paragraph.\n\n^kobo-b1\n\n\
+             **Note:** Worth rereading\n\n*2024-01-15*\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_callout_standalone_note_is_not_a_callout() {
+        let h = Highlight {
+            annotation: Some("Just a note".into()),
+            ..make_highlight("", "id")
+        };
+        let opts = RenderOptions {
+            quote_style: QuoteStyle::Callout,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "**Note:** Just a note\n");
+    }
+
+    #[test]
+    fn format_highlight_shows_location_when_enabled() {
+        let h = Highlight {
+            location: Some("span#kobo\\.12\\.3".into()),
+            ..make_highlight("Text", "id")
+        };
+        assert_eq!(format_highlight(&h, &RenderOptions::default()), "> Text\n");
+        let opts = RenderOptions {
+            locations: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&h, &opts),
+            "> Text\n\n`loc: span#kobo\\.12\\.3`\n"
+        );
+    }
+
+    #[test]
+    fn format_highlight_multiline_note_is_quoted_under_label() {
+        let h = Highlight {
+            annotation: Some("First thought\n- second thought".into()),
+            ..make_highlight("Passage", "id")
+        };
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> Passage\n\n**Note:**\n> First thought\n> - second thought\n"
+        );
+
+        let callout = RenderOptions {
+            quote_style: QuoteStyle::Callout,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&h, &callout),
+            "> [!quote]\n> Passage\n>\n> **Note:**\n> > First thought\n> > - second thought\n"
+        );
+    }
+
+    #[test]
+    fn format_note_keeps_blank_lines_inside_the_note() {
+        let opts = RenderOptions::default();
+        assert_eq!(
+            format_note("One\r\n\r\nTwo\n", &opts),
+            "**Note:**\n> One\n>\n> Two"
+        );
+        // A trailing newline alone doesn't make a note multiline
+        assert_eq!(format_note("Single\n", &opts), "**Note:** Single");
+
+        let tasks = RenderOptions {
+            notes_as_tasks: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_note("Look up\n\nthe source", &tasks),
+            "- [ ] Note: Look up\n\n  the source"
+        );
+    }
+
+    // --- notes_as_tasks ---
+
+    #[test]
+    fn format_highlight_notes_as_tasks() {
+        let opts = RenderOptions {
+            notes_as_tasks: true,
+            ..Default::default()
+        };
+        let h = Highlight {
+            annotation: Some("Look this up".into()),
+            ..make_highlight("Passage", "id")
+        };
+        assert_eq!(
+            format_highlight(&h, &opts),
+            "> Passage\n\n- [ ] Note: Look this up\n"
+        );
+
+        let standalone = Highlight {
+            annotation: Some("Just a thought".into()),
+            ..make_highlight("", "id")
+        };
+        assert_eq!(
+            format_highlight(&standalone, &opts),
+            "- [ ] Note: Just a thought\n"
+        );
+
+        // Highlights without a note are unchanged
+        let plain = make_highlight("Passage", "id");
+        assert_eq!(format_highlight(&plain, &opts), "> Passage\n");
+    }
+
+    #[test]
+    fn format_highlight_notes_as_tasks_in_callout() {
+        let opts = RenderOptions {
+            notes_as_tasks: true,
+            quote_style: QuoteStyle::Callout,
+            ..Default::default()
+        };
+        let h = Highlight {
+            annotation: Some("Look this up".into()),
+            ..make_highlight("Passage", "id")
+        };
+        assert_eq!(
+            format_highlight(&h, &opts),
+            "> [!quote]\n> Passage\n>\n> - [ ] Note: Look this up\n"
+        );
+    }
+
+    // --- escape_markdown ---
+
+    #[test]
+    fn escape_markdown_escapes_special_characters() {
+        assert_eq!(
+            escape_markdown("a*b_c[d]e(f)g#h"),
+            "a\\*b\\_c\\[d\\]e\\(f\\)g\\#h"
+        );
+    }
+
+    #[test]
+    fn escape_markdown_escapes_backslash() {
+        assert_eq!(escape_markdown(r"C:\*"), r"C:\\\*");
+    }
+
+    #[test]
+    fn escape_markdown_keeps_other_punctuation() {
+        assert_eq!(escape_markdown("- well, > 3!"), "- well, > 3!");
+    }
+
+    // --- decode_entities ---
+
+    #[test]
+    fn decode_entities_named_and_numeric() {
+        assert_eq!(
+            decode_entities("Fish &amp; chips&nbsp;&mdash; &#8220;yum&#x201D; &lt;3"),
+            "Fish & chips — “yum” <3"
+        );
+    }
+
+    #[test]
+    fn decode_entities_keeps_unknown_and_bare_ampersands() {
+        assert_eq!(
+            decode_entities("A & B &bogus; &#xZZ; &amp"),
+            "A & B &bogus; &#xZZ; &amp"
+        );
+    }
+
+    #[test]
+    fn strip_tags_removes_inline_tags_only() {
+        assert_eq!(
+            strip_tags("An <i>emphatic</i> <span class=\"x\">word</span>, 1 < 2 <table>"),
+            "An emphatic word, 1 < 2 <table>"
+        );
+    }
+
+    // --- normalize_punctuation ---
+
+    #[test]
+    fn normalize_punctuation_straight() {
+        assert_eq!(
+            normalize_punctuation("“It’s ‘fine’” – she said—twice…", Punctuation::Straight),
+            "\"It's 'fine'\" - she said--twice..."
+        );
+    }
+
+    #[test]
+    fn normalize_punctuation_smart() {
+        assert_eq!(
+            normalize_punctuation(
+                "\"It's 'fine'\" - she said--twice... (\"really\")",
+                Punctuation::Smart
+            ),
+            "“It’s ‘fine’” – she said—twice… (“really”)"
+        );
+        // Curly punctuation is left as it is
+        assert_eq!(
+            normalize_punctuation("“Done”—’tis", Punctuation::Smart),
+            "“Done”—’tis"
+        );
+    }
+
+    #[test]
+    fn format_highlight_normalizes_punctuation_when_enabled() {
+        let h = make_highlight("“Mixed\" quotes", "id");
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> “Mixed\" quotes\n"
+        );
+        let opts = RenderOptions {
+            punctuation: Some(Punctuation::Straight),
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "> \"Mixed\" quotes\n");
+    }
+
+    #[test]
+    fn format_highlight_decodes_entities_when_enabled() {
+        let h = make_highlight("<i>Caf&eacute;</i> &amp; bar", "id");
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "> <i>Caf&eacute;</i> &amp; bar\n"
+        );
+        let opts = RenderOptions {
+            decode_entities: true,
+            strip_tags: true,
+            ..Default::default()
+        };
+        assert_eq!(format_highlight(&h, &opts), "> Caf&eacute; & bar\n");
+    }
+
+    // --- clean_text ---
+
+    #[test]
+    fn clean_text_collapses_whitespace_within_lines() {
+        assert_eq!(
+            clean_text("  one \t two\u{00A0} \nthree  "),
+            "one two\nthree"
+        );
+        assert_eq!(clean_text("\u{200B}\u{FEFF}"), "");
+    }
+
+    #[test]
+    fn clean_text_keeps_line_breaks() {
+        assert_eq!(clean_text("Line one\n\nLine two"), "Line one\n\nLine two");
+    }
+
+    // --- trim ---
+
+    #[test]
+    fn highlight_text_trims_surrounding_whitespace() {
+        let h = make_highlight("\n\n  First line\n\nSecond line \n\n", "id");
+        let opts = RenderOptions {
+            clean: false,
+            ..Default::default()
+        };
+        assert_eq!(highlight_text(&h, &opts), "First line\n\nSecond line");
+        assert_eq!(
+            format_highlight(&h, &opts),
+            "> First line\n>\n> Second line\n"
+        );
+        let book = Book::default();
+        let html = generate_html(&book, &[], std::slice::from_ref(&h), &opts);
+        assert!(html.contains("<blockquote>First line<br>\n<br>\nSecond line</blockquote>"));
+
+        let untrimmed = RenderOptions {
+            trim: false,
+            ..opts
+        };
+        assert_eq!(highlight_text(&h, &untrimmed), h.text);
+        assert!(format_highlight(&h, &untrimmed).starts_with(">   First line\n"));
+    }
+
+    // --- progress_percent ---
+
+    #[test]
+    fn progress_percent_rounds_to_nearest() {
+        assert_eq!(progress_percent(0.0), 0);
+        assert_eq!(progress_percent(0.425), 43);
+        assert_eq!(progress_percent(0.999), 100);
+    }
+
+    #[test]
+    fn progress_percent_clamps_out_of_range() {
+        assert_eq!(progress_percent(-0.2), 0);
+        assert_eq!(progress_percent(1.7), 100);
+    }
+
+    // --- group_by date ---
+
+    #[test]
+    fn generate_markdown_group_by_date() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Chapter 1", "book!ch01.xhtml", 1),
+            ("Chapter 2", "book!ch02.xhtml", 1),
+        ]);
+        let dated = |text: &str, content_id: &str, date: Option<&str>| Highlight {
+            date_created: date.map(Into::into),
+            ..make_highlight(text, content_id)
+        };
+        let highlights = vec![
+            dated("late", "book!ch01.xhtml", Some("2024-06-02T08:00:00")),
+            dated("early", "book!ch02.xhtml", Some("2024-06-01T21:00:00")),
+            dated("mid", "book!ch01.xhtml", Some("2024-06-01T22:00:00")),
+            dated("never", "book!unknown.xhtml", None),
+        ];
+        let opts = RenderOptions {
+            group_by: GroupBy::Date,
+            progress: false,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains(
+            "## 2024-06-01\n\n\
+             > early\n\n*2024-06-01T21:00:00*\n\n**Chapter:** Chapter 2\n\n\
+             > mid\n\n*2024-06-01T22:00:00*\n\n**Chapter:** Chapter 1\n\n\
+             ## 2024-06-02\n\n\
+             > late\n\n*2024-06-02T08:00:00*\n\n**Chapter:** Chapter 1\n\n\
+             ## Undated\n\n> never\n\n"
+        ));
+        assert!(!md.contains("## Chapter 1"));
+    }
+
+    #[test]
+    fn generate_markdown_group_by_date_newest_first_keeps_undated_last() {
+        let book = Book::default();
+        let highlights = vec![
+            Highlight {
+                date_created: Some("2024-06-01T09:00:00".into()),
+                ..make_highlight("old", "x")
+            },
+            make_highlight("undated", "x"),
+            Highlight {
+                date_created: Some("2024-07-01T09:00:00".into()),
+                ..make_highlight("new", "x")
+            },
+        ];
+        let opts = RenderOptions {
+            group_by: GroupBy::Date,
+            newest_first: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &[], &highlights, &opts);
+        let new = md.find("## 2024-07-01").unwrap();
+        let old = md.find("## 2024-06-01").unwrap();
+        let undated = md.find("## Undated").unwrap();
+        assert!(new < old && old < undated);
+    }
+
+    // --- stats ---
+
+    #[test]
+    fn count_words_splits_on_whitespace() {
+        assert_eq!(count_words("one two  three\nfour\tfive"), 5);
+        assert_eq!(count_words("   "), 0);
+        assert_eq!(count_words(""), 0);
+    }
+
+    #[test]
+    fn highlight_stats_counts_and_aggregates() {
+        let highlights = vec![
+            Highlight {
+                annotation: Some("note".into()),
+                ..make_highlight("three little words", "id")
+            },
+            make_highlight("two words", "id"),
+        ];
+        let stats = HighlightStats::from_highlights(&highlights);
+        assert_eq!(
+            stats,
+            HighlightStats {
+                highlights: 2,
+                words: 5,
+                annotated: 1
+            }
+        );
+
+        let mut total = HighlightStats::default();
+        total.add(stats);
+        total.add(stats);
+        assert_eq!(total.words, 10);
+    }
+
+    #[test]
+    fn generate_markdown_stats_footer() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_highlight("a b c", "id")];
+        let opts = RenderOptions {
+            stats: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.ends_with("---\n\n**Highlights:** 1 · **Words:** 3 · **Annotated:** 0\n"));
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(!md.contains("**Words:**"));
+    }
+
+    // --- heading_offset ---
+
+    #[test]
+    fn generate_markdown_heading_offset_shifts_all_headings() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1), ("Sec", "id_1", 2)]);
+        let highlights = vec![
+            make_highlight("text", "id_1"),
+            make_highlight("orphan", "unknown"),
+        ];
+        let opts = RenderOptions {
+            heading_offset: 1,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.starts_with("## T\n"));
+        assert!(md.contains("\n### Ch\n"));
+        assert!(md.contains("\n#### Sec\n"));
+        assert!(md.contains("\n### Uncategorized\n"));
+    }
+
+    #[test]
+    fn validate_chapter_template_rejects_unknown_placeholders() {
+        assert!(validate_chapter_template("{depth_marker} {title} ({count}, {depth})").is_ok());
+        assert!(validate_chapter_template("## Chapter").is_ok());
+        assert_eq!(
+            validate_chapter_template("{depth_marker} {name}").unwrap_err(),
+            "unknown placeholder `{name}`, expected one of {depth_marker}, {depth}, {title}, {count}"
+        );
+        assert!(validate_chapter_template("{title").is_err());
+    }
+
+    #[test]
+    fn generate_markdown_chapter_template() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Part One", "book!part1.xhtml", 1),
+            ("Chapter 1", "book!ch01.xhtml", 2),
+        ]);
+        let highlights = vec![
+            make_highlight("a", "book!ch01.xhtml"),
+            make_highlight("b", "book!ch01.xhtml"),
+            make_highlight("orphan", "book!unknown.xhtml"),
+        ];
+        let opts = RenderOptions {
+            chapter_template: Some("{depth_marker} {title} ({count}, depth {depth})".into()),
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains("## Part One (0, depth 1)\n\n### Chapter 1 (2, depth 2)\n\n> a\n"));
+        assert!(md.contains("## Uncategorized (1, depth 1)\n\n> orphan\n"));
+    }
+
+    #[test]
+    fn chapter_template_too_deep_is_bold() {
+        let opts = RenderOptions {
+            chapter_template: Some("{depth_marker} {title}".into()),
+            heading_offset: 5,
+            ..Default::default()
+        };
+        assert_eq!(chapter_heading("Deep", 3, 2, None, &opts), "**Deep**\n\n");
+    }
+
+    #[test]
+    fn heading_hashes_capped_at_six() {
+        let opts = RenderOptions {
+            heading_offset: 3,
+            ..Default::default()
+        };
+        assert_eq!(heading_hashes(2, &opts), "#####");
+        assert_eq!(heading_hashes(5, &opts), "######");
+        assert_eq!(heading_hashes(1, &RenderOptions::default()), "#");
+    }
+
+    #[test]
+    fn generate_markdown_entries_deeper_than_six_levels_are_bold() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Level 5", "d5", 5),
+            ("Level 6", "d6", 6),
+            ("Level 7", "d7", 7),
+        ]);
+        let highlights = vec![
+            make_highlight("five", "d5"),
+            make_highlight("six", "d6"),
+            make_highlight("seven", "d7"),
+        ];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.contains("\n###### Level 5\n\n> five\n"));
+        assert!(md.contains("\n**Level 6**\n\n> six\n"));
+        assert!(md.contains("\n**Level 7**\n\n> seven\n"));
+        assert!(!md.contains("#######"));
+    }
+
+    // --- newest_first ---
+
+    #[test]
+    fn toc_order_reverses_siblings_below_their_parents() {
+        let toc = make_toc(&[
+            ("Part 1", "p1", 1),
+            ("Ch 1", "c1", 2),
+            ("Sec 1.1", "s11", 3),
+            ("Ch 2", "c2", 2),
+            ("Part 2", "p2", 1),
+            ("Ch 3", "c3", 2),
+        ]);
+        assert_eq!(toc_order(&toc, false), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(toc_order(&toc, true), [4, 5, 0, 3, 1, 2]);
+    }
+
+    #[test]
+    fn generate_markdown_newest_first_reverses_chapters() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Part 1", "p1", 1),
+            ("Ch 1", "c1", 2),
+            ("Ch 2", "c2", 2),
+            ("Part 2", "p2", 1),
+            ("Ch 3", "c3", 2),
+        ]);
+        // As queried with --newest-first: newest first
+        let highlights = vec![
+            make_highlight("newest", "c3"),
+            make_highlight("middle", "c1"),
+            make_highlight("c1 older", "c1"),
+            make_highlight("oldest", "c2"),
+        ];
+        let opts = RenderOptions {
+            newest_first: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        let body = &md[md.find("## Part 2").unwrap()..];
+        assert_eq!(
+            body,
+            "## Part 2\n\n### Ch 3\n\n> newest\n\n\
+             ## Part 1\n\n### Ch 2\n\n> oldest\n\n\
+             ### Ch 1\n\n> middle\n\n> c1 older\n\n"
+        );
+    }
+
+    // --- chapter_counts ---
+
+    #[test]
+    fn generate_markdown_chapter_counts() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Part One", "book!part1.xhtml", 1),
+            ("Chapter 1", "book!ch01.xhtml", 2),
+        ]);
+        let highlights = vec![
+            make_highlight("one", "book!ch01.xhtml"),
+            make_highlight("two", "book!ch01.xhtml"),
+            make_highlight("lost", "book!ch99.xhtml"),
+        ];
+        let opts = RenderOptions {
+            chapter_counts: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains("## Part One\n\n### Chapter 1 (2)\n"));
+        assert!(md.contains("## Uncategorized (1)\n"));
+    }
+
+    // --- flatten ---
+
+    #[test]
+    fn generate_markdown_flatten_puts_chapters_on_one_level() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Part", "p", 1), ("Ch", "id", 2), ("Sec", "id_1", 3)]);
+        let highlights = vec![make_highlight("one", "id"), make_highlight("two", "id_1")];
+        let opts = RenderOptions {
+            flatten: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(!md.contains("Part"));
+        assert!(md.contains("\n## Ch\n\n> one\n"));
+        assert!(md.contains("\n## Sec\n\n> two\n"));
+        assert!(!md.contains("###"));
+    }
+
+    #[test]
+    fn generate_markdown_flatten_respects_heading_offset() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1), ("Sec", "id_1", 2)]);
+        let highlights = vec![make_highlight("text", "id_1")];
+        let opts = RenderOptions {
+            flatten: true,
+            heading_offset: 1,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains("\n### Sec\n"));
+        assert!(!md.contains("Ch\n"));
+    }
+
+    // --- group_by_color ---
+
+    fn make_colored_highlight(text: &str, content_id: &str, color: Option<i64>) -> Highlight {
+        Highlight {
+            color,
+            ..make_highlight(text, content_id)
+        }
+    }
+
+    #[test]
+    fn generate_markdown_group_by_color_nests_under_chapter() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![
+            make_colored_highlight("a blue one", "id", Some(2)),
+            make_colored_highlight("no color", "id", None),
+            make_colored_highlight("a yellow one", "id", Some(0)),
+            make_colored_highlight("another blue", "id", Some(2)),
+        ];
+        let opts = RenderOptions {
+            group_by_color: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains(
+            "## Ch\n\n\
+             ### Yellow\n\n> a yellow one\n\n\
+             ### Blue\n\n> a blue one\n\n> another blue\n\n\
+             ### No color\n\n> no color\n\n"
+        ));
+        // The group heading replaces the per-highlight color tag
+        assert!(!md.contains("[color:"));
+    }
+
+    #[test]
+    fn generate_markdown_without_group_by_color_is_flat() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_colored_highlight("text", "id", Some(2))];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(!md.contains("### Blue"));
+        assert!(md.contains("[color: blue]"));
+    }
+
+    #[test]
+    fn color_group_label_capitalizes_name() {
+        assert_eq!(color_group_label(Some(1)), "Red");
+        assert_eq!(color_group_label(Some(99)), "Other");
+        assert_eq!(color_group_label(None), "No color");
+    }
+
+    #[test]
+    fn format_highlight_note_only_has_no_blockquote() {
+        let h = Highlight {
+            annotation: Some("Just a thought".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_highlight(&h, &RenderOptions::default()),
+            "**Note:** Just a thought\n"
+        );
+    }
+
+    #[test]
+    fn dedup_highlights_keeps_standalone_notes() {
+        let first = Highlight {
+            annotation: Some("one".into()),
+            ..make_highlight("", "book!ch01.xhtml")
+        };
+        let second = Highlight {
+            annotation: Some("two".into()),
+            ..make_highlight("", "book!ch01.xhtml")
+        };
+        assert_eq!(dedup_highlights(vec![first, second]).len(), 2);
+    }
+
+    // --- date formatting ---
+
+    #[test]
+    fn parse_kobo_date_known_formats() {
+        let expected = chrono::NaiveDate::from_ymd_opt(2024, 1, 15)
+            .unwrap()
+            .and_hms_opt(10, 30, 0)
+            .unwrap();
+        assert_eq!(parse_kobo_date("2024-01-15T10:30:00"), Some(expected));
+        assert_eq!(parse_kobo_date("2024-01-15T10:30:00.000"), Some(expected));
+        assert_eq!(parse_kobo_date("2024-01-15T10:30:00Z"), Some(expected));
+        assert_eq!(parse_kobo_date("2024-01-15 10:30:00"), Some(expected));
+        assert!(parse_kobo_date("2024-01-15").is_some());
+        assert_eq!(parse_kobo_date("yesterday"), None);
+    }
+
+    #[test]
+    fn display_date_applies_format() {
+        let opts = RenderOptions {
+            date_format: Some("%B %d, %Y".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            display_date("2024-01-15T10:30:00.000", &opts),
+            "January 15, 2024"
+        );
+
+        let opts = RenderOptions {
+            date_format: Some("%Y-%m-%d".into()),
+            ..Default::default()
+        };
+        assert_eq!(display_date("2024-01-15T10:30:00", &opts), "2024-01-15");
+    }
+
+    #[test]
+    fn display_date_falls_back_to_raw() {
+        let opts = RenderOptions {
+            date_format: Some("%Y-%m-%d".into()),
+            ..Default::default()
+        };
+        assert_eq!(display_date("not a date", &opts), "not a date");
+        assert_eq!(
+            display_date("2024-01-15T10:30:00", &RenderOptions::default()),
+            "2024-01-15T10:30:00"
+        );
+    }
+
+    #[test]
+    fn display_date_with_time_zone_specifier_falls_back_to_raw() {
+        let opts = RenderOptions {
+            date_format: Some("%Y-%m-%d %z".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            display_date("2024-01-15T10:30:00", &opts),
+            "2024-01-15T10:30:00"
+        );
+    }
+
+    #[test]
+    fn relative_date_picks_largest_unit() {
+        let now = parse_kobo_date("2024-06-15T12:00:00").unwrap();
+        let ago = |date: &str| relative_date(parse_kobo_date(date).unwrap(), now);
+        assert_eq!(ago("2024-06-15T11:59:30").as_deref(), Some("just now"));
+        assert_eq!(
+            ago("2024-06-15T11:15:00").as_deref(),
+            Some("45 minutes ago")
+        );
+        assert_eq!(ago("2024-06-15T01:00:00").as_deref(), Some("11 hours ago"));
+        assert_eq!(ago("2024-06-14T08:00:00").as_deref(), Some("yesterday"));
+        assert_eq!(ago("2024-06-13T12:00:00").as_deref(), Some("2 days ago"));
+        assert_eq!(ago("2024-05-10T12:00:00").as_deref(), Some("1 month ago"));
+        assert_eq!(ago("2024-03-01T12:00:00").as_deref(), Some("3 months ago"));
+        assert_eq!(ago("2021-01-01T00:00:00").as_deref(), Some("3 years ago"));
+        assert_eq!(ago("2024-06-16T00:00:00"), None);
+    }
+
+    #[test]
+    fn display_date_relative_falls_back_to_absolute() {
+        let opts = RenderOptions {
+            relative_to: parse_kobo_date("2024-06-15T12:00:00"),
+            ..Default::default()
+        };
+        assert_eq!(display_date("2024-06-12T09:00:00Z", &opts), "3 days ago");
+        assert_eq!(display_date("not a date", &opts), "not a date");
+        // Dates after the export time are shown as stored
+        assert_eq!(
+            display_date("2025-01-01T00:00:00", &opts),
+            "2025-01-01T00:00:00"
+        );
+
+        let h = Highlight {
+            date_created: Some("2024-03-01T10:00:00.000".into()),
+            ..make_highlight("Passage", "id")
+        };
+        assert_eq!(format_highlight(&h, &opts), "> Passage\n\n*3 months ago*\n");
+    }
+
+    #[test]
+    fn rfc3339_date_normalizes_kobo_formats() {
+        assert_eq!(rfc3339_date("2024-01-15T10:30:00"), "2024-01-15T10:30:00Z");
+        assert_eq!(rfc3339_date("2024-01-15 10:30:00"), "2024-01-15T10:30:00Z");
+        assert_eq!(
+            rfc3339_date("2024-01-15T10:30:00.123"),
+            "2024-01-15T10:30:00.123Z"
+        );
+        assert_eq!(rfc3339_date("2024-01-15T10:30:00Z"), "2024-01-15T10:30:00Z");
+        assert_eq!(
+            rfc3339_date("2024-01-15T12:30:00+02:00"),
+            "2024-01-15T10:30:00Z"
+        );
+        assert_eq!(rfc3339_date("2024-01-15"), "2024-01-15T00:00:00Z");
+    }
+
+    #[test]
+    fn rfc3339_date_passes_unparseable_through() {
+        assert_eq!(rfc3339_date("last Tuesday"), "last Tuesday");
+    }
+
+    // --- color_name ---
+
+    #[test]
+    fn color_name_known_codes() {
+        assert_eq!(color_name(0), "yellow");
+        assert_eq!(color_name(1), "red");
+        assert_eq!(color_name(2), "blue");
+        assert_eq!(color_name(3), "green");
+    }
+
+    #[test]
+    fn color_name_unknown_code_is_neutral() {
+        assert_eq!(color_name(42), "other");
+        assert_eq!(color_name(-1), "other");
+    }
+
+    #[test]
+    fn color_code_accepts_names_and_codes() {
+        assert_eq!(color_code("yellow"), Some(0));
+        assert_eq!(color_code("Green"), Some(3));
+        assert_eq!(color_code("2"), Some(2));
+        assert_eq!(color_code("purple"), None);
+        assert_eq!(color_code("other"), None);
+    }
+
+    // --- assign_highlights ---
+
+    fn make_toc(entries: &[(&str, &str, u32)]) -> Vec<TocEntry> {
+        entries
+            .iter()
+            .map(|(title, match_id, depth)| TocEntry {
+                title: title.to_string(),
+                match_id: match_id.to_string(),
+                depth: *depth,
+            })
+            .collect()
+    }
+
+    fn make_highlight(text: &str, content_id: &str) -> Highlight {
+        Highlight {
+            text: text.into(),
+            annotation: None,
+            chapter_content_id: content_id.into(),
+            date_created: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn assign_highlights_exact_match() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+        ]);
+        let highlights = vec![make_highlight("hello", "book!ch01.xhtml#ch01_1")];
+
+        let (assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert_eq!(assigned.get(&1).unwrap().len(), 1);
+        assert!(uncategorized.is_empty());
+    }
+
+    #[test]
+    fn assign_highlights_unmatched_goes_to_uncategorized() {
+        let toc = make_toc(&[("Chapter I", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![make_highlight("hello", "book!ch99.xhtml#unknown")];
+
+        let (assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert!(assigned.is_empty());
+        assert_eq!(uncategorized.len(), 1);
+    }
+
+    #[test]
+    fn assign_highlights_falls_back_to_file_path() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+            ("Chapter II", "book!ch02.xhtml#ch02", 1),
+        ]);
+        let highlights = vec![make_highlight("hello", "book!ch02.xhtml#unlisted_anchor")];
+
+        let (assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert_eq!(assigned.get(&2).unwrap().len(), 1);
+        assert!(uncategorized.is_empty());
+    }
+
+    #[test]
+    fn assign_highlights_fallback_uses_first_entry_in_file() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+        ]);
+        let highlights = vec![
+            make_highlight("exact", "book!ch01.xhtml#ch01_1"),
+            make_highlight("fuzzy", "book!ch01.xhtml"),
+        ];
+
+        let (assigned, _) = assign_highlights(&toc, &highlights, false);
+        // Exact match is still preferred over the file-path fallback
+        assert_eq!(assigned.get(&1).unwrap()[0].text, "exact");
+        assert_eq!(assigned.get(&0).unwrap()[0].text, "fuzzy");
+    }
+
+    #[test]
+    fn assign_highlights_normalizes_backslashes() {
+        let toc = make_toc(&[
+            (
+                "Cover",
+                r"file:///mnt/onboard/Side\book.epub!OEBPS\cover.xhtml",
+                1,
+            ),
+            (
+                "Ch 1",
+                r"file:///mnt/onboard/Side\book.epub!OEBPS\ch01.xhtml#s1",
+                1,
+            ),
+        ]);
+        let highlights = vec![
+            make_highlight(
+                "exact",
+                "file:///mnt/onboard/Side/book.epub!OEBPS/ch01.xhtml#s1",
+            ),
+            make_highlight(
+                "same file",
+                r"file:///mnt/onboard/Side\book.epub!OEBPS\ch01.xhtml",
+            ),
+        ];
+
+        let (assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert!(uncategorized.is_empty());
+        assert_eq!(assigned[&1].len(), 2);
+    }
+
+    // --- resolve_chapter_title ---
+
+    #[test]
+    fn resolve_chapter_title_exact_match() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+        ]);
+        assert_eq!(
+            resolve_chapter_title(&toc, "book!ch01.xhtml#ch01_1"),
+            Some("Section 1")
+        );
+    }
+
+    #[test]
+    fn resolve_chapter_title_falls_back_to_first_entry_in_file() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+        ]);
+        assert_eq!(
+            resolve_chapter_title(&toc, "book!ch01.xhtml#unlisted"),
+            Some("Chapter I")
+        );
+    }
+
+    #[test]
+    fn resolve_chapter_title_no_match() {
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("", "book!ch02.xhtml", 1),
+        ]);
+        assert_eq!(resolve_chapter_title(&toc, "book!ch99.xhtml#x"), None);
+        // An untitled entry isn't a title to show
+        assert_eq!(resolve_chapter_title(&toc, "book!ch02.xhtml"), None);
+        assert_eq!(resolve_chapter_title(&[], "book!ch01.xhtml"), None);
+    }
+
+    #[test]
+    fn assign_highlights_decodes_percent_escapes() {
+        let toc = make_toc(&[
+            ("Café", "book!OEBPS/Caf%C3%A9 Noir.xhtml#top", 1),
+            ("Plain", "book!OEBPS/Chapter Two.xhtml#s1", 1),
+        ]);
+        let highlights = vec![
+            make_highlight("decoded toc", "book!OEBPS/Café%20Noir.xhtml#top"),
+            make_highlight("encoded bookmark", "book!OEBPS/Chapter%20Two.xhtml#s1"),
+        ];
+
+        let (assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert!(uncategorized.is_empty());
+        assert_eq!(assigned[&0][0].text, "decoded toc");
+        assert_eq!(assigned[&1][0].text, "encoded bookmark");
+        // The raw ContentID is kept for display
+        assert_eq!(
+            assigned[&1][0].chapter_content_id,
+            "book!OEBPS/Chapter%20Two.xhtml#s1"
+        );
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("a%20b%C3%A9"), "a bé");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        // A lone continuation byte isn't UTF-8
+        assert_eq!(percent_decode("x%A9"), "x%A9");
+    }
+
+    #[test]
+    fn assign_highlights_prefer_deepest_breaks_shared_match_id_ties() {
+        let toc = make_toc(&[
+            ("Part One", "book!part1.xhtml", 1),
+            ("Chapter 1", "book!part1.xhtml", 2),
+            ("Opening", "book!part1.xhtml", 2),
+        ]);
+        let highlights = vec![make_highlight("hello", "book!part1.xhtml")];
+
+        let (assigned, _) = assign_highlights(&toc, &highlights, false);
+        assert_eq!(assigned.keys().collect::<Vec<_>>(), [&0]);
+        // The deepest entry wins; the first of equally deep ones
+        let (assigned, _) = assign_highlights(&toc, &highlights, true);
+        assert_eq!(assigned.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[test]
+    fn natural_cmp_compares_numbers_by_value() {
+        assert_eq!(natural_cmp("ch9.xhtml", "ch10.xhtml"), Ordering::Less);
+        assert_eq!(natural_cmp("ch010.xhtml", "ch9.xhtml"), Ordering::Greater);
+        assert_eq!(natural_cmp("a.xhtml", "b.xhtml"), Ordering::Less);
+        assert_eq!(natural_cmp("ch1", "ch1.xhtml"), Ordering::Less);
+        assert_eq!(natural_cmp("ch2.xhtml", "ch2.xhtml"), Ordering::Equal);
+    }
+
+    #[test]
+    fn assign_to_preceding_uses_last_entry_of_nearest_earlier_file() {
+        let toc = make_toc(&[
+            ("Chapter 1", "book!ch01.xhtml#ch01", 1),
+            ("Chapter 2", "book!ch02.xhtml#ch02", 1),
+            ("Section 2.1", "book!ch02.xhtml#s1", 2),
+            ("Chapter 10", "book!ch10.xhtml#ch10", 1),
+        ]);
+        let highlights = vec![
+            make_highlight("after two", "book!ch03.xhtml#x"),
+            make_highlight("after ten", "book!ch11.xhtml"),
+            make_highlight("before all", "book!00_cover.xhtml"),
+        ];
+
+        let (mut assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        assert_eq!(uncategorized.len(), 3);
+        let remaining = assign_to_preceding(&toc, &mut assigned, uncategorized);
+        assert_eq!(assigned[&2].len(), 1);
+        assert_eq!(assigned[&2][0].text, "after two");
+        assert_eq!(assigned[&3].len(), 1);
+        assert_eq!(assigned[&3][0].text, "after ten");
+        // "00_cover" sorts before every chapter file, so nothing precedes it
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].text, "before all");
+    }
+
+    #[test]
+    fn assign_to_preceding_skips_untitled_entries() {
+        let toc = make_toc(&[
+            ("Chapter 1", "book!ch01.xhtml#ch01", 1),
+            ("", "book!ch02.xhtml", 1),
+        ]);
+        let highlights = vec![make_highlight("orphan", "book!ch03.xhtml")];
+
+        let (mut assigned, uncategorized) = assign_highlights(&toc, &highlights, false);
+        let remaining = assign_to_preceding(&toc, &mut assigned, uncategorized);
+        assert!(remaining.is_empty());
+        assert_eq!(assigned[&0].len(), 1);
+        assert_eq!(assigned[&0][0].text, "orphan");
+    }
+
+    #[test]
+    fn nearest_match_id_picks_longest_common_prefix() {
+        let toc = make_toc(&[
+            ("Cover", "book!OPS!cover.xhtml", 1),
+            ("Ch 1", "book!OPS!text/ch01.xhtml#s1", 1),
+            ("Ch 2", "book!OPS!text/ch02.xhtml#s1", 1),
+        ]);
+        assert_eq!(
+            nearest_match_id(&toc, "book!OPS!text/ch02.html#s1"),
+            Some("book!OPS!text/ch02.xhtml#s1")
+        );
+        assert_eq!(nearest_match_id(&[], "anything"), None);
+    }
+
+    // --- file_path ---
+
+    #[test]
+    fn file_path_strips_fragment() {
+        assert_eq!(file_path("book!ch01.xhtml#ch01_4"), "book!ch01.xhtml");
+        assert_eq!(file_path("book!ch01.xhtml"), "book!ch01.xhtml");
+    }
+
+    #[test]
+    fn assign_highlights_multiple_to_same_section() {
+        let toc = make_toc(&[("Section", "book!ch01.xhtml#sec1", 3)]);
+        let highlights = vec![
+            make_highlight("first", "book!ch01.xhtml#sec1"),
+            make_highlight("second", "book!ch01.xhtml#sec1"),
+        ];
+
+        let (assigned, _) = assign_highlights(&toc, &highlights, false);
+        assert_eq!(assigned.get(&0).unwrap().len(), 2);
+    }
+
+    // --- merge_adjacent_highlights ---
+
+    fn make_positioned_highlight(text: &str, progress: f64, note: Option<&str>) -> Highlight {
+        Highlight {
+            chapter_progress: Some(progress),
+            annotation: note.map(Into::into),
+            ..make_highlight(text, "book!ch01.xhtml")
+        }
+    }
+
+    #[test]
+    fn merge_adjacent_highlights_joins_near_positions() {
+        let merged = merge_adjacent_highlights(vec![
+            make_positioned_highlight("First half of the", 0.400, Some("one")),
+            make_positioned_highlight("sentence.", 0.405, Some("two")),
+            make_positioned_highlight("Far away.", 0.800, None),
+        ]);
+        assert_eq!(texts(&merged), ["First half of the sentence.", "Far away."]);
+        assert_eq!(merged[0].annotation.as_deref(), Some("one\ntwo"));
+    }
+
+    #[test]
+    fn merge_adjacent_highlights_keeps_other_chapters_and_unknown_positions_apart() {
+        let other_chapter = Highlight {
+            chapter_content_id: "book!ch02.xhtml".into(),
+            ..make_positioned_highlight("b", 0.5, None)
+        };
+        let unknown = Highlight {
+            chapter_progress: None,
+            ..make_positioned_highlight("c", 0.5, None)
+        };
+        let merged = merge_adjacent_highlights(vec![
+            make_positioned_highlight("a", 0.5, None),
+            other_chapter,
+            unknown,
+        ]);
+        assert_eq!(texts(&merged), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn merge_adjacent_highlights_takes_second_annotation_when_first_has_none() {
+        let merged = merge_adjacent_highlights(vec![
+            make_positioned_highlight("a", 0.5, None),
+            make_positioned_highlight("b", 0.5, Some("note")),
+        ]);
+        assert_eq!(merged[0].annotation.as_deref(), Some("note"));
+    }
+
+    // --- split_annotations ---
+
+    #[test]
+    fn split_annotations_separates_quotes_and_notes() {
+        let highlights = vec![
+            make_highlight("plain", "id"),
+            Highlight {
+                annotation: Some("mine".into()),
+                ..make_highlight("noted", "id")
+            },
+            Highlight {
+                annotation: Some("standalone".into()),
+                ..make_highlight("", "id")
+            },
+        ];
+        let (quotes, annotated) = split_annotations(&highlights);
+        assert_eq!(texts(&quotes), ["plain", "noted"]);
+        assert!(quotes.iter().all(|h| h.annotation.is_none()));
+        assert_eq!(texts(&annotated), ["noted", ""]);
+        assert_eq!(annotated[0].annotation.as_deref(), Some("mine"));
+    }
+
+    // --- without_dates ---
+
+    #[test]
+    fn without_dates_leaves_no_date_in_any_format() {
+        let book = Book {
+            title: "Book".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = without_dates(vec![Highlight {
+            annotation: Some("note".into()),
+            date_created: Some("2024-06-01T10:30:00".into()),
+            ..make_highlight("Passage", "id")
+        }]);
+        let opts = RenderOptions {
+            date_format: Some("%B %d, %Y".into()),
+            relative_to: parse_kobo_date("2024-07-01T00:00:00"),
+            ..Default::default()
+        };
+
+        let outputs = [
+            generate_markdown(&book, &toc, &highlights, &opts),
+            generate_org(&book, &toc, &highlights, &opts),
+            generate_html(&book, &toc, &highlights, &opts),
+            generate_json(&book, &toc, &highlights, false).unwrap(),
+            generate_jsonl(&book, &toc, &highlights, false).unwrap(),
+            generate_csv(&book, &toc, &highlights, true, false).unwrap(),
+            generate_anki(&book, &toc, &highlights, false),
+        ];
+        for output in outputs {
+            assert!(output.contains("Passage"), "{output}");
+            for date in ["2024", "June", "ago"] {
+                assert!(!output.contains(date), "{output}");
+            }
+        }
+    }
+
+    #[test]
+    fn without_dates_leaves_no_date_in_sqlite_export() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_sqlite_export(&conn).unwrap();
+        let book = Book {
+            content_id: "book1".into(),
+            title: "Book".into(),
+            last_read: Some("2024-06-02T08:00:00".into()),
+            date_added: Some("2024-05-01T08:00:00".into()),
+            ..Default::default()
+        };
+        let highlights = without_dates(vec![Highlight {
+            date_created: Some("2024-06-01T10:30:00".into()),
+            ..make_highlight("Passage", "id")
+        }]);
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        insert_sqlite_book(&conn, &book_without_dates(&book), &toc, &highlights, false).unwrap();
+
+        let dates: [Option<String>; 3] = conn
+            .query_row(
+                "SELECT last_read, date_added, date_created FROM books
+                 JOIN highlights ON highlights.book_id = books.id",
+                [],
+                |row| Ok([row.get(0)?, row.get(1)?, row.get(2)?]),
+            )
+            .unwrap();
+        assert_eq!(dates, [None, None, None]);
+    }
+
+    // --- without_annotations ---
+
+    #[test]
+    fn without_annotations_leaves_no_note_in_any_format() {
+        let book = Book {
+            title: "Book".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = without_annotations(vec![
+            Highlight {
+                annotation: Some("private thought".into()),
+                date_created: Some("2024-06-01T10:30:00".into()),
+                ..make_highlight("Passage", "id")
+            },
+            Highlight {
+                annotation: Some("standalone remark".into()),
+                ..make_highlight("", "id")
+            },
+        ]);
+        assert_eq!(highlights.len(), 1);
+
+        let outputs = [
+            generate_markdown(&book, &toc, &highlights, &RenderOptions::default()),
+            generate_org(&book, &toc, &highlights, &RenderOptions::default()),
+            generate_html(&book, &toc, &highlights, &RenderOptions::default()),
+            generate_json(&book, &toc, &highlights, false).unwrap(),
+            generate_jsonl(&book, &toc, &highlights, false).unwrap(),
+            generate_csv(&book, &toc, &highlights, true, false).unwrap(),
+            generate_anki(&book, &toc, &highlights, false),
+        ];
+        for output in outputs {
+            assert!(output.contains("Passage"), "{output}");
+            assert!(output.contains("Ch"), "{output}");
+            for note in ["private", "standalone", "Note"] {
+                assert!(!output.contains(note), "{output}");
+            }
+        }
+    }
+
+    // --- dedup_highlights ---
+
+    #[test]
+    fn dedup_highlights_collapses_identical() {
+        let highlights = vec![
+            make_highlight("same", "book!ch01.xhtml"),
+            make_highlight("same", "book!ch01.xhtml"),
+        ];
+        assert_eq!(dedup_highlights(highlights).len(), 1);
+    }
+
+    #[test]
+    fn dedup_highlights_prefers_annotated_duplicate() {
+        let mut annotated = make_highlight("same", "book!ch01.xhtml");
+        annotated.annotation = Some("my note".into());
+        let highlights = vec![
+            make_highlight("first", "book!ch01.xhtml"),
+            make_highlight("same", "book!ch01.xhtml"),
+            annotated,
+        ];
+
+        let unique = dedup_highlights(highlights);
+        assert_eq!(texts(&unique), vec!["first", "same"]);
+        assert_eq!(unique[1].annotation.as_deref(), Some("my note"));
+    }
+
+    #[test]
+    fn dedup_highlights_keeps_same_text_in_other_chapter() {
+        let highlights = vec![
+            make_highlight("same", "book!ch01.xhtml"),
+            make_highlight("same", "book!ch02.xhtml"),
+        ];
+        assert_eq!(dedup_highlights(highlights).len(), 2);
+    }
+
+    // --- toc ---
+
+    #[test]
+    fn generate_markdown_toc_links_match_heading_anchors() {
+        let book = Book {
+            title: "Intro".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Part One: Roots", "p1", 1),
+            ("Intro", "c1", 2),
+            ("Part Two", "p2", 1),
+            ("Intro", "c2", 2),
+        ]);
+        let highlights = vec![make_highlight("a", "c1"), make_highlight("b", "c2")];
+        let opts = RenderOptions {
+            toc: true,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains(
+            "- [Part One: Roots](#part-one-roots)\n  \
+             - [Intro](#intro-1)\n\
+             - [Part Two](#part-two)\n  \
+             - [Intro](#intro-2)\n\n---\n\n"
+        ));
+
+        // Every link points at a heading of the document
+        let mut used: HashMap<String, usize> = HashMap::new();
+        let mut anchors = Vec::new();
+        for text in md.lines().filter_map(heading_text) {
+            let base = heading_anchor(text);
+            let seen = used.entry(base.clone()).or_insert(0);
+            anchors.push(match *seen {
+                0 => base,
+                n => format!("{base}-{n}"),
+            });
+            *seen += 1;
+        }
+        for link in md.lines().filter_map(|l| l.split("](#").nth(1)) {
+            assert!(anchors.contains(&link.trim_end_matches(')').to_string()));
+        }
+    }
+
+    #[test]
+    fn generate_markdown_toc_lists_only_emitted_chapters() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("One", "c1", 1), ("Two", "c2", 1)]);
+        let opts = RenderOptions {
+            toc: true,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &toc, &[make_highlight("a", "c2")], &opts);
+        assert!(md.contains("# T\n\n- [Two](#two)\n\n---\n\n## Two\n"));
+        assert!(!md.contains("One"));
+
+        let md = generate_markdown(&book, &toc, &[], &opts);
+        assert!(md.starts_with("# T\n\n---\n\n"));
+    }
+
+    #[test]
+    fn heading_anchor_follows_github() {
+        assert_eq!(heading_anchor("Part One: Roots (3)"), "part-one-roots-3");
+        assert_eq!(heading_anchor("Über_alles -- Ende"), "über_alles----ende");
+    }
+
+    // --- generate_markdown ---
+
+    #[test]
+    fn generate_markdown_is_deterministic() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let entries: Vec<(String, String, u32)> = (1..=40)
+            .map(|n| {
+                (
+                    format!("Chapter {n}"),
+                    format!("book!ch{n:02}.xhtml"),
+                    1 + n % 3,
+                )
+            })
+            .collect();
+        let entries: Vec<(&str, &str, u32)> = entries
+            .iter()
+            .map(|(title, id, depth)| (title.as_str(), id.as_str(), *depth))
+            .collect();
+        let toc = make_toc(&entries);
+        let mut highlights: Vec<Highlight> = (1..=40)
+            .rev()
+            .map(|n| Highlight {
+                color: Some(n % 4),
+                ..make_highlight(&format!("in {n}"), &format!("book!ch{n:02}.xhtml"))
+            })
+            .collect();
+        highlights.extend((1..=5).map(|n| make_highlight(&format!("orphan {n}"), "elsewhere")));
+
+        for opts in [
+            RenderOptions::default(),
+            RenderOptions {
+                group_by_color: true,
+                flatten_uncategorized: true,
+                ..Default::default()
+            },
+            RenderOptions {
+                flatten: true,
+                newest_first: true,
+                ..Default::default()
+            },
+        ] {
+            let first = generate_markdown(&book, &toc, &highlights, &opts);
+            for _ in 0..10 {
+                assert_eq!(generate_markdown(&book, &toc, &highlights, &opts), first);
+            }
+        }
+    }
+
+    #[test]
+    fn generate_markdown_basic_structure() {
+        let book = Book {
+            content_id: "book1".into(),
+            title: "Test Book".into(),
+            author: Some("Author Name".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#sec1", 2),
+        ]);
+        let highlights = vec![make_highlight("Important text", "book!ch01.xhtml#sec1")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.starts_with("# Test Book\n"));
+        assert!(md.contains("**Author:** Author Name"));
+        assert!(md.contains("## Chapter I\n"));
+        assert!(md.contains("### Section 1\n"));
+        assert!(md.contains("> Important text\n"));
+    }
+
+    #[test]
+    fn generate_markdown_parent_chapter_emitted_for_subsection_highlights() {
+        let toc = make_toc(&[
+            ("KAPITEL I", "book!ch01.xhtml#ch01", 1),
+            ("1. Abschnitt", "book!ch01.xhtml#ch01_1", 2),
+            ("KAPITEL II", "book!ch02.xhtml#ch02", 1),
+            ("1. Abschnitt", "book!ch02.xhtml#ch02_1", 2),
+        ]);
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        // Only highlight in chapter I sub-section
+        let highlights = vec![make_highlight("text", "book!ch01.xhtml#ch01_1")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        // Chapter I heading must appear even though only sub-section has highlights
+        assert!(md.contains("## KAPITEL I\n"));
+        assert!(md.contains("### 1. Abschnitt\n"));
+        // Chapter II should NOT appear (no highlights)
+        assert!(!md.contains("## KAPITEL II"));
+    }
+
+    #[test]
+    fn generate_markdown_multi_level_hierarchy() {
+        let toc = make_toc(&[
+            ("The Enchanted Forest", "book!forest.html#id_1", 1),
+            ("I. The Crystal Cave", "book!forest.html#id_2", 2),
+            ("1. The Hidden Door", "book!forest.html#id_3", 3),
+            ("a) The Silver Key", "book!forest.html#id_4", 4),
+            ("II. The Mountain Pass", "book!forest.html#id_5", 2),
+        ]);
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let highlights = vec![make_highlight("deep text", "book!forest.html#id_4")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        // All ancestors should be emitted (depth+1 = heading level)
+        assert!(md.contains("## The Enchanted Forest\n"));
+        assert!(md.contains("### I. The Crystal Cave\n"));
+        assert!(md.contains("#### 1. The Hidden Door\n"));
+        assert!(md.contains("##### a) The Silver Key\n"));
+        // Section II should NOT appear (no highlights)
+        assert!(!md.contains("II. The Mountain Pass"));
+    }
+
+    #[test]
+    fn generate_markdown_separate_files_hierarchy() {
+        // Each entry in its own file, depth from suffix
+        let toc = make_toc(&[
+            ("Part One: The Dawn", "book!_1h_1.xhtml", 2),
+            ("1. The Awakening", "book!_1h_2.xhtml", 3),
+            ("2. The First Light", "book!_1h_3.xhtml", 3),
+            ("Part Two: The Dusk", "book!_1h_7.xhtml", 2),
+            ("1. The Fading Star", "book!_1h_8.xhtml", 3),
+        ]);
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let highlights = vec![make_highlight("text", "book!_1h_2.xhtml")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.contains("### Part One: The Dawn\n"));
+        assert!(md.contains("#### 1. The Awakening\n"));
+        // Part Two should NOT appear
+        assert!(!md.contains("Part Two: The Dusk"));
+        // Other sections without highlights should not appear
+        assert!(!md.contains("2. The First Light"));
+    }
+
+    #[test]
+    fn generate_markdown_uncategorized_section() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![make_highlight("orphan", "book!unknown.xhtml#x")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.contains("## Uncategorized\n"));
+        assert!(md.contains("> orphan\n"));
+    }
+
+    #[test]
+    fn generate_markdown_flatten_uncategorized_into_chapters() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![
+            make_highlight("matched", "book!ch01.xhtml#ch01"),
+            make_highlight("orphan", "book!ch01b.xhtml#x"),
+        ];
+        let opts = RenderOptions {
+            flatten_uncategorized: true,
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains(
+            "## Ch
+
+> matched
+
+> orphan
+"
+        ));
+        assert!(!md.contains("Uncategorized"));
+    }
+
+    #[test]
+    fn generate_markdown_custom_uncategorized_label() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![make_highlight("orphan", "book!unknown.xhtml#x")];
+        let opts = RenderOptions {
+            uncategorized_label: "Sonstiges".into(),
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.contains("## Sonstiges\n\n> orphan\n"));
+        assert!(!md.contains("Uncategorized"));
+    }
+
+    #[test]
+    fn generate_markdown_empty_uncategorized_label_omits_heading() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![
+            make_highlight("matched", "book!ch01.xhtml#ch01"),
+            make_highlight("orphan", "book!unknown.xhtml#x"),
+        ];
+        let opts = RenderOptions {
+            uncategorized_label: String::new(),
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.ends_with("## Ch\n\n> matched\n\n> orphan\n\n"));
+    }
+
+    #[test]
+    fn generate_markdown_no_uncategorized_when_all_matched() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![make_highlight("matched", "book!ch01.xhtml#ch01")];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(!md.contains("Uncategorized"));
+    }
+
+    #[test]
+    fn generate_markdown_highlight_with_annotation_and_date() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![Highlight {
+            text: "highlighted".into(),
+            annotation: Some("my note".into()),
+            chapter_content_id: "id".into(),
+            date_created: Some("2024-06-01".into()),
+            ..Default::default()
+        }];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.contains("> highlighted\n"));
+        assert!(md.contains("**Note:** my note"));
+        assert!(md.contains("*2024-06-01*"));
+    }
+
+    // --- frontmatter ---
+
+    #[test]
+    fn yaml_string_escapes_quotes_and_backslashes() {
+        assert_eq!(yaml_string("Plain"), "\"Plain\"");
+        assert_eq!(yaml_string("Dune: Messiah"), "\"Dune: Messiah\"");
+        assert_eq!(
+            yaml_string("The \"Best\" C:\\Path"),
+            "\"The \\\"Best\\\" C:\\\\Path\""
+        );
+        assert_eq!(yaml_string("two\nlines"), "\"two\\nlines\"");
+    }
+
+    #[test]
+    fn generate_markdown_with_frontmatter() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "Dune: Messiah".into(),
+            author: Some("Frank \"Herb\" Herbert".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_highlight("one", "id"), make_highlight("two", "id")];
+        let opts = RenderOptions {
+            frontmatter: Some("2024-06-01".into()),
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &toc, &highlights, &opts);
+        assert!(md.starts_with(
+            "---\n\
+             title: \"Dune: Messiah\"\n\
+             author: \"Frank \\\"Herb\\\" Herbert\"\n\
+             highlight_count: 2\n\
+             exported: 2024-06-01\n\
+             ---\n\
+             # Dune: Messiah\n"
+        ));
+    }
+
+    #[test]
+    fn generate_markdown_frontmatter_lists_tags() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let tags = vec![
+            "kobo".into(),
+            "#to read".into(),
+            "non-fiction/essays!".into(),
+        ];
+        let opts = RenderOptions {
+            frontmatter: Some("2024-06-01".into()),
+            tags: tags.clone(),
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &opts);
+        assert!(
+            md.contains("exported: 2024-06-01\ntags: [kobo, to-read, non-fiction/essays]\n---\n")
+        );
+
+        let opts = RenderOptions {
+            tags,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &opts);
+        assert!(md.starts_with("#kobo #to-read #non-fiction/essays\n\n# T\n"));
+    }
+
+    #[test]
+    fn sanitize_tag_rejects_empty_and_numeric() {
+        assert_eq!(
+            sanitize_tag("Science Fiction").as_deref(),
+            Some("Science-Fiction")
+        );
+        assert_eq!(sanitize_tag("##").as_deref(), None);
+        assert_eq!(sanitize_tag("2024").as_deref(), None);
+        assert_eq!(sanitize_tag("y2024").as_deref(), Some("y2024"));
+    }
+
+    #[test]
+    fn generate_markdown_frontmatter_omits_missing_author() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            frontmatter: Some("2024-06-01".into()),
+            ..Default::default()
+        };
+
+        let md = generate_markdown(&book, &[], &[], &opts);
+        assert!(!md.contains("author:"));
+        assert!(md.contains("highlight_count: 0\n"));
+    }
+
+    #[test]
+    fn generate_markdown_without_frontmatter_starts_with_title() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(md.starts_with("# T\n"));
+    }
+
+    // --- bibtex ---
+
+    #[test]
+    fn generate_bibtex_with_all_fields() {
+        let book = Book {
+            title: "Dune".into(),
+            author: Some("Frank Herbert".into()),
+            publication_date: Some("1965-08-01T00:00:00".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_bibtex(&book),
+            "```bibtex\n\
+             @book{herbert1965,\n  \
+             title = {Dune},\n  \
+             author = {Frank Herbert},\n  \
+             year = {1965}\n\
+             }\n\
+             ```\n\n"
+        );
+    }
+
+    #[test]
+    fn generate_bibtex_omits_missing_fields() {
+        let book = Book {
+            title: "Anonymous Tales".into(),
+            ..Default::default()
+        };
+        let bib = generate_bibtex(&book);
+        assert!(bib.contains("@book{anonymous,\n  title = {Anonymous Tales}\n}"));
+        assert!(!bib.contains("author"));
+        assert!(!bib.contains("year"));
+    }
+
+    #[test]
+    fn cite_key_uses_first_author_surname() {
+        let book = Book {
+            title: "T".into(),
+            author: Some("Le Guin, Ursula K. & Someone Else".into()),
+            ..Default::default()
+        };
+        assert_eq!(cite_key(&book, Some("1969")), "leguin1969");
+        assert_eq!(publication_year("unknown"), None);
+    }
+
+    #[test]
+    fn generate_markdown_bibtex_follows_frontmatter() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            frontmatter: Some("2024-06-01".into()),
+            bibtex: true,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &opts);
+        assert!(md.contains("---\n```bibtex\n@book{t,\n"));
+        assert!(md.contains("```\n\n# T\n"));
+    }
+
+    // --- bookmarks ---
+
+    #[test]
+    fn markdown_bookmarks_lists_chapter_and_progress() {
+        let toc = make_toc(&[("Chapter 1", "book!ch01.xhtml", 1)]);
+        let bookmarks = vec![
+            Highlight {
+                chapter_progress: Some(0.25),
+                ..make_highlight("", "book!ch01.xhtml")
+            },
+            make_highlight("", "book!ch09.xhtml"),
+        ];
+        assert_eq!(
+            markdown_bookmarks(&toc, &bookmarks, &RenderOptions::default()),
+            "## Bookmarks\n\n- Chapter 1 — 25%\n- Uncategorized\n\n"
+        );
+        assert_eq!(markdown_bookmarks(&toc, &[], &RenderOptions::default()), "");
+    }
+
+    // --- render_template ---
+
+    #[test]
+    fn render_template_substitutes_placeholders() {
+        let book = Book {
+            title: "T".into(),
+            author: Some("A; B".into()),
+            percent_read: Some(40),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_highlight("text", "id")];
+        let template = "title: {{title}}\nby {{ author }} ({{progress}}, {{highlight_count}})\n\n{{highlights}}";
+
+        assert_eq!(
+            render_template(
+                template,
+                &book,
+                &toc,
+                &highlights,
+                &RenderOptions::default()
+            ),
+            "title: T\nby A, B (40%, 1)\n\n## Ch\n\n> text\n\n"
+        );
+    }
+
+    #[test]
+    fn render_template_keeps_unknown_and_unclosed_placeholders() {
+        let book = Book {
+            title: "{{author}}".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_template(
+                "{{nope}} {{title}} {{author}}|{{progress}}| {{title",
+                &book,
+                &[],
+                &[],
+                &RenderOptions::default()
+            ),
+            "{{nope}} {{author}} || {{title"
+        );
+    }
+
+    #[test]
+    fn generate_markdown_uses_template() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            template: Some("# {{title}}\n".into()),
+            ..Default::default()
+        };
+        assert_eq!(generate_markdown(&book, &[], &[], &opts), "# T\n");
+    }
+
+    // --- generate_org ---
+
+    #[test]
+    fn generate_org_structure() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "Test Book".into(),
+            author: Some("Author Name".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#sec1", 2),
+        ]);
+        let highlights = vec![Highlight {
+            text: "Line one\nLine two".into(),
+            annotation: Some("my note".into()),
+            chapter_content_id: "book!ch01.xhtml#sec1".into(),
+            date_created: Some("2024-06-01".into()),
+            ..Default::default()
+        }];
+
+        let org = generate_org(&book, &toc, &highlights, &RenderOptions::default());
+        assert_eq!(
+            org,
+            "#+TITLE: Test Book\n\
+             #+AUTHOR: Author Name\n\
+             \n\
+             * Chapter I\n\
+             \n\
+             ** Section 1\n\
+             \n\
+             #+begin_quote\n\
+             Line one\n\
+             Line two\n\
+             #+end_quote\n\
+             - Note :: my note\n\
+             \n\
+             /2024-06-01/\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn generate_org_uncategorized_section() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![make_highlight("orphan", "book!unknown.xhtml#x")];
+
+        let org = generate_org(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(!org.contains("#+AUTHOR:"));
+        assert!(!org.contains("* Ch\n"));
+        assert!(org.contains("* Uncategorized\n\n#+begin_quote\norphan\n#+end_quote\n"));
+    }
+
+    // --- generate_html ---
+
+    #[test]
+    fn escape_html_entities() {
+        assert_eq!(
+            escape_html("<b>Tom & \"Jerry's\"</b>"),
+            "&lt;b&gt;Tom &amp; &quot;Jerry&#39;s&quot;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn generate_html_nests_chapters_in_details() {
+        let book = Book {
+            title: "Tom & Jerry".into(),
+            author: Some("A <Writer>".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Chapter I", "book!ch01.xhtml#ch01", 1),
+            ("Section 1", "book!ch01.xhtml#sec1", 2),
+            ("Chapter II", "book!ch02.xhtml#ch02", 1),
+        ]);
+        let highlights = vec![
+            Highlight {
+                annotation: Some("a <note>".into()),
+                ..make_highlight("one\ntwo", "book!ch01.xhtml#sec1")
+            },
+            make_highlight("second chapter", "book!ch02.xhtml#ch02"),
+        ];
+
+        let html = generate_html(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(html.contains("<h1>Tom &amp; Jerry</h1>"));
+        assert!(html.contains("<strong>Author:</strong> A &lt;Writer&gt;"));
+        assert!(html.contains(
+            "<details open>\n<summary>Chapter I</summary>\n\
+             <details open>\n<summary>Section 1</summary>\n\
+             <blockquote>one<br>\ntwo</blockquote>\n\
+             <p class=\"note\"><strong>Note:</strong> a &lt;note&gt;</p>\n\
+             </details>\n</details>\n\
+             <details open>\n<summary>Chapter II</summary>\n\
+             <blockquote>second chapter</blockquote>\n\
+             </details>\n"
+        ));
+        assert!(html.ends_with("</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn generate_html_uncategorized_section() {
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let highlights = vec![make_highlight("orphan", "unknown")];
+
+        let html = generate_html(&book, &[], &highlights, &RenderOptions::default());
+        assert!(html.contains(
+            "<details open>\n<summary>Uncategorized</summary>\n<blockquote>orphan</blockquote>\n</details>\n"
+        ));
+    }
+
+    #[test]
+    fn generate_html_sets_lang_and_rtl_dir() {
+        let book = |language: Option<&str>| Book {
+            title: "T".into(),
+            language: language.map(Into::into),
+            ..Default::default()
+        };
+        let html = |book: &Book| generate_html(book, &[], &[], &RenderOptions::default());
+        assert!(html(&book(Some("ar")))
+            .starts_with("<!DOCTYPE html>\n<html lang=\"ar\" dir=\"rtl\">\n"));
+        assert!(html(&book(Some("en-US"))).starts_with("<!DOCTYPE html>\n<html lang=\"en-US\">\n"));
+        assert!(html(&book(None)).starts_with("<!DOCTYPE html>\n<html>\n"));
+    }
+
+    #[test]
+    fn generate_markdown_isolates_rtl_highlight_lines() {
+        let book = Book {
+            title: "T".into(),
+            language: Some("he-IL".into()),
+            ..Default::default()
+        };
+        let highlights = vec![make_highlight("שלום, עולם.\n\nשורה שנייה", "unknown")];
+        let md = generate_markdown(&book, &[], &highlights, &RenderOptions::default());
+        assert!(md.contains("> \u{2067}שלום, עולם.\u{2069}\n>\n> \u{2067}שורה שנייה\u{2069}\n"));
+
+        let ltr = Book {
+            language: Some("en".into()),
+            ..book
+        };
+        let md = generate_markdown(&ltr, &[], &highlights, &RenderOptions::default());
+        assert!(!md.contains('\u{2067}'));
+    }
+
+    #[test]
+    fn is_rtl_language_checks_primary_subtag() {
+        assert!(is_rtl_language("ar"));
+        assert!(is_rtl_language("he-IL"));
+        assert!(is_rtl_language("FA_ir"));
+        assert!(!is_rtl_language("en"));
+        assert!(!is_rtl_language("arn"));
+        assert!(!is_rtl_language(""));
+        assert!(!is_rtl_language("und"));
+    }
+
+    // --- generate_notion ---
+
+    #[test]
+    fn generate_notion_flattens_chapters_into_breadcrumbs() {
+        let book = Book {
+            title: "T".into(),
+            author: Some("A".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[
+            ("Part One", "book!part1.xhtml", 1),
+            ("Chapter I", "book!ch01.xhtml", 2),
+            ("Section 1", "book!ch01a.xhtml", 3),
+            ("Chapter II", "book!ch02.xhtml", 2),
+        ]);
+        let highlights = vec![
+            Highlight {
+                annotation: Some("my note".into()),
+                ..make_highlight("First line.\n\nSecond paragraph.", "book!ch01a.xhtml")
+            },
+            make_highlight("Later", "book!ch02.xhtml"),
+            make_highlight("Lost", "elsewhere"),
+        ];
+        let opts = RenderOptions {
+            progress: false,
+            color: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            generate_notion(&book, &toc, &highlights, &opts),
+            "# T\n\n**Author:** A\n\n\
+             ## Part One › Chapter I › Section 1\n\n\
+             > First line.\n\n> Second paragraph.\n\n<aside>\n💬 my note\n</aside>\n\n\
+             ## Part One › Chapter II\n\n\
+             > Later\n\n\
+             ## Uncategorized\n\n\
+             > Lost\n\n"
+        );
+    }
+
+    // --- generate_json ---
+
+    #[test]
+    fn generate_json_includes_book_and_chapter() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "Test Book".into(),
+            author: Some("Author Name".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Chapter I", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![Highlight {
+            text: "Important text".into(),
+            annotation: Some("my note".into()),
+            chapter_content_id: "book!ch01.xhtml#ch01".into(),
+            date_created: Some("2024-06-01".into()),
+            ..Default::default()
+        }];
+
+        let json = generate_json(&book, &toc, &highlights, false).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["title"], "Test Book");
+        assert_eq!(value["author"], "Author Name");
+        assert_eq!(value["highlights"][0]["text"], "Important text");
+        assert_eq!(value["highlights"][0]["annotation"], "my note");
+        assert_eq!(
+            value["highlights"][0]["date_created"],
+            "2024-06-01T00:00:00Z"
+        );
+        assert_eq!(value["highlights"][0]["chapter"], "Chapter I");
+    }
+
+    #[test]
+    fn generate_json_uncategorized_has_null_chapter() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![
+            make_highlight("orphan", "book!unknown.xhtml#x"),
+            make_highlight("matched", "book!ch01.xhtml#ch01"),
+        ];
+
+        let json = generate_json(&book, &toc, &highlights, false).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value["author"].is_null());
+        // Matched highlights come first, uncategorized ones last
+        assert_eq!(value["highlights"][0]["text"], "matched");
+        assert_eq!(value["highlights"][1]["text"], "orphan");
+        assert!(value["highlights"][1]["chapter"].is_null());
+    }
+
+    // --- generate_jsonl ---
+
+    #[test]
+    fn generate_jsonl_writes_one_object_per_line() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "Test Book".into(),
+            author: Some("Author Name".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Chapter I", "book!ch01.xhtml#ch01", 1)]);
+        let highlights = vec![
+            Highlight {
+                annotation: Some("my note".into()),
+                date_created: Some("2024-06-01".into()),
+                ..make_highlight("line\nbreak", "book!ch01.xhtml#ch01")
+            },
+            make_highlight("orphan", "book!unknown.xhtml#x"),
+        ];
+
+        let jsonl = generate_jsonl(&book, &toc, &highlights, false).unwrap();
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["book_title"], "Test Book");
+        assert_eq!(lines[0]["author"], "Author Name");
+        assert_eq!(lines[0]["chapter"], "Chapter I");
+        assert_eq!(lines[0]["text"], "line\nbreak");
+        assert_eq!(lines[0]["annotation"], "my note");
+        assert_eq!(lines[0]["date"], "2024-06-01T00:00:00Z");
+        assert!(lines[1]["chapter"].is_null());
+        assert!(lines[1]["date"].is_null());
+    }
+
+    #[test]
+    fn generate_jsonl_no_highlights_is_empty() {
+        assert_eq!(
+            generate_jsonl(&Book::default(), &[], &[], false).unwrap(),
+            ""
+        );
+    }
+
+    // --- generate_anki ---
+
+    #[test]
+    fn generate_anki_front_and_back() {
+        let book = Book {
+            title: "Book".into(),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch 1", "id", 1)]);
+        let highlights = vec![
+            Highlight {
+                annotation: Some("why <this>".into()),
+                ..make_highlight("Line one\tand\nline two", "id")
+            },
+            make_highlight("orphan", "unknown"),
+            Highlight {
+                annotation: Some("note only".into()),
+                ..make_highlight("", "id")
+            },
+        ];
+
+        assert_eq!(
+            generate_anki(&book, &toc, &highlights, false),
+            "Line one and<br>line two\tBook — Ch 1 — why &lt;this&gt;\n\
+             orphan\tBook\n"
+        );
+    }
+
+    // --- sqlite export ---
+
+    #[test]
+    fn insert_sqlite_book_writes_books_and_highlights() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_sqlite_export(&conn).unwrap();
+        let book = Book {
+            content_id: "book1".into(),
+            title: "Test Book".into(),
+            author: Some("Author Name".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Chapter I", "book!ch01.xhtml", 1)]);
+        let highlights = vec![
+            Highlight {
+                id: "bm1".into(),
+                color: Some(2),
+                date_created: Some("2024-06-01 08:00:00".into()),
+                ..make_highlight("in chapter", "book!ch01.xhtml")
+            },
+            make_noted_highlight(),
+        ];
+        insert_sqlite_book(&conn, &book, &toc, &highlights, false).unwrap();
+
+        let title: String = conn
+            .query_row("SELECT title FROM books", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(title, "Test Book");
+        let mut stmt = conn
+            .prepare(
+                "SELECT chapter, text, date_created, color FROM highlights
+                 JOIN books ON books.id = highlights.book_id
+                 WHERE books.content_id = 'book1'
+                 ORDER BY position",
+            )
+            .unwrap();
+        let rows: Vec<[Option<String>; 4]> = stmt
+            .query_map([], |row| {
+                Ok([row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?])
+            })
+            .unwrap()
+            .collect::<SqlResult<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                [
+                    Some("Chapter I".into()),
+                    Some("in chapter".into()),
+                    Some("2024-06-01T08:00:00Z".into()),
+                    Some("blue".into())
+                ],
+                [
+                    None,
+                    Some("First line.\n\nSecond paragraph.".into()),
+                    Some("2024-01-15T00:00:00Z".into()),
+                    None
+                ]
+            ]
+        );
+    }
+
+    // --- generate_csv ---
+
+    #[test]
+    fn generate_csv_quotes_special_characters() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "Book, Title".into(),
+            author: Some("Author".into()),
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![Highlight {
+            text: "He said \"hi\",\nthen left".into(),
+            annotation: Some("note".into()),
+            chapter_content_id: "id".into(),
+            date_created: Some("2024-06-01".into()),
+            ..Default::default()
+        }];
+
+        let csv = generate_csv(&book, &toc, &highlights, true, false).unwrap();
+        assert_eq!(
+            csv,
+            "book,author,chapter,text,annotation,date\n\
+             \"Book, Title\",Author,Ch,\"He said \"\"hi\"\",\nthen left\",note,2024-06-01T00:00:00Z\n"
+        );
+    }
+
+    #[test]
+    fn generate_csv_without_header_for_combined_output() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let highlights = vec![make_highlight("orphan", "unknown")];
+
+        let csv = generate_csv(&book, &[], &highlights, false, false).unwrap();
+        assert_eq!(csv, "T,,,orphan,,\n");
+    }
+
+    #[test]
+    fn generate_csv_round_trips_through_reader() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_highlight("a, \"tricky\"\nvalue", "id")];
+
+        let csv = generate_csv(&book, &toc, &highlights, true, false).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!(&record[2], "Ch");
+        assert_eq!(&record[3], "a, \"tricky\"\nvalue");
+    }
+
+    // --- incremental state ---
+
+    fn make_dated_highlight(date: Option<&str>) -> Highlight {
+        Highlight {
+            text: "text".into(),
+            date_created: date.map(Into::into),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn has_new_highlights_compares_against_last_run() {
+        let highlights = vec![
+            make_dated_highlight(Some("2024-01-01T08:00:00")),
+            make_dated_highlight(Some("2024-02-01T08:00:00")),
+        ];
+        assert!(has_new_highlights(&highlights, "2024-01-15T00:00:00"));
+        assert!(!has_new_highlights(&highlights, "2024-02-01T08:00:00"));
+    }
+
+    #[test]
+    fn has_new_highlights_treats_undated_as_new() {
+        let highlights = vec![
+            make_dated_highlight(Some("2023-01-01T08:00:00")),
+            make_dated_highlight(None),
+        ];
+        assert!(has_new_highlights(&highlights, "2024-01-01T00:00:00"));
+    }
+
+    // --- generate_index ---
+
+    fn make_index_entry(title: &str, author: Option<&str>, highlights: usize) -> IndexEntry {
+        IndexEntry {
+            title: title.into(),
+            author: author.map(Into::into),
+            link: format!("{title}.md"),
+            highlights,
+        }
+    }
+
+    #[test]
+    fn generate_index_groups_books_by_author() {
+        let index = generate_index(&[
+            make_index_entry("Zebra", Some("Bo"), 2),
+            make_index_entry("Orphan", None, 1),
+            make_index_entry("Apple", Some("Bo"), 5),
+            make_index_entry("Middle", Some("al"), 3),
+        ]);
+        assert_eq!(
+            index,
+            "# Highlights\n\n\
+             ## al\n\n- [Middle](<Middle.md>) (3 highlights)\n\n\
+             ## Bo\n\n- [Apple](<Apple.md>) (5 highlights)\n- [Zebra](<Zebra.md>) (2 highlights)\n\n\
+             ## Unknown Author\n\n- [Orphan](<Orphan.md>) (1 highlights)\n\n"
+        );
+    }
+
+    #[test]
+    fn generate_index_links_nested_paths() {
+        let entry = IndexEntry {
+            link: "Jane Doe/My Book.md".into(),
+            ..make_index_entry("My Book", Some("Jane Doe"), 1)
+        };
+        assert!(generate_index(&[entry]).contains("- [My Book](<Jane Doe/My Book.md>)"));
+    }
+
+    // --- combine_markdown ---
+
+    #[test]
+    fn combine_markdown_separates_books_with_rule() {
+        let first = Book {
+            content_id: "b1".into(),
+            title: "Alpha".into(),
+            author: None,
+            ..Default::default()
+        };
+        let second = Book {
+            content_id: "b2".into(),
+            title: "Beta".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        let highlights = vec![make_highlight("text", "id")];
+
+        let combined = combine_markdown(&[
+            generate_markdown(&first, &toc, &highlights, &RenderOptions::default()),
+            generate_markdown(&second, &toc, &highlights, &RenderOptions::default()),
+        ]);
+        assert!(combined.starts_with("# Alpha\n"));
+        assert!(combined.contains("> text\n\n---\n\n# Beta\n"));
+        assert!(!combined.ends_with("---\n\n"));
+    }
+
+    #[test]
+    fn combine_markdown_single_book_unchanged() {
+        let doc = "# Alpha\n\n---\n\n> text\n\n".to_string();
+        assert_eq!(combine_markdown(std::slice::from_ref(&doc)), doc);
+    }
+
+    // --- analytics fallback ---
+
+    fn create_analytics_db() -> Connection {
+        let conn = create_test_db();
+        conn.execute_batch(
+            r#"CREATE TABLE AnalyticsEvents (
+                Id TEXT PRIMARY KEY,
+                Type TEXT,
+                Count INTEGER,
+                FirstOccurrence TEXT,
+                LastOccurrence TEXT,
+                Attributes TEXT,
+                Metrics TEXT
+            );
+            INSERT INTO AnalyticsEvents (Id, Type, FirstOccurrence, Attributes) VALUES
+                ('e1', 'HighlightAdded', '2024-02-01T09:00:00',
+                 '{"VolumeId":"book1","ChapterId":"book1#ch2","Text":"Later passage","ChapterProgress":"0.5"}'),
+                ('e2', 'HighlightAdded', '2024-01-01T09:00:00',
+                 '{"volumeid":"book1","chapterid":"book1#ch1","text":"Earlier passage","annotation":"A note"}'),
+                ('e3', 'HighlightAdded', '2024-01-02T09:00:00',
+                 '{"VolumeId":"book2","Text":"Other book"}'),
+                ('e4', 'HighlightAdded', '2024-01-03T09:00:00', 'not json'),
+                ('e5', 'OpenContent', '2024-01-04T09:00:00', '{"VolumeId":"book1","Text":"Not a highlight"}');"#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn query_analytics_highlights_parses_events_of_the_book() {
+        let conn = create_analytics_db();
+        let highlights =
+            query_analytics_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(texts(&highlights), ["Earlier passage", "Later passage"]);
+        assert_eq!(highlights[0].annotation.as_deref(), Some("A note"));
+        assert_eq!(highlights[0].chapter_content_id, "book1#ch1");
+        assert_eq!(
+            highlights[0].date_created.as_deref(),
+            Some("2024-01-01T09:00:00")
+        );
+        assert_eq!(highlights[1].chapter_progress, Some(0.5));
+
+        let filter = HighlightFilter {
+            since: Some("2024-01-15".into()),
+            ..Default::default()
+        };
+        let highlights = query_analytics_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), ["Later passage"]);
+    }
+
+    #[test]
+    fn query_analytics_highlights_without_table_is_empty() {
+        let conn = create_test_db();
+        let highlights =
+            query_analytics_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert!(highlights.is_empty());
+    }
+
+    // --- DB integration test with in-memory SQLite ---
+
+    fn create_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE content (
+                ContentID TEXT NOT NULL,
+                ContentType TEXT NOT NULL,
+                BookID TEXT,
+                Title TEXT,
+                Attribution TEXT,
+                VolumeIndex INTEGER DEFAULT 0,
+                ___PercentRead INTEGER,
+                DateLastRead TEXT,
+                ___SyncTime TEXT
+            );
+            CREATE TABLE Bookmark (
+                BookmarkID TEXT NOT NULL,
+                VolumeID TEXT NOT NULL,
+                ContentID TEXT NOT NULL,
+                Text TEXT,
+                Annotation TEXT,
+                DateCreated TEXT,
+                ChapterProgress REAL DEFAULT 0,
+                Hidden BOOL DEFAULT 0,
+                Color INTEGER DEFAULT 0,
+                StyleID INTEGER
+            );",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn db_query_bookmarks_returns_only_dogears() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('h', 'book1', 'book1!ch01', 'Highlighted', NULL, 0.1),
+                    ('n', 'book1', 'book1!ch01', NULL, 'A note', 0.2),
+                    ('d2', 'book1', 'book1!ch01', NULL, NULL, 0.9),
+                    ('d1', 'book1', 'book1!ch01', '', NULL, 0.3),
+                    ('other', 'book2', 'book2!ch01', NULL, NULL, 0.5);",
+        )
+        .unwrap();
+
+        let bookmarks = query_bookmarks(&conn, "book1").unwrap();
+        let ids: Vec<&str> = bookmarks.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, ["d1", "d2"]);
+        assert_eq!(bookmarks[0].chapter_progress, Some(0.3));
+
+        // Dogears are still left out of the highlights
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights.len(), 1);
+    }
+
+    #[test]
+    fn db_query_highlights_reads_start_container_path() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text)
+             VALUES ('bm1', 'book1', 'book1!ch01', 'text');",
+        )
+        .unwrap();
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights[0].location, None);
+
+        conn.execute_batch(
+            "ALTER TABLE Bookmark ADD COLUMN StartContainerPath TEXT;
+             UPDATE Bookmark SET StartContainerPath = 'span#kobo\\.4\\.1';",
+        )
+        .unwrap();
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights[0].location.as_deref(), Some("span#kobo\\.4\\.1"));
+    }
+
+    #[test]
+    fn db_schema_version() {
+        let conn = create_test_db();
+        assert_eq!(schema_version(&conn).unwrap(), None);
+
+        conn.execute_batch("PRAGMA user_version = 7;").unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), Some(7));
+
+        // DbVersion wins over the pragma
+        conn.execute_batch(
+            "CREATE TABLE DbVersion (version INTEGER);
+             INSERT INTO DbVersion VALUES (174);",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), Some(174));
+    }
+
+    #[test]
+    fn db_available_columns() {
+        let conn = create_test_db();
+        let columns = available_columns(&conn).unwrap();
+        assert!(columns.contains(&("Bookmark", "Color")));
+        assert!(columns.contains(&("Bookmark", "StyleID")));
+        assert!(!columns.contains(&("Bookmark", "StartContainerPath")));
+    }
+
+    #[test]
+    fn db_query_highlights_filters_by_color() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Color)
+             VALUES ('y', 'book1', 'book1!ch01', 'quote', 0),
+                    ('r', 'book1', 'book1!ch01', 'disagree', 1),
+                    ('n', 'book1', 'book1!ch01', 'no color', NULL);",
+        )
+        .unwrap();
+        let filter = HighlightFilter {
+            color: Some(1),
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), ["disagree"]);
+        assert_eq!(count_highlights(&conn, "book1", &filter).unwrap(), 1);
+    }
+
+    #[test]
+    fn db_missing_tables() {
+        assert!(missing_tables(&create_test_db()).unwrap().is_empty());
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE CONTENT (ContentID TEXT);")
+            .unwrap();
+        assert_eq!(missing_tables(&conn).unwrap(), ["Bookmark"]);
+    }
+
+    #[test]
+    fn db_query_books_of_alternate_type() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title)
+             VALUES ('book1', '6', NULL, 'Book'),
+                    ('doc1', '10', NULL, 'Sideloaded'),
+                    ('toc1', '899', 'book1', 'Chapter');",
+        )
+        .unwrap();
+
+        let titles = |types: &[i64]| -> Vec<String> {
+            query_books_of_types(&conn, types)
+                .unwrap()
+                .into_iter()
+                .map(|b| b.title)
+                .collect()
+        };
+        assert_eq!(titles(&[BOOK_CONTENT_TYPE]), ["Book"]);
+        assert_eq!(titles(&[10]), ["Sideloaded"]);
+        assert_eq!(titles(&[6, 10]), ["Book", "Sideloaded"]);
+    }
+
+    #[test]
+    fn db_query_books() {
+        let conn = create_test_db();
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, Attribution)
+             VALUES ('book1', '6', NULL, 'Blue Lantern', 'Nora Finch')",
+            [],
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].title, "Blue Lantern");
+        assert_eq!(books[0].author.as_deref(), Some("Nora Finch"));
+    }
+
+    #[test]
+    fn db_query_books_null_title_falls_back() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title)
+             VALUES ('file:///mnt/onboard/Sideloaded Novel.epub', '6', NULL, NULL);
+             INSERT INTO content (ContentID, ContentType, BookID, Title)
+             VALUES ('0a1b2c3d', '6', NULL, NULL);",
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        let titles: Vec<&str> = books.iter().map(|b| b.title.as_str()).collect();
+        assert_eq!(titles, vec!["Sideloaded Novel", "Untitled"]);
+    }
+
+    #[test]
+    fn generate_markdown_empty_title_uses_untitled() {
+        let book = Book {
+            content_id: "b".into(),
+            title: String::new(),
+            author: None,
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(md.starts_with("# Untitled\n"));
+    }
+
+    #[test]
+    fn db_query_books_reads_percent_read() {
+        let conn = create_test_db();
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, ___PercentRead)
+             VALUES ('book1', '6', NULL, 'Book', 73)",
+            [],
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        assert_eq!(books[0].percent_read, Some(73));
+    }
+
+    #[test]
+    fn db_query_books_reads_last_read() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, DateLastRead, ___SyncTime)
+             VALUES ('a', '6', NULL, 'A', '2024-03-01T09:00:00Z', '2024-01-01T00:00:00Z'),
+                    ('b', '6', NULL, 'B', NULL, '2024-02-01T00:00:00Z'),
+                    ('c', '6', NULL, 'C', NULL, NULL);",
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        let last_read: Vec<_> = books.iter().map(|b| b.last_read.as_deref()).collect();
+        assert_eq!(
+            last_read,
+            [
+                Some("2024-03-01T09:00:00Z"),
+                Some("2024-02-01T00:00:00Z"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn modified_since_compares_last_read_date() {
+        let book = Book {
+            last_read: Some("2024-03-01T09:00:00Z".into()),
+            ..Default::default()
+        };
+        assert!(modified_since(&book, "2024-03-01"));
+        assert!(!modified_since(&book, "2024-03-02"));
+        assert!(!modified_since(&Book::default(), "2000-01-01"));
+    }
+
+    #[test]
+    fn db_query_books_reads_date_added_with_sync_time_fallback() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "ALTER TABLE content ADD COLUMN DateAdded TEXT;
+             INSERT INTO content (ContentID, ContentType, BookID, Title, DateAdded, ___SyncTime)
+             VALUES ('a', '6', NULL, 'A', '2023-05-01T12:00:00Z', '2024-01-01T00:00:00Z'),
+                    ('b', '6', NULL, 'B', NULL, '2024-02-01T00:00:00Z'),
+                    ('c', '6', NULL, 'C', NULL, NULL);",
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        let added: Vec<_> = books.iter().map(|b| b.date_added.as_deref()).collect();
+        assert_eq!(
+            added,
+            [
+                Some("2023-05-01T12:00:00Z"),
+                Some("2024-02-01T00:00:00Z"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn db_query_books_reads_language() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "ALTER TABLE content ADD COLUMN Language TEXT;
+             INSERT INTO content (ContentID, ContentType, BookID, Title, Language)
+             VALUES ('a', '6', NULL, 'A', 'ar'),
+                    ('b', '6', NULL, 'B', ' '),
+                    ('c', '6', NULL, 'C', NULL);",
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        let languages: Vec<_> = books.iter().map(|b| b.language.as_deref()).collect();
+        assert_eq!(languages, [Some("ar"), None, None]);
+    }
+
+    #[test]
+    fn added_since_excludes_books_without_date() {
+        let book = Book {
+            date_added: Some("2024-03-01T09:00:00Z".into()),
+            ..Default::default()
+        };
+        assert!(added_since(&book, "2024-03-01"));
+        assert!(!added_since(&book, "2024-03-02"));
+        assert!(!added_since(&Book::default(), "2000-01-01"));
+    }
+
+    #[test]
+    fn db_query_books_without_percent_read_column() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE content (
+                ContentID TEXT NOT NULL,
+                ContentType TEXT NOT NULL,
+                BookID TEXT,
+                Title TEXT,
+                Attribution TEXT
+            );
+            INSERT INTO content (ContentID, ContentType, BookID, Title)
+            VALUES ('book1', '6', NULL, 'Old Schema Book');",
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].percent_read, None);
+        assert_eq!(books[0].last_read, None);
+        assert_eq!(books[0].date_added, None);
+        assert_eq!(books[0].language, None);
+    }
+
+    #[test]
+    fn parse_authors_splits_on_separators() {
+        assert_eq!(
+            parse_authors("Author One\nAuthor Two"),
+            ["Author One", "Author Two"]
+        );
+        assert_eq!(parse_authors("A; B & C"), ["A", "B", "C"]);
+        assert_eq!(parse_authors("Hale, Samir"), ["Hale, Samir"]);
+        assert!(parse_authors(" ; ").is_empty());
+    }
+
+    #[test]
+    fn generate_markdown_multiple_authors() {
+        let book = Book {
+            title: "T".into(),
+            author: Some("Author One\nAuthor Two".into()),
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(md.contains("**Authors:** Author One, Author Two\n"));
+        assert!(!md.contains("**Author:**"));
+
+        let book = Book {
+            title: "T".into(),
+            author: Some("Solo".into()),
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(md.contains("**Author:** Solo\n"));
+    }
+
+    #[test]
+    fn generate_org_joins_multiple_authors() {
+        let book = Book {
+            title: "T".into(),
+            author: Some("A; B".into()),
+            ..Default::default()
+        };
+        let org = generate_org(&book, &[], &[], &RenderOptions::default());
+        assert!(org.contains("#+AUTHOR: A, B\n"));
+    }
+
+    #[test]
+    fn generate_markdown_progress_line() {
+        let book = Book {
+            title: "T".into(),
+            percent_read: Some(73),
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(md.contains("**Progress:** 73%\n"));
+
+        let book = Book {
+            title: "T".into(),
+            ..Default::default()
+        };
+        let md = generate_markdown(&book, &[], &[], &RenderOptions::default());
+        assert!(!md.contains("**Progress:**"));
+    }
+
+    #[test]
+    fn db_query_books_skips_chapters() {
+        let conn = create_test_db();
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title)
+             VALUES ('book1', '6', NULL, 'Book')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title)
+             VALUES ('ch1', '9', 'book1', 'Chapter file')",
+            [],
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        assert_eq!(books.len(), 1);
+    }
+
+    #[test]
+    fn db_query_toc_hierarchy() {
+        let conn = create_test_db();
+        // Chapter file (type 9) — should be ignored
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+             VALUES ('book!Chapter01.xhtml', '9', 'book1', 'Chapter01.xhtml', 0)",
+            [],
+        )
+        .unwrap();
+        // Chapter heading (type 899, depth 2 = part level)
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+             VALUES ('book!Chapter01.xhtml#ch01-2', '899', 'book1', 'I. KAPITEL', 0)",
+            [],
+        )
+        .unwrap();
+        // Sub-section (type 899, depth 3 = section level)
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+             VALUES ('book!Chapter01.xhtml#ch01_1-3', '899', 'book1', '1. Section', 1)",
+            [],
+        )
+        .unwrap();
+
+        let toc = query_toc(&conn, "book1").unwrap();
+        assert_eq!(toc.len(), 2);
+
+        assert_eq!(toc[0].title, "I. KAPITEL");
+        assert_eq!(toc[0].depth, 2);
+        assert_eq!(toc[0].match_id, "book!Chapter01.xhtml#ch01");
+
+        assert_eq!(toc[1].title, "1. Section");
+        assert_eq!(toc[1].depth, 3);
+        assert_eq!(toc[1].match_id, "book!Chapter01.xhtml#ch01_1");
+    }
+
+    #[test]
+    fn db_query_toc_tied_volume_index_ordered_by_content_id() {
+        let conn = create_test_db();
+        // Inserted out of order, all sharing VolumeIndex 0
+        for (content_id, title) in [
+            ("book!ch03.xhtml-1", "Three"),
+            ("book!ch01.xhtml-1", "One"),
+            ("book!ch02.xhtml-1", "Two"),
+        ] {
+            conn.execute(
+                "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+                 VALUES (?1, '899', 'book1', ?2, 0)",
+                [content_id, title],
+            )
+            .unwrap();
+        }
+
+        let toc = query_toc(&conn, "book1").unwrap();
+        let titles: Vec<&str> = toc.iter().map(|e| e.title.as_str()).collect();
+        assert_eq!(titles, vec!["One", "Two", "Three"]);
+    }
+
+    #[test]
+    fn db_query_highlights() {
+        let conn = create_test_db();
+        conn.execute(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, DateCreated, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml#sec1', 'highlighted text', 'my note', '2024-01-15', 0.5)",
+            [],
+        )
+        .unwrap();
+        // Dogear bookmark (no text) — should be excluded
+        conn.execute(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml#sec2', NULL, 0.8)",
+            [],
+        )
+        .unwrap();
+
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].text, "highlighted text");
+        assert_eq!(highlights[0].annotation.as_deref(), Some("my note"));
+    }
+
+    #[test]
+    fn db_query_highlights_breaks_ties_by_bookmark_id() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('c', 'book1', 'book!ch01.xhtml', 'third', '2024-01-15', 0.5),
+                    ('a', 'book1', 'book!ch01.xhtml', 'first', '2024-01-15', 0.5),
+                    ('b', 'book1', 'book!ch01.xhtml', 'second', '2024-01-15', 0.5);",
+        )
+        .unwrap();
+
+        for sort in [SortOrder::Position, SortOrder::Date] {
+            let filter = HighlightFilter {
+                sort,
+                ..Default::default()
+            };
+            let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+            assert_eq!(texts(&highlights), ["first", "second", "third"]);
+        }
+    }
+
+    #[test]
+    fn db_query_highlights_reads_color() {
+        let conn = create_test_db();
+        conn.execute(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Color)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'colored', 3)",
+            [],
+        )
+        .unwrap();
+
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights[0].color, Some(3));
+    }
+
+    #[test]
+    fn db_query_highlights_without_color_column() {
+        // Older firmware has no Color column on Bookmark
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE Bookmark (
+                BookmarkID TEXT NOT NULL,
+                VolumeID TEXT NOT NULL,
+                ContentID TEXT NOT NULL,
+                Text TEXT,
+                Annotation TEXT,
+                DateCreated TEXT,
+                ChapterProgress REAL DEFAULT 0,
+                Hidden BOOL DEFAULT 0
+            );
+            INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text)
+            VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'plain');",
+        )
+        .unwrap();
+
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].color, None);
+    }
+
+    #[test]
+    fn db_query_highlights_notes_only() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', NULL, 'standalone note', 0.1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', '', 'empty-text note', 0.2);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, ChapterProgress)
+             VALUES ('bm3', 'book1', 'book!ch01.xhtml', NULL, 0.3);",
+        )
+        .unwrap();
+
+        let default = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert!(default.is_empty());
+
+        let filter = HighlightFilter {
+            include_notes_only: true,
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        // The plain dogear (no text, no note) still stays out
+        assert_eq!(highlights.len(), 2);
+        assert_eq!(highlights[0].text, "");
+        assert_eq!(highlights[0].annotation.as_deref(), Some("standalone note"));
+    }
+
+    #[test]
+    fn db_query_highlights_highlights_only_drops_underlines() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, StyleID, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'highlighted', 0, 0.1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, StyleID, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'underlined', 1, 0.2);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, StyleID, ChapterProgress)
+             VALUES ('bm3', 'book1', 'book!ch01.xhtml', 'no style', NULL, 0.3);",
+        )
+        .unwrap();
+
+        let all = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(texts(&all), ["highlighted", "underlined", "no style"]);
+        assert!(is_underline(&all[1]));
+
+        let filter = HighlightFilter {
+            highlights_only: true,
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), ["highlighted", "no style"]);
+    }
+
+    #[test]
+    fn db_count_highlights_matches_query() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'one', 0);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'hidden', 1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation)
+             VALUES ('bm3', 'book1', 'book!ch01.xhtml', NULL, 'note');
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text)
+             VALUES ('bm4', 'book2', 'other!ch01.xhtml', 'elsewhere');",
+        )
+        .unwrap();
+
+        let filter = HighlightFilter::default();
+        assert_eq!(count_highlights(&conn, "book1", &filter).unwrap(), 1);
+        let filter = HighlightFilter {
+            include_notes_only: true,
+            ..Default::default()
+        };
+        assert_eq!(count_highlights(&conn, "book1", &filter).unwrap(), 2);
+        assert_eq!(count_highlights(&conn, "missing", &filter).unwrap(), 0);
+    }
+
+    #[test]
+    fn db_count_library_highlights_sums_selected_books() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'one', 0),
+                    ('bm2', 'book1', 'book!ch01.xhtml', 'two', 0),
+                    ('bm3', 'book1', 'book!ch01.xhtml', 'hidden', 1),
+                    ('bm4', 'book2', 'other!ch01.xhtml', 'elsewhere', 0),
+                    ('bm5', 'book3', 'third!ch01.xhtml', 'not selected', 0);",
+        )
+        .unwrap();
+
+        let filter = HighlightFilter::default();
+        let count = |ids: &[&str], min| count_library_highlights(&conn, ids, &filter, min).unwrap();
+        assert_eq!(count(&["book1", "book2"], 1), 3);
+        // Books below --min-highlights don't count
+        assert_eq!(count(&["book1", "book2"], 2), 2);
+        assert_eq!(count(&[], 1), 0);
+    }
+
+    #[test]
+    fn db_query_highlights_annotated_only() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'plain', NULL, 0.1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'empty note', '', 0.2);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, ChapterProgress)
+             VALUES ('bm3', 'book1', 'book!ch02.xhtml', 'annotated', 'why', 0.3);",
+        )
+        .unwrap();
+
+        let filter = HighlightFilter {
+            annotated_only: true,
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), ["annotated"]);
+        assert_eq!(count_highlights(&conn, "book1", &filter).unwrap(), 1);
+    }
+
+    #[test]
+    fn db_query_highlights_skips_hidden() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'visible', 'false');
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'deleted as string', 'true');
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm3', 'book1', 'book!ch01.xhtml', 'deleted as integer', 1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Hidden)
+             VALUES ('bm4', 'book1', 'book!ch01.xhtml', 'no flag', NULL);",
+        )
+        .unwrap();
+
+        let highlights = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        let texts: Vec<&str> = highlights.iter().map(|h| h.text.as_str()).collect();
+        assert_eq!(texts, vec!["visible", "no flag"]);
+    }
+
+    fn insert_dated_highlights(conn: &Connection) {
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'old', '2023-12-31T23:59:59', 0.1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'new', '2024-01-01T08:00:00', 0.2);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('bm3', 'book1', 'book!ch01.xhtml', 'undated', NULL, 0.3);",
+        )
+        .unwrap();
+    }
+
+    fn texts(highlights: &[Highlight]) -> Vec<&str> {
+        highlights.iter().map(|h| h.text.as_str()).collect()
+    }
+
+    #[test]
+    fn db_query_highlights_since_excludes_older_and_undated() {
+        let conn = create_test_db();
+        insert_dated_highlights(&conn);
+
+        let filter = HighlightFilter {
+            since: Some("2024-01-01".into()),
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), vec!["new"]);
+    }
+
+    #[test]
+    fn db_query_highlights_until_is_inclusive_and_keeps_undated() {
+        let conn = create_test_db();
+        insert_dated_highlights(&conn);
+
+        let filter = HighlightFilter {
+            until: Some("2023-12-31".into()),
+            ..Default::default()
+        };
+        let highlights = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(texts(&highlights), vec!["old", "undated"]);
+    }
+
+    #[test]
+    fn db_query_highlights_sort_by_date() {
+        let conn = create_test_db();
+        conn.execute_batch(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book!ch01.xhtml', 'early in book, late in time', '2024-03-01T00:00:00', 0.1);
+             INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, DateCreated, ChapterProgress)
+             VALUES ('bm2', 'book1', 'book!ch01.xhtml', 'late in book, early in time', '2024-01-01T00:00:00', 0.9);",
+        )
+        .unwrap();
+
+        let by_position = query_highlights(&conn, "book1", &HighlightFilter::default()).unwrap();
+        assert_eq!(
+            texts(&by_position),
+            vec!["early in book, late in time", "late in book, early in time"]
+        );
+
+        let filter = HighlightFilter {
+            sort: SortOrder::Date,
+            ..Default::default()
+        };
+        let by_date = query_highlights(&conn, "book1", &filter).unwrap();
+        assert_eq!(
+            texts(&by_date),
+            vec!["late in book, early in time", "early in book, late in time"]
+        );
+    }
+
+    #[test]
+    fn generate_markdown_date_sorted_within_chapter() {
+        let book = Book {
+            content_id: "b".into(),
+            title: "T".into(),
+            author: None,
+            ..Default::default()
+        };
+        let toc = make_toc(&[("Ch", "id", 1)]);
+        // Already in date order, as returned by query_highlights with SortOrder::Date
+        let highlights = vec![
+            make_highlight("second", "id"),
+            make_highlight("first", "id"),
+        ];
+
+        let md = generate_markdown(&book, &toc, &highlights, &RenderOptions::default());
+        assert!(md.find("> second").unwrap() < md.find("> first").unwrap());
+    }
+
+    #[test]
+    fn db_end_to_end() {
+        let conn = create_test_db();
+
+        // Book
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, Attribution)
+             VALUES ('book1', '6', NULL, 'The Paper Orchard', 'Samir Hale')",
+            [],
+        )
+        .unwrap();
+
+        // TOC entries (899 only matter)
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+             VALUES ('book1!ch01.xhtml#ch01-1', '899', 'book1', 'I. Chapter Seven', 0)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
+             VALUES ('book1!ch01.xhtml#ch01_1-2', '899', 'book1', '1. Abschnitt', 1)",
+            [],
+        )
+        .unwrap();
+
+        // Highlight matching the sub-section
+        conn.execute(
+            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, ChapterProgress)
+             VALUES ('bm1', 'book1', 'book1!ch01.xhtml#ch01_1', 'A curious passage about seasons', 0.1)",
+            [],
+        )
+        .unwrap();
+
+        let books = query_books(&conn).unwrap();
+        assert_eq!(books.len(), 1);
+
+        let toc = query_toc(&conn, &books[0].content_id).unwrap();
+        let highlights =
+            query_highlights(&conn, &books[0].content_id, &HighlightFilter::default()).unwrap();
+        let md = generate_markdown(&books[0], &toc, &highlights, &RenderOptions::default());
+
+        assert!(md.contains("# The Paper Orchard\n"));
+        assert!(md.contains("**Author:** Samir Hale"));
+        assert!(md.contains("## I. Chapter Seven\n"));
+        assert!(md.contains("### 1. Abschnitt\n"));
+        assert!(md.contains("> A curious passage about seasons\n\n^kobo-bm1\n"));
+        assert!(!md.contains("Uncategorized"));
+    }
+}
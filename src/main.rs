@@ -1,319 +1,1627 @@
-use clap::Parser;
-use rusqlite::{Connection, Result as SqlResult};
-use std::collections::HashMap;
+mod config;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use kobo_highlights_exporter::{
+    added_since, assign_highlights, author_file_stem, available_columns, book_dir,
+    book_without_dates, color_code, combine_documents, contains_ignore_case, count_highlights,
+    count_library_highlights, create_sqlite_export, dedup_highlights, generate_anki, generate_csv,
+    generate_html, generate_index, generate_json, generate_jsonl, generate_markdown,
+    generate_notion, generate_org, has_new_highlights, insert_sqlite_book, markdown_bookmarks,
+    merge_adjacent_highlights, missing_tables, modified_since, nearest_match_id, normalize_depths,
+    parse_kobo_date, query_analytics_highlights, query_bookmarks, query_books_of_types,
+    query_highlights, query_toc, sanitize_filename, sanitize_tag, schema_version,
+    split_annotations, unique_file_stem, validate_chapter_template, without_annotations,
+    without_dates, Book, GroupBy, Highlight, HighlightFilter, HighlightStats, IndexEntry,
+    OutputFormat, Punctuation, QuoteStyle, RenderOptions, SortOrder, TocEntry, BOOK_CONTENT_TYPE,
+    UNCATEGORIZED,
+};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// The `--zip` archive, built in memory and written out at the end.
+type Archive = ZipWriter<Cursor<Vec<u8>>>;
+
+/// Progress and status messages on stderr, silenced by `--quiet`.
+macro_rules! status {
+    ($cli:expr, $($arg:tt)*) => {
+        if !$cli.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Output the user asked for with `--dry-run` or `--stats`: on stderr with
+/// the status messages, or on stdout with `--quiet`.
+macro_rules! report {
+    ($cli:expr, $($arg:tt)*) => {
+        if $cli.quiet {
+            println!($($arg)*);
+        } else {
+            eprintln!($($arg)*);
+        }
+    };
+}
 
 #[derive(Parser)]
 #[command(name = "kobo-highlights-exporter")]
 #[command(about = "Export Kobo highlights and annotations to Markdown")]
 struct Cli {
     /// Path to the KoboReader.sqlite file
-    db_path: PathBuf,
+    #[arg(env = "KOBO_DB")]
+    db_path: Option<PathBuf>,
 
-    /// Output directory for Markdown files
+    /// Read option defaults from this file instead of ./kobo-export.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Mount point of the Kobo device; the database is read from
+    /// <MOUNTPOINT>/.kobo/KoboReader.sqlite
+    #[arg(long, value_name = "MOUNTPOINT", conflicts_with = "auto")]
+    device: Option<PathBuf>,
+
+    /// Look for a connected Kobo device in the usual mount locations
+    #[arg(long)]
+    auto: bool,
+
+    /// Output directory for Markdown files, or "-" for stdout; the database
+    /// file with --format sqlite
     #[arg(short, long, default_value = "highlights")]
     output_dir: PathBuf,
-}
 
-struct Book {
-    content_id: String,
-    title: String,
+    /// Write the output of all books to stdout instead of files
+    #[arg(long, conflicts_with_all = ["single_file", "index", "by_author", "incremental"])]
+    stdout: bool,
+
+    /// Only export books whose title contains this text (case-insensitive)
+    #[arg(long, value_name = "SUBSTRING")]
+    book: Option<String>,
+
+    /// Only export books whose author contains this text (case-insensitive)
+    #[arg(long, value_name = "SUBSTRING")]
     author: Option<String>,
+
+    /// Skip books whose title contains this text (case-insensitive); can be
+    /// repeated, and wins over --book
+    #[arg(long, value_name = "SUBSTRING")]
+    exclude_book: Vec<String>,
+
+    /// ContentType code of the library entries that are books; can be
+    /// repeated for libraries where other codes are books too
+    #[arg(long, value_name = "N", default_values_t = [BOOK_CONTENT_TYPE])]
+    book_content_type: Vec<i64>,
+
+    /// Output format for the exported files
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Markdown)]
+    format: OutputFormat,
+
+    /// Write all books into this single file instead of one file per book
+    /// (output_dir is ignored)
+    #[arg(long, value_name = "PATH")]
+    single_file: Option<PathBuf>,
+
+    /// Write the files into this zip archive instead of a directory, with
+    /// output_dir as the folder they go in inside the archive
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["single_file", "stdout", "incremental"])]
+    zip: Option<PathBuf>,
+
+    /// Also write an index.md linking to every exported book
+    #[arg(long, conflicts_with = "single_file")]
+    index: bool,
+
+    /// Write each book into a subdirectory named after its author
+    #[arg(long, conflicts_with = "single_file")]
+    by_author: bool,
+
+    /// Write one Markdown file per author, with all their books in it
+    #[arg(
+        long,
+        conflicts_with_all = ["single_file", "by_author", "index", "incremental", "separate_notes", "skip_existing"]
+    )]
+    combine_by_author: bool,
+
+    /// Don't tag highlights with their color
+    #[arg(long)]
+    no_color: bool,
+
+    /// Reformat highlight dates with this strftime pattern, e.g. "%Y-%m-%d"
+    /// or "%B %d, %Y" (default: as stored by Kobo)
+    #[arg(long, value_name = "STRFTIME", value_parser = parse_date_format)]
+    date_format: Option<String>,
+
+    /// Leave the highlight dates out of every output format, overriding
+    /// --date-format and --relative-dates
+    #[arg(long)]
+    no_dates: bool,
+
+    /// Show highlight dates relative to the export time, e.g. "3 months ago"
+    #[arg(long, conflicts_with = "date_format")]
+    relative_dates: bool,
+
+    /// Keep soft hyphens, zero-width characters and repeated whitespace in
+    /// highlight text
+    #[arg(long)]
+    no_clean: bool,
+
+    /// Keep whitespace and blank lines before and after highlight text
+    #[arg(long)]
+    no_trim: bool,
+
+    /// Backslash-escape * _ [ ] ( ) # and \ in Markdown highlight text
+    #[arg(long)]
+    escape_markdown: bool,
+
+    /// Don't show how far into the chapter each highlight is
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Render each Markdown file through this template instead of the
+    /// built-in layout (placeholders: {{title}}, {{author}}, {{progress}},
+    /// {{highlight_count}}, {{highlights}})
+    #[arg(long, value_name = "FILE")]
+    template: Option<PathBuf>,
+
+    /// Layout of the chapter headings in Markdown output (placeholders:
+    /// {depth_marker}, {title}, {count}, {depth})
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_chapter_template)]
+    chapter_template: Option<String>,
+
+    /// Prepend YAML frontmatter (title, author, highlight count, export date)
+    /// to each Markdown file
+    #[arg(long)]
+    frontmatter: bool,
+
+    /// Tag every Markdown file, in the frontmatter or else on a `#tag` line
+    /// at the top; can be repeated
+    #[arg(long, value_name = "NAME", value_parser = parse_tag)]
+    tag: Vec<String>,
+
+    /// Push every Markdown heading down by this many levels (capped at 6)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    heading_offset: usize,
+
+    /// Also export annotations that have no highlighted text
+    #[arg(long)]
+    include_notes_only: bool,
+
+    /// Put every chapter at the same heading level instead of nesting
+    /// sections under their parents
+    #[arg(long)]
+    flatten: bool,
+
+    /// Leave out underlined passages, exporting only highlights
+    #[arg(long)]
+    highlights_only: bool,
+
+    /// Heading for highlights that match no chapter; an empty label appends
+    /// them without a heading
+    #[arg(long, value_name = "LABEL", default_value = UNCATEGORIZED)]
+    uncategorized_label: String,
+
+    /// Write your notes to a separate <title>.notes.md next to each book's
+    /// Markdown file, which then only holds the highlighted passages
+    #[arg(long, conflicts_with_all = ["single_file", "stdout"])]
+    separate_notes: bool,
+
+    /// Add a Bookmarks section listing dogeared pages to each Markdown file
+    #[arg(long)]
+    include_bookmarks: bool,
+
+    /// Only export highlights of this color: yellow, red, blue, green or a
+    /// numeric Kobo color code
+    #[arg(long, value_name = "COLOR", value_parser = parse_color)]
+    only_color: Option<i64>,
+
+    /// Only export highlights you wrote a note for
+    #[arg(long)]
+    annotated_only: bool,
+
+    /// Leave your notes out of every output format, keeping only the
+    /// highlighted passages
+    #[arg(long, conflicts_with_all = ["annotated_only", "separate_notes"])]
+    strip_annotations: bool,
+
+    /// For books without highlights in the Bookmark table, look for them in
+    /// the AnalyticsEvents log older firmware keeps; notes are often missing
+    #[arg(long)]
+    analytics_fallback: bool,
+
+    /// Show where each highlight starts in the book (its EPUB container path)
+    #[arg(long)]
+    locations: bool,
+
+    /// Write notes as "- [ ] Note: ..." task items to tick off while reviewing
+    #[arg(long)]
+    notes_as_tasks: bool,
+
+    /// Decode HTML entities such as &amp; that the book stored literally in
+    /// highlight text
+    #[arg(long)]
+    decode_entities: bool,
+
+    /// Remove inline HTML tags such as <i> that the book stored literally in
+    /// highlight text
+    #[arg(long)]
+    strip_tags: bool,
+
+    /// Convert curly quotes and dashes in highlight text to plain ASCII ones
+    /// (or curly ones with --smart-quotes)
+    #[arg(long)]
+    normalize_punctuation: bool,
+
+    /// Normalize punctuation to curly quotes and en/em dashes
+    #[arg(long, conflicts_with = "straight_quotes")]
+    smart_quotes: bool,
+
+    /// Normalize punctuation to straight quotes and ASCII dashes, the
+    /// default direction of --normalize-punctuation
+    #[arg(long)]
+    straight_quotes: bool,
+
+    /// Show the number of highlights after each chapter heading
+    #[arg(long)]
+    chapter_counts: bool,
+
+    /// List the chapters with highlights at the top of each Markdown file,
+    /// linked to their headings
+    #[arg(long)]
+    toc: bool,
+
+    /// Group highlights under a sub-heading per color within each chapter
+    #[arg(long)]
+    group_by_color: bool,
+
+    /// Put Markdown highlights under a heading per chapter, or per day they
+    /// were made
+    #[arg(long, value_enum, default_value_t = GroupBy::Chapter)]
+    group_by: GroupBy,
+
+    /// Append highlight, word and annotation counts to each Markdown file and
+    /// to the final summary
+    #[arg(long)]
+    stats: bool,
+
+    /// When several TOC entries point at the same location, file highlights
+    /// under the deepest one instead of the first
+    #[arg(long)]
+    prefer_deepest: bool,
+
+    /// Renumber TOC levels that skip a depth, so a chapter's sections are
+    /// always one heading level below it
+    #[arg(long)]
+    normalize_depth: bool,
+
+    /// File highlights that match no TOC entry under the chapter whose file
+    /// most closely precedes theirs, instead of under "Uncategorized"
+    #[arg(long)]
+    flatten_uncategorized_into_chapters: bool,
+
+    /// Prepend a BibTeX entry for the book to each Markdown file
+    #[arg(long)]
+    bibtex: bool,
+
+    /// Merge consecutive highlights that are next to each other in the same
+    /// chapter into one quote
+    #[arg(long)]
+    merge_adjacent: bool,
+
+    /// Keep duplicate highlights with identical text in the same chapter
+    #[arg(long)]
+    no_dedup: bool,
+
+    /// Only export highlights created on or after this date
+    #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_date)]
+    since: Option<String>,
+
+    /// Only export highlights created on or before this date
+    #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_date)]
+    until: Option<String>,
+
+    /// Skip books with fewer highlights than this
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    min_highlights: usize,
+
+    /// Only export the first N books left after the other filters, for
+    /// trying out options quickly
+    #[arg(long, value_name = "N")]
+    max_books: Option<usize>,
+
+    /// Print the output of the first book whose title contains this text to
+    /// stdout, without writing any files
+    #[arg(
+        long,
+        value_name = "SUBSTRING",
+        conflicts_with_all = ["single_file", "index", "by_author", "incremental", "separate_notes", "zip", "list", "count_only"]
+    )]
+    preview: Option<String>,
+
+    /// Only export books opened on or after this date
+    #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_date)]
+    modified_since: Option<String>,
+
+    /// Only export books added to the library on or after this date
+    #[arg(long, value_name = "YYYY-MM-DD", value_parser = parse_date)]
+    added_since: Option<String>,
+
+    /// How highlighted passages are set off in Markdown output
+    #[arg(long, value_enum, default_value_t = QuoteStyle::Blockquote)]
+    quote_style: QuoteStyle,
+
+    /// Order of highlights within each chapter
+    #[arg(long, value_enum, default_value_t = SortOrder::Position)]
+    sort: SortOrder,
+
+    /// Put the most recent highlights first, and the chapters of Markdown
+    /// output in reverse order; implies --sort date
+    #[arg(long)]
+    newest_first: bool,
+
+    /// Only re-export books with highlights created since the last
+    /// incremental run (tracked in a state file in the output directory)
+    #[arg(long, conflicts_with = "single_file")]
+    incremental: bool,
+
+    /// Print a numbered list of the books with their highlight counts and
+    /// exit without exporting
+    #[arg(long)]
+    list: bool,
+
+    /// Print the total number of highlights of the selected books and exit
+    /// without exporting
+    #[arg(long, conflicts_with = "list")]
+    count_only: bool,
+
+    /// Copy the database to a temporary file and export from the copy, for
+    /// when the device is busy syncing
+    #[arg(long)]
+    copy_first: bool,
+
+    /// Always open the database in immutable mode, ignoring highlights still
+    /// in its write-ahead log (-wal file)
+    #[arg(long)]
+    immutable: bool,
+
+    /// Report per book how many highlights matched a chapter, and why the
+    /// others ended up uncategorized
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Only print errors. Dry-run and --stats reports go to stdout instead
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Run all queries and generate the output, but only report what would
+    /// be written instead of touching the disk
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep a book's file if it already exists in the output directory, e.g.
+    /// because you edited it, instead of writing it again
+    #[arg(long, conflicts_with_all = ["single_file", "stdout", "zip"])]
+    skip_existing: bool,
+
+    /// Rewrite books' files that already exist (the default)
+    #[arg(long, conflicts_with = "skip_existing")]
+    overwrite: bool,
+
+    /// After the export, write a JSON summary of the run to this file: the
+    /// books exported, skipped and failed, and the number of highlights
+    #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+    report: Option<PathBuf>,
 }
 
-struct TocEntry {
-    title: String,
-    /// ContentID with the trailing "-N" suffix stripped, used for matching bookmarks.
-    match_id: String,
-    /// TOC depth level extracted from the trailing "-N" suffix (1 = top, 2 = part, 3 = section, etc.)
-    depth: u32,
+/// The `--report` summary of a run.
+#[derive(Serialize)]
+struct RunReport<'a> {
+    /// When the export finished, RFC 3339 in UTC
+    timestamp: String,
+    /// Books in the library, before any filter
+    books_found: usize,
+    books_exported: usize,
+    books_skipped: Vec<SkippedBook<'a>>,
+    books_failed: Vec<FailedBook<'a>>,
+    /// Highlights in the exported books
+    highlights: usize,
 }
 
-struct Highlight {
-    text: String,
-    annotation: Option<String>,
-    chapter_content_id: String,
-    date_created: Option<String>,
+#[derive(Serialize)]
+struct SkippedBook<'a> {
+    title: &'a str,
+    reason: &'static str,
 }
 
-fn query_books(conn: &Connection) -> SqlResult<Vec<Book>> {
-    let mut stmt = conn.prepare(
-        "SELECT ContentID, Title, Attribution
-         FROM content
-         WHERE BookID IS NULL AND ContentType = 6
-         ORDER BY Title",
-    )?;
+#[derive(Serialize)]
+struct FailedBook<'a> {
+    title: &'a str,
+    error: String,
+}
 
-    let books = stmt
-        .query_map([], |row| {
-            Ok(Book {
-                content_id: row.get(0)?,
-                title: row.get(1)?,
-                author: row.get(2)?,
-            })
-        })?
-        .collect::<SqlResult<Vec<_>>>()?;
+/// Validate a `--date-format` pattern up front: chrono panics when formatting
+/// with an invalid specifier, or with an offset or time zone (`%z`, `%Z`),
+/// which Kobo's dates don't have.
+fn parse_date_format(s: &str) -> Result<String, String> {
+    use chrono::format::{Item, StrftimeItems};
+    use std::fmt::Write;
+    if StrftimeItems::new(s).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid strftime pattern '{s}'"));
+    }
+    let sample = chrono::NaiveDate::from_ymd_opt(2024, 1, 15)
+        .and_then(|d| d.and_hms_opt(10, 30, 0))
+        .expect("valid date");
+    if write!(String::new(), "{}", sample.format(s)).is_err() {
+        return Err(format!(
+            "strftime pattern '{s}' needs a time zone, Kobo dates have none"
+        ));
+    }
+    Ok(s.to_string())
+}
 
-    Ok(books)
+fn parse_tag(s: &str) -> Result<String, String> {
+    sanitize_tag(s).ok_or_else(|| {
+        format!("invalid tag '{s}', tags need a letter, `_`, `-` or `/` besides digits")
+    })
 }
 
-/// Strip the trailing "-N" (digits) suffix from a ContentID.
-/// E.g. "...xhtml#chapter01_4-2" → "...xhtml#chapter01_4"
-///       "...Cover.xhtml-1"      → "...Cover.xhtml"
-fn strip_suffix(content_id: &str) -> String {
-    if let Some(pos) = content_id.rfind('-') {
-        let after = &content_id[pos + 1..];
-        if !after.is_empty() && after.chars().all(|c| c.is_ascii_digit()) {
-            return content_id[..pos].to_string();
-        }
+fn parse_color(s: &str) -> Result<i64, String> {
+    color_code(s).ok_or_else(|| {
+        format!("unknown color '{s}', expected yellow, red, blue, green or a color code")
+    })
+}
+
+/// Location of the database relative to the device's mount point.
+const DEVICE_DB_PATH: &str = ".kobo/KoboReader.sqlite";
+
+/// Where removable drives get mounted on Linux, macOS and Windows.
+fn default_mount_roots() -> Vec<PathBuf> {
+    if cfg!(windows) {
+        ('D'..='Z')
+            .map(|drive| PathBuf::from(format!("{drive}:\\")))
+            .collect()
+    } else {
+        ["/media", "/run/media", "/Volumes"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
     }
-    content_id.to_string()
 }
 
-/// Extract the depth level from the trailing "-N" suffix of a ContentID.
-/// E.g. "...xhtml#chapter01_4-2" → 2, "...Cover.xhtml-1" → 1
-/// Returns 1 if no suffix is found (treat as top-level).
-fn extract_depth(content_id: &str) -> u32 {
-    if let Some(pos) = content_id.rfind('-') {
-        let after = &content_id[pos + 1..];
-        if !after.is_empty() && after.chars().all(|c| c.is_ascii_digit()) {
-            return after.parse().unwrap_or(1);
+/// Find mount points containing a Kobo database. Each root is searched itself
+/// and up to two levels below it, which covers `/Volumes/<drive>`,
+/// `/media/<user>/<drive>` and `/run/media/<user>/<drive>`.
+fn find_kobo_devices(roots: &[PathBuf]) -> Vec<PathBuf> {
+    fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+
+    let mut devices = Vec::new();
+    for root in roots {
+        let mut candidates = vec![root.clone()];
+        for child in subdirectories(root) {
+            candidates.extend(subdirectories(&child));
+            candidates.push(child);
+        }
+        for candidate in candidates {
+            if candidate.join(DEVICE_DB_PATH).is_file() && !devices.contains(&candidate) {
+                devices.push(candidate);
+            }
         }
     }
-    1
+    devices.sort();
+    devices
 }
 
-/// Fetch only ContentType=899 entries (the real TOC) ordered by VolumeIndex.
-/// The trailing "-N" suffix on the ContentID encodes the TOC depth level.
-fn query_toc(conn: &Connection, book_content_id: &str) -> SqlResult<Vec<TocEntry>> {
-    let mut stmt = conn.prepare(
-        "SELECT ContentID, Title
-         FROM content
-         WHERE BookID = ?1
-           AND ContentType = 899
-         ORDER BY VolumeIndex",
-    )?;
+/// Name of the file in the output directory that records the newest
+/// `DateCreated` seen by the last `--incremental` run.
+const STATE_FILE: &str = ".kobo-export-state";
+
+/// Validate a `YYYY-MM-DD` command line date.
+fn parse_date(s: &str) -> Result<String, String> {
+    let parts: Vec<&str> = s.split('-').collect();
+    let valid = match parts.as_slice() {
+        [year, month, day] => {
+            year.len() == 4
+                && month.len() == 2
+                && day.len() == 2
+                && parts.iter().all(|p| p.chars().all(|c| c.is_ascii_digit()))
+                && (1..=12).contains(&month.parse::<u32>().unwrap_or(0))
+                && (1..=31).contains(&day.parse::<u32>().unwrap_or(0))
+        }
+        _ => false,
+    };
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("invalid date '{s}', expected YYYY-MM-DD"))
+    }
+}
 
-    let entries: Vec<TocEntry> = stmt
-        .query_map([book_content_id], |row| {
-            let content_id: String = row.get(0)?;
-            let title: String = row.get(1)?;
-            Ok((content_id, title))
-        })?
-        .collect::<SqlResult<Vec<_>>>()?
-        .into_iter()
-        .map(|(content_id, title)| {
-            let match_id = strip_suffix(&content_id);
-            let depth = extract_depth(&content_id);
-            TocEntry {
-                title,
-                match_id,
-                depth,
+/// Write a generated file, into the `--zip` archive when there is one. Inside
+/// the archive the path keeps its directories, output_dir included.
+fn write_output(archive: &mut Option<Archive>, path: &Path, contents: &str) -> anyhow::Result<()> {
+    match archive {
+        Some(archive) => {
+            // Files are stamped with the time of the export, as on disk
+            let mut options = SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .unix_permissions(0o644)
+                .large_file(contents.len() as u64 >= u64::from(u32::MAX));
+            if let Ok(time) = zip::DateTime::try_from(chrono::Local::now().naive_local()) {
+                options = options.last_modified_time(time);
             }
-        })
-        .collect();
+            archive.start_file(archive_name(path), options)?;
+            archive.write_all(contents.as_bytes())?;
+        }
+        None => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, contents)?;
+        }
+    }
+    Ok(())
+}
 
-    Ok(entries)
+/// Write a book's file like `write_output`, except that with `--skip-existing`
+/// a file already at `path` is kept. Returns whether the file was written.
+fn write_book_output(
+    archive: &mut Option<Archive>,
+    path: &Path,
+    contents: &str,
+    skip_existing: bool,
+) -> anyhow::Result<bool> {
+    if skip_existing && path.exists() {
+        return Ok(false);
+    }
+    write_output(archive, path, contents)?;
+    Ok(true)
 }
 
-fn query_highlights(conn: &Connection, book_content_id: &str) -> SqlResult<Vec<Highlight>> {
-    let mut stmt = conn.prepare(
-        "SELECT Text, Annotation, ContentID, ChapterProgress, DateCreated
-         FROM Bookmark
-         WHERE VolumeID = ?1
-           AND Text IS NOT NULL
-           AND Text != ''
-         ORDER BY ContentID, ChapterProgress",
-    )?;
+/// The `--format sqlite` output file: output_dir, with a `.sqlite` extension
+/// if it has none.
+fn sqlite_path(output_dir: &Path) -> PathBuf {
+    if output_dir.extension().is_some() {
+        output_dir.to_path_buf()
+    } else {
+        output_dir.with_extension(OutputFormat::Sqlite.extension())
+    }
+}
 
-    let highlights = stmt
-        .query_map([book_content_id], |row| {
-            Ok(Highlight {
-                text: row.get(0)?,
-                annotation: row.get(1)?,
-                chapter_content_id: row.get(2)?,
-                date_created: row.get(4)?,
-            })
-        })?
-        .collect::<SqlResult<Vec<_>>>()?;
+/// Create the `--format sqlite` output database, replacing an earlier export
+/// at that path. Refuses to replace the Kobo database it reads from.
+fn create_export_database(path: &Path, db_path: &Path) -> anyhow::Result<Connection> {
+    if path.is_dir() {
+        anyhow::bail!(
+            "{} is a directory, pass the database file to write with --output-dir",
+            path.display()
+        );
+    }
+    if let (Ok(output), Ok(input)) = (path.canonicalize(), db_path.canonicalize()) {
+        if output == input {
+            anyhow::bail!(
+                "{} is the Kobo database being exported, pass another file with --output-dir",
+                path.display()
+            );
+        }
+    }
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let conn = Connection::open(path)?;
+    create_sqlite_export(&conn)?;
+    Ok(conn)
+}
 
-    Ok(highlights)
+/// The name of a file inside a zip archive: its path with `/` separators and
+/// without a leading `/`, `.` or `..`.
+fn archive_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
-fn sanitize_filename(name: &str) -> String {
-    name.chars()
-        .filter(|c| c.is_alphanumeric() || *c == ' ' || *c == '-')
-        .collect::<String>()
-        .trim()
-        .to_string()
+/// A file size for the summary, e.g. "812 bytes" or "14.2 KB".
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
 }
 
-fn format_highlight(h: &Highlight) -> String {
-    let mut out = String::new();
+fn parse_chapter_template(s: &str) -> Result<String, String> {
+    validate_chapter_template(s)?;
+    Ok(s.to_string())
+}
 
-    for line in h.text.lines() {
-        out.push_str(&format!("> {}\n", line));
-    }
+/// Whether the book passes `--modified-since` and `--added-since`.
+fn matches_date_filters(cli: &Cli, book: &Book) -> bool {
+    cli.modified_since
+        .as_deref()
+        .is_none_or(|since| modified_since(book, since))
+        && cli
+            .added_since
+            .as_deref()
+            .is_none_or(|since| added_since(book, since))
+}
 
-    if let Some(ref note) = h.annotation {
-        if !note.is_empty() {
-            out.push_str(&format!("\n**Note:** {}\n", note));
+/// Drop the books the date filters reject, then keep the first `--max-books`
+/// of the rest. Returns the note for the summary line when books were left
+/// out by the limit.
+fn select_books(cli: &Cli, books: &mut Vec<Book>) -> String {
+    books.retain(|book| matches_date_filters(cli, book));
+    match cli.max_books {
+        Some(max_books) if books.len() > max_books => {
+            let note = format!(" (--max-books: {max_books} of {} books)", books.len());
+            books.truncate(max_books);
+            note
         }
+        _ => String::new(),
     }
+}
 
-    if let Some(ref date) = h.date_created {
-        out.push_str(&format!("\n*{date}*\n"));
+/// Render the `--list` table: number, highlight count, title and author.
+fn format_book_list(books: &[(&Book, usize)]) -> String {
+    let title_width = books
+        .iter()
+        .map(|(book, _)| book.title.chars().count())
+        .chain(["Title".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut out = format!(
+        "{:>3}  {:>10}  {:<title_width$}  Author\n",
+        "#", "Highlights", "Title"
+    );
+    for (i, (book, count)) in books.iter().enumerate() {
+        let line = format!(
+            "{:>3}  {count:>10}  {:<title_width$}  {}",
+            i + 1,
+            book.title,
+            book.author.as_deref().unwrap_or("")
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
     }
-
     out
 }
 
-/// Assign highlights to TOC entries.
-///
-/// Matching strategy: the bookmark's ContentID equals a TOC entry's match_id
-/// (which is the 899 ContentID with the trailing "-N" suffix stripped).
-///
-/// Example:
-///   Bookmark:  ...Chapter01.xhtml#chapter01_4
-///   TOC entry: ...Chapter01.xhtml#chapter01_4-2  →  match_id: ...Chapter01.xhtml#chapter01_4
-///   → MATCH
-fn assign_highlights<'a>(
-    toc: &[TocEntry],
-    highlights: &'a [Highlight],
-) -> (HashMap<usize, Vec<&'a Highlight>>, Vec<&'a Highlight>) {
-    // Map from match_id → TOC entry index
-    let mut match_index: HashMap<&str, usize> = HashMap::new();
-    for (i, entry) in toc.iter().enumerate() {
-        match_index.entry(&entry.match_id).or_insert(i);
+/// The write-ahead log SQLite keeps next to a database in WAL mode.
+fn wal_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push("-wal");
+    PathBuf::from(path)
+}
+
+/// An SQLite `file:` URI for the database at `path`, with characters that
+/// have a meaning in URIs, such as spaces, `?`, `#` and `%`, percent-encoded.
+fn database_uri(path: &Path, immutable: bool) -> String {
+    let path = path.to_string_lossy();
+    let path = if cfg!(windows) {
+        // Canonical Windows paths have the `\\?\C:\...` form
+        path.strip_prefix(r"\\?\")
+            .unwrap_or(&path)
+            .replace('\\', "/")
+    } else {
+        path.into_owned()
+    };
+    let mut uri = String::from("file:");
+    if path.starts_with('/') {
+        uri.push_str("//");
+    } else if path.as_bytes().get(1) == Some(&b':') {
+        uri.push_str("///");
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    if immutable {
+        uri.push_str("?immutable=1");
     }
+    uri
+}
+
+/// Open the database read-only, and make a first read to surface lock errors
+/// up front. `immutable=1` keeps SQLite from touching the device's files,
+/// but it also ignores the write-ahead log, where the newest highlights can
+/// still be. So the database is only opened immutable when `immutable` is
+/// set or there is no `-wal` file; otherwise it is opened in plain read-only
+/// mode, which reads the log but takes locks and may create a `-shm` file.
+fn open_database(path: &Path, immutable: bool) -> rusqlite::Result<Connection> {
+    // Through a symlink, the -wal file is next to the file it points to
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let uri = database_uri(&path, immutable || !wal_path(&path).exists());
+    let conn = Connection::open_with_flags(
+        &uri,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_URI,
+    )?;
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))?;
+    Ok(conn)
+}
 
-    let mut assigned: HashMap<usize, Vec<&'a Highlight>> = HashMap::new();
-    let mut uncategorized: Vec<&'a Highlight> = Vec::new();
+/// Exit code for a database file that isn't a KoboReader database.
+const EXIT_NOT_KOBO_DATABASE: i32 = 2;
 
-    for h in highlights {
-        if let Some(&idx) = match_index.get(h.chapter_content_id.as_str()) {
-            assigned.entry(idx).or_default().push(h);
-        } else {
-            uncategorized.push(h);
+/// Exit code for a run where some books failed to export but the rest were.
+const EXIT_PARTIAL_FAILURE: i32 = 3;
+
+fn exit_not_kobo_database(db_path: &Path, reason: &str) -> ! {
+    eprintln!(
+        "Error: {} doesn't look like a KoboReader database ({reason})",
+        db_path.display()
+    );
+    std::process::exit(EXIT_NOT_KOBO_DATABASE);
+}
+
+/// Whether opening failed because the device is busy writing the database.
+fn is_locked(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+/// File name ending of the `--separate-notes` files.
+const NOTES_SUFFIX: &str = "notes.md";
+
+/// Copy of the database in the temp directory, along with its write-ahead
+/// log if it has one, deleted when dropped.
+struct TempCopy(PathBuf);
+
+impl TempCopy {
+    fn new(db_path: &Path) -> std::io::Result<Self> {
+        // Copies made by the same process (e.g. in tests) still get their own file
+        static COPIES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "kobo-highlights-exporter-{}-{}.sqlite",
+            std::process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::copy(db_path, &path)?;
+        if wal_path(db_path).exists() {
+            fs::copy(wal_path(db_path), wal_path(&path))?;
         }
+        Ok(TempCopy(path))
     }
+}
 
-    (assigned, uncategorized)
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+        let _ = fs::remove_file(wal_path(&self.0));
+    }
 }
 
-fn generate_markdown(book: &Book, toc: &[TocEntry], highlights: &[Highlight]) -> String {
-    let mut md = String::new();
+/// `--verbose` report of how a book's highlights matched its TOC, listing
+/// the ContentID of each uncategorized highlight and the closest TOC entry.
+fn print_match_diagnostics(
+    title: &str,
+    toc: &[TocEntry],
+    highlights: &[Highlight],
+    prefer_deepest: bool,
+) {
+    let (assigned, uncategorized) = assign_highlights(toc, highlights, prefer_deepest);
+    let matched: usize = assigned.values().map(Vec::len).sum();
+    eprintln!(
+        "  {title}: {matched} matched, {} uncategorized ({} TOC entries)",
+        uncategorized.len(),
+        toc.len()
+    );
+    for h in uncategorized {
+        match nearest_match_id(toc, &h.chapter_content_id) {
+            Some(nearest) => eprintln!(
+                "    no match for {} (nearest TOC entry: {nearest})",
+                h.chapter_content_id
+            ),
+            None => eprintln!("    no match for {}", h.chapter_content_id),
+        }
+    }
+}
+
+/// File name of the `--index` page in the output directory.
+const INDEX_FILE: &str = "index.md";
+
+/// Read the timestamp stored by the previous incremental run, if any.
+fn read_state(output_dir: &Path) -> std::io::Result<Option<String>> {
+    match fs::read_to_string(output_dir.join(STATE_FILE)) {
+        Ok(contents) => Ok(Some(contents.trim().to_string()).filter(|s| !s.is_empty())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-    // Header
-    md.push_str(&format!("# {}\n\n", book.title));
-    if let Some(ref author) = book.author {
-        if !author.is_empty() {
-            md.push_str(&format!("**Author:** {author}\n\n"));
+fn write_state(output_dir: &Path, last_date: &str) -> std::io::Result<()> {
+    fs::write(output_dir.join(STATE_FILE), format!("{last_date}\n"))
+}
+
+/// Parse the command line, taking options it doesn't set from the config
+/// file: the `--config` one, or `kobo-export.toml` in the working directory.
+fn parse_cli() -> Cli {
+    let mut args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(config::CONFIG_FILE)).filter(|path| path.exists()),
+    };
+
+    if let Some(path) = path {
+        let extra_args = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| config::parse(&text))
+            .and_then(|entries| config::to_args(&Cli::command(), &matches, &entries));
+        match extra_args {
+            Ok(extra_args) => args.extend(extra_args),
+            Err(e) => {
+                eprintln!("Error: {}: {e}", path.display());
+                std::process::exit(1);
+            }
         }
     }
-    md.push_str("---\n\n");
 
-    let (assigned, uncategorized) = assign_highlights(toc, highlights);
+    let matches = Cli::command().get_matches_from(args);
+    Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
 
-    // Determine which ancestor headings need to be emitted.
-    // For each TOC entry with highlights, mark all ancestor entries (entries at
-    // shallower depth that precede it) as needed.
-    let mut heading_needed: std::collections::HashSet<usize> = std::collections::HashSet::new();
-    for (i, _entry) in toc.iter().enumerate() {
-        if assigned.contains_key(&i) {
-            heading_needed.insert(i);
-            // Walk backwards to find and mark all ancestor headings
-            let current_depth = toc[i].depth;
-            let mut need_depth = current_depth;
-            for j in (0..i).rev() {
-                if toc[j].depth < need_depth {
-                    heading_needed.insert(j);
-                    need_depth = toc[j].depth;
-                    if need_depth <= 1 {
-                        break;
-                    }
+fn main() -> anyhow::Result<()> {
+    let cli = parse_cli();
+
+    let db_path = if let Some(ref device) = cli.device {
+        device.join(DEVICE_DB_PATH)
+    } else if cli.auto {
+        let mut devices = find_kobo_devices(&default_mount_roots());
+        match (devices.len(), &cli.db_path) {
+            (0, Some(path)) => path.clone(),
+            (0, None) => {
+                eprintln!("Error: no Kobo device found, pass the database path or --device");
+                std::process::exit(1);
+            }
+            (1, _) => {
+                let device = devices.remove(0);
+                status!(cli, "Using Kobo device at {}", device.display());
+                device.join(DEVICE_DB_PATH)
+            }
+            _ => {
+                eprintln!("Error: found several Kobo devices, choose one with --device:");
+                for device in &devices {
+                    eprintln!("  {}", device.display());
                 }
+                std::process::exit(1);
             }
         }
+    } else if let Some(ref path) = cli.db_path {
+        path.clone()
+    } else {
+        eprintln!(
+            "Error: no database given. Pass the path to KoboReader.sqlite, set KOBO_DB, \
+             or use --device/--auto"
+        );
+        std::process::exit(1);
+    };
+
+    if !db_path.exists() {
+        eprintln!("Error: database file not found: {}", db_path.display());
+        std::process::exit(1);
+    }
+
+    if cli.template.is_some() && cli.format != OutputFormat::Markdown {
+        eprintln!("Error: --template is only supported with --format markdown");
+        std::process::exit(1);
+    }
+
+    if cli.chapter_template.is_some() && cli.format != OutputFormat::Markdown {
+        eprintln!("Error: --chapter-template is only supported with --format markdown");
+        std::process::exit(1);
     }
 
-    // Walk TOC in VolumeIndex order
-    for (i, entry) in toc.iter().enumerate() {
-        if !heading_needed.contains(&i) || entry.title.is_empty() {
-            continue;
+    if cli.group_by == GroupBy::Date {
+        if cli.format != OutputFormat::Markdown {
+            eprintln!("Error: --group-by date is only supported with --format markdown");
+            std::process::exit(1);
+        }
+        if cli.group_by_color {
+            eprintln!("Error: --group-by date can't be combined with --group-by-color");
+            std::process::exit(1);
         }
+    }
+
+    if cli.separate_notes && cli.format != OutputFormat::Markdown {
+        eprintln!("Error: --separate-notes is only supported with --format markdown");
+        std::process::exit(1);
+    }
+
+    if cli.include_bookmarks && cli.format != OutputFormat::Markdown {
+        eprintln!("Error: --include-bookmarks is only supported with --format markdown");
+        std::process::exit(1);
+    }
+
+    let to_stdout = cli.stdout || cli.output_dir == Path::new("-") || cli.preview.is_some();
+    if to_stdout
+        && (cli.single_file.is_some()
+            || cli.index
+            || cli.by_author
+            || cli.combine_by_author
+            || cli.incremental
+            || cli.separate_notes
+            || cli.zip.is_some())
+    {
+        eprintln!(
+            "Error: --single-file, --index, --by-author, --combine-by-author, --incremental, \
+             --separate-notes and --zip can't be used when writing to stdout"
+        );
+        std::process::exit(1);
+    }
+
+    if cli.combine_by_author && cli.format != OutputFormat::Markdown {
+        eprintln!("Error: --combine-by-author is only supported with --format markdown");
+        std::process::exit(1);
+    }
+
+    if cli.single_file.is_some() && !cli.format.supports_single_file() {
+        eprintln!(
+            "Error: --single-file is only supported with --format markdown, csv, jsonl or anki"
+        );
+        std::process::exit(1);
+    }
 
-        // depth 1 → ## (2 hashes), depth 2 → ### (3 hashes), etc.
-        // # is reserved for the book title, so heading level = depth + 1
-        let hashes = "#".repeat((entry.depth + 1) as usize);
-        md.push_str(&format!("{hashes} {}\n\n", entry.title));
+    if cli.format == OutputFormat::Sqlite
+        && (to_stdout || cli.index || cli.by_author || cli.incremental || cli.zip.is_some())
+    {
+        eprintln!(
+            "Error: --format sqlite writes a single database file and can't be combined with \
+             --stdout, --preview, --index, --by-author, --incremental or --zip"
+        );
+        std::process::exit(1);
+    }
 
-        if let Some(hl) = assigned.get(&i) {
-            for h in hl {
-                md.push_str(&format_highlight(h));
-                md.push('\n');
+    let filter = HighlightFilter {
+        since: cli.since.clone(),
+        until: cli.until.clone(),
+        sort: if cli.newest_first {
+            SortOrder::Date
+        } else {
+            cli.sort
+        },
+        include_notes_only: cli.include_notes_only,
+        highlights_only: cli.highlights_only,
+        annotated_only: cli.annotated_only,
+        color: cli.only_color,
+    };
+    let template = match cli.template {
+        Some(ref path) => match fs::read_to_string(path) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                eprintln!("Error: cannot read template {}: {e}", path.display());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let opts = RenderOptions {
+        color: !cli.no_color,
+        progress: !cli.no_progress,
+        frontmatter: cli
+            .frontmatter
+            .then(|| chrono::Local::now().format("%Y-%m-%d").to_string()),
+        tags: cli.tag.clone(),
+        group_by_color: cli.group_by_color,
+        group_by: cli.group_by,
+        heading_offset: cli.heading_offset,
+        stats: cli.stats,
+        date_format: cli.date_format.clone(),
+        relative_to: cli.relative_dates.then(|| chrono::Utc::now().naive_utc()),
+        clean: !cli.no_clean,
+        trim: !cli.no_trim,
+        flatten: cli.flatten,
+        escape_markdown: cli.escape_markdown,
+        template,
+        chapter_template: cli.chapter_template.clone(),
+        uncategorized_label: cli.uncategorized_label.clone(),
+        bibtex: cli.bibtex,
+        prefer_deepest: cli.prefer_deepest,
+        quote_style: cli.quote_style,
+        chapter_counts: cli.chapter_counts,
+        toc: cli.toc,
+        locations: cli.locations,
+        newest_first: cli.newest_first,
+        notes_as_tasks: cli.notes_as_tasks,
+        decode_entities: cli.decode_entities,
+        strip_tags: cli.strip_tags,
+        punctuation: if cli.smart_quotes {
+            Some(Punctuation::Smart)
+        } else if cli.straight_quotes || cli.normalize_punctuation {
+            Some(Punctuation::Straight)
+        } else {
+            None
+        },
+        flatten_uncategorized: cli.flatten_uncategorized_into_chapters,
+        // Turned on per book from its language
+        rtl: false,
+    };
+
+    // Keeps the --copy-first copy alive until the export is done
+    let mut temp_copy = None;
+    let conn = if cli.copy_first {
+        None
+    } else {
+        match open_database(&db_path, cli.immutable) {
+            Ok(conn) => Some(conn),
+            Err(e) if is_locked(&e) => {
+                status!(
+                    cli,
+                    "Database is locked ({e}), exporting from a temporary copy instead"
+                );
+                None
+            }
+            Err(e) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::NotADatabase) => {
+                exit_not_kobo_database(&db_path, "not an SQLite file")
             }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    let conn = match conn {
+        Some(conn) => conn,
+        None => {
+            let copy = TempCopy::new(&db_path)?;
+            status!(cli, "Copied database to {}", copy.0.display());
+            let conn = open_database(&copy.0, cli.immutable)?;
+            temp_copy = Some(copy);
+            conn
         }
+    };
+
+    let missing = missing_tables(&conn)?;
+    if !missing.is_empty() {
+        drop(conn);
+        drop(temp_copy);
+        exit_not_kobo_database(&db_path, &format!("no {} table", missing.join(" or ")));
     }
 
-    if !uncategorized.is_empty() {
-        md.push_str("## Uncategorized\n\n");
-        for h in &uncategorized {
-            md.push_str(&format_highlight(h));
-            md.push('\n');
+    if cli.verbose {
+        match schema_version(&conn)? {
+            Some(version) => eprintln!("Database schema version {version}"),
+            None => eprintln!("Database schema version unknown"),
+        }
+        let columns: Vec<String> = available_columns(&conn)?
+            .iter()
+            .map(|(table, column)| format!("{table}.{column}"))
+            .collect();
+        if columns.is_empty() {
+            eprintln!("  optional columns: none");
+        } else {
+            eprintln!("  optional columns: {}", columns.join(", "));
         }
     }
 
-    md
-}
+    let mut books = query_books_of_types(&conn, &cli.book_content_type)?;
+    // Everything in the library, to tell --report which books the filters left out
+    let library: Vec<(String, String)> = books
+        .iter()
+        .map(|book| (book.content_id.clone(), book.title.clone()))
+        .collect();
+    status!(cli, "Found {} books in database", books.len());
+
+    if let Some(ref needle) = cli.book {
+        books.retain(|book| contains_ignore_case(&book.title, needle));
+        if books.is_empty() {
+            eprintln!("Error: no book title contains \"{needle}\"");
+            drop(conn);
+            drop(temp_copy);
+            std::process::exit(1);
+        }
+        status!(cli, "{} books match \"{needle}\"", books.len());
+    }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    if let Some(ref needle) = cli.author {
+        books.retain(|book| {
+            book.author
+                .as_deref()
+                .is_some_and(|author| contains_ignore_case(author, needle))
+        });
+        if books.is_empty() {
+            eprintln!("Error: no book author contains \"{needle}\"");
+            drop(conn);
+            drop(temp_copy);
+            std::process::exit(1);
+        }
+        status!(cli, "{} books by \"{needle}\"", books.len());
+    }
 
-    if !cli.db_path.exists() {
-        eprintln!("Error: database file not found: {}", cli.db_path.display());
-        std::process::exit(1);
+    if !cli.exclude_book.is_empty() {
+        let before = books.len();
+        books.retain(|book| {
+            !cli.exclude_book
+                .iter()
+                .any(|needle| contains_ignore_case(&book.title, needle))
+        });
+        status!(cli, "Excluded {} books", before - books.len());
     }
 
-    let uri = format!("file:{}?immutable=1", cli.db_path.display());
-    let conn = Connection::open_with_flags(
-        &uri,
-        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_URI,
-    )?;
+    if let Some(ref needle) = cli.preview {
+        let matches: Vec<&Book> = books
+            .iter()
+            .filter(|book| contains_ignore_case(&book.title, needle))
+            .collect();
+        let Some(first) = matches.first() else {
+            eprintln!("Error: no book title contains \"{needle}\"");
+            drop(conn);
+            drop(temp_copy);
+            std::process::exit(1);
+        };
+        if matches.len() > 1 {
+            status!(
+                cli,
+                "  Warning: {} books match \"{needle}\", previewing only \"{}\"",
+                matches.len(),
+                first.title
+            );
+        }
+        let content_id = first.content_id.clone();
+        books.retain(|book| book.content_id == content_id);
+    }
+
+    // Appended to the summary line when --max-books left books out
+    let limit_note = select_books(&cli, &mut books);
 
-    let books = query_books(&conn)?;
-    eprintln!("Found {} books in database", books.len());
+    if cli.count_only {
+        let ids: Vec<&str> = books.iter().map(|book| book.content_id.as_str()).collect();
+        let total = count_library_highlights(&conn, &ids, &filter, cli.min_highlights)?;
+        println!("{total}");
+        return Ok(());
+    }
 
-    fs::create_dir_all(&cli.output_dir)?;
+    if cli.list {
+        let mut rows = Vec::with_capacity(books.len());
+        for book in &books {
+            rows.push((book, count_highlights(&conn, &book.content_id, &filter)?));
+        }
+        print!("{}", format_book_list(&rows));
+        return Ok(());
+    }
+
+    // Books are collected and written at the end instead of one file each
+    let combined = cli.single_file.is_some() || to_stdout || cli.format == OutputFormat::Sqlite;
+    if !combined && !cli.dry_run && cli.zip.is_none() {
+        fs::create_dir_all(&cli.output_dir)?;
+    }
+    let sqlite_path = sqlite_path(&cli.output_dir);
+    let export_db = if cli.format == OutputFormat::Sqlite && !cli.dry_run {
+        Some(create_export_database(&sqlite_path, &db_path)?)
+    } else {
+        None
+    };
+    let mut archive = cli
+        .zip
+        .as_ref()
+        .map(|_| ZipWriter::new(Cursor::new(Vec::new())));
+
+    let last_run = if cli.incremental {
+        read_state(&cli.output_dir)?
+    } else {
+        None
+    };
+    if let Some(ref last_run) = last_run {
+        status!(
+            cli,
+            "Incremental export of highlights created after {last_run}"
+        );
+    }
 
     let mut exported = 0;
+    let mut skipped = 0;
+    let mut documents = Vec::new();
+    // With --combine-by-author, each author's books by file stem
+    let mut author_documents: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut used_stems: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    if cli.index {
+        // Keep books from overwriting the index
+        let stem = INDEX_FILE.trim_end_matches(".md").to_string();
+        used_stems
+            .entry(cli.output_dir.clone())
+            .or_default()
+            .insert(stem);
+    }
+    let mut index_entries = Vec::new();
+    let mut total_stats = HighlightStats::default();
+    let mut newest_date: Option<String> = None;
+    let mut failed = 0;
+    // Books whose existing file --skip-existing kept
+    let mut kept = 0;
+    let mut skipped_books: Vec<SkippedBook> = library
+        .iter()
+        .filter(|(id, _)| !books.iter().any(|book| &book.content_id == id))
+        .map(|(_, title)| SkippedBook {
+            title,
+            reason: "filtered out",
+        })
+        .collect();
+    let mut failed_books = Vec::new();
     for book in &books {
-        let highlights = query_highlights(&conn, &book.content_id)?;
-        if highlights.is_empty() {
-            continue;
+        let mut skip = |reason| {
+            skipped_books.push(SkippedBook {
+                title: &book.title,
+                reason,
+            })
+        };
+        // A book that fails is reported and left out, the others still export
+        let result = (|| -> anyhow::Result<()> {
+            let mut highlights = query_highlights(&conn, &book.content_id, &filter)?;
+            if highlights.is_empty() && cli.analytics_fallback {
+                highlights = query_analytics_highlights(&conn, &book.content_id, &filter)?;
+                if !highlights.is_empty() {
+                    status!(
+                        cli,
+                        "  Recovered {} highlights of \"{}\" from AnalyticsEvents",
+                        highlights.len(),
+                        book.title
+                    );
+                }
+            }
+            if cli.strip_annotations {
+                highlights = without_annotations(highlights);
+            }
+            if !cli.no_dedup {
+                highlights = dedup_highlights(highlights);
+            }
+            if cli.merge_adjacent {
+                highlights = merge_adjacent_highlights(highlights);
+            }
+            if cli.newest_first {
+                highlights.reverse();
+            }
+            if highlights.is_empty() {
+                skip("no highlights");
+                return Ok(());
+            }
+            if highlights.len() < cli.min_highlights {
+                skip("below min-highlights");
+                skipped += 1;
+                return Ok(());
+            }
+
+            for date in highlights.iter().filter_map(|h| h.date_created.as_deref()) {
+                if newest_date.as_deref().is_none_or(|newest| date > newest) {
+                    newest_date = Some(date.to_string());
+                }
+            }
+
+            if let Some(ref last_run) = last_run {
+                if !has_new_highlights(&highlights, last_run) {
+                    skip("no new highlights");
+                    return Ok(());
+                }
+            }
+            // After the incremental bookkeeping, which needs the dates
+            if cli.no_dates {
+                highlights = without_dates(highlights);
+            }
+            let undated;
+            let book = if cli.no_dates {
+                undated = book_without_dates(book);
+                &undated
+            } else {
+                book
+            };
+
+            let mut toc = query_toc(&conn, &book.content_id)?;
+            if cli.normalize_depth {
+                toc = normalize_depths(toc);
+            }
+            if cli.verbose {
+                print_match_diagnostics(&book.title, &toc, &highlights, cli.prefer_deepest);
+                if matches!(
+                    cli.format,
+                    OutputFormat::Json
+                        | OutputFormat::Csv
+                        | OutputFormat::Jsonl
+                        | OutputFormat::Sqlite
+                ) {
+                    for date in highlights.iter().filter_map(|h| h.date_created.as_deref()) {
+                        if parse_kobo_date(date).is_none() {
+                            eprintln!("    cannot parse date \"{date}\", exporting it unchanged");
+                        }
+                    }
+                }
+            }
+            // With --separate-notes the book's file only gets the passages
+            let quotes;
+            let mut notes_output = None;
+            let passages = if cli.separate_notes {
+                let annotated;
+                (quotes, annotated) = split_annotations(&highlights);
+                if !annotated.is_empty() {
+                    notes_output = Some(generate_markdown(book, &toc, &annotated, &opts));
+                }
+                &quotes
+            } else {
+                &highlights
+            };
+            let output = match cli.format {
+                OutputFormat::Markdown if cli.include_bookmarks => {
+                    let bookmarks = query_bookmarks(&conn, &book.content_id)?;
+                    generate_markdown(book, &toc, passages, &opts)
+                        + &markdown_bookmarks(&toc, &bookmarks, &opts)
+                }
+                OutputFormat::Markdown => generate_markdown(book, &toc, passages, &opts),
+                OutputFormat::Json => generate_json(book, &toc, &highlights, cli.prefer_deepest)?,
+                OutputFormat::Csv => {
+                    let header = !combined || documents.is_empty();
+                    generate_csv(book, &toc, &highlights, header, cli.prefer_deepest)?
+                }
+                OutputFormat::Org => generate_org(book, &toc, &highlights, &opts),
+                OutputFormat::Html => generate_html(book, &toc, &highlights, &opts),
+                OutputFormat::Notion => generate_notion(book, &toc, &highlights, &opts),
+                OutputFormat::Jsonl => generate_jsonl(book, &toc, &highlights, cli.prefer_deepest)?,
+                OutputFormat::Anki => generate_anki(book, &toc, &highlights, cli.prefer_deepest),
+                // Written to the database right away, nothing to collect
+                OutputFormat::Sqlite => {
+                    if let Some(ref db) = export_db {
+                        insert_sqlite_book(db, book, &toc, &highlights, cli.prefer_deepest)?;
+                    }
+                    String::new()
+                }
+            };
+
+            if combined {
+                documents.push(output);
+                if cli.dry_run {
+                    report!(
+                        cli,
+                        "  Would export: {} ({} highlights)",
+                        book.title,
+                        highlights.len()
+                    );
+                }
+            } else if cli.combine_by_author {
+                let stem = author_file_stem(book.author.as_deref());
+                if cli.dry_run {
+                    report!(
+                        cli,
+                        "  Would add: {} to {stem}.md ({} highlights)",
+                        book.title,
+                        highlights.len()
+                    );
+                }
+                author_documents.entry(stem).or_default().push(output);
+            } else {
+                let dir = book_dir(&cli.output_dir, book.author.as_deref(), cli.by_author);
+                let stem = sanitize_filename(&book.title);
+                let used = used_stems.entry(dir.clone()).or_default();
+                let unique_stem = unique_file_stem(&stem, book.author.as_deref(), used);
+                if unique_stem != stem {
+                    status!(
+                        cli,
+                        "  Warning: \"{stem}\" is already used by another book, writing \"{unique_stem}\" instead"
+                    );
+                }
+                let filename = format!("{unique_stem}.{}", cli.format.extension());
+                let path = dir.join(&filename);
+                let mut keep = false;
+                if cli.dry_run {
+                    if cli.skip_existing && path.exists() {
+                        report!(cli, "  Would skip: {} (already exists)", path.display());
+                        keep = true;
+                    } else {
+                        report!(
+                            cli,
+                            "  Would write: {} ({} highlights)",
+                            path.display(),
+                            highlights.len()
+                        );
+                    }
+                } else if !write_book_output(&mut archive, &path, &output, cli.skip_existing)? {
+                    status!(cli, "  Skipped: {} already exists", path.display());
+                    keep = true;
+                }
+                if let Some(ref notes) = notes_output {
+                    let notes_path = dir.join(format!("{unique_stem}.{NOTES_SUFFIX}"));
+                    if cli.dry_run {
+                        if !(cli.skip_existing && notes_path.exists()) {
+                            report!(cli, "  Would write: {}", notes_path.display());
+                        }
+                    } else {
+                        write_book_output(&mut archive, &notes_path, notes, cli.skip_existing)?;
+                    }
+                }
+                let relative = path.strip_prefix(&cli.output_dir).unwrap_or(&path);
+                index_entries.push(IndexEntry {
+                    title: book.title.clone(),
+                    author: book.author.clone(),
+                    link: relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    highlights: highlights.len(),
+                });
+                if keep {
+                    skip("file exists");
+                    kept += 1;
+                    return Ok(());
+                }
+            }
+
+            if !cli.dry_run && !to_stdout {
+                status!(
+                    cli,
+                    "  Exported: {} ({} highlights)",
+                    book.title,
+                    highlights.len()
+                );
+            }
+            exported += 1;
+            total_stats.add(HighlightStats::from_highlights(&highlights));
+            Ok(())
+        })();
+        if let Err(e) = result {
+            eprintln!("  Error: {}: {e:#}", book.title);
+            failed_books.push(FailedBook {
+                title: &book.title,
+                error: format!("{e:#}"),
+            });
+            failed += 1;
+        }
+    }
+    // Closed before an early exit, which would skip removing the copy
+    drop(conn);
+    drop(temp_copy);
+
+    let destination = match cli.single_file {
+        _ if to_stdout => {
+            if !cli.dry_run {
+                print!("{}", combine_documents(cli.format, &documents));
+            }
+            Path::new("stdout")
         }
+        Some(ref path) => {
+            if !cli.dry_run {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, combine_documents(cli.format, &documents))?;
+            }
+            path
+        }
+        None if cli.format == OutputFormat::Sqlite => &sqlite_path,
+        None => cli.zip.as_ref().unwrap_or(&cli.output_dir),
+    };
+
+    for (stem, documents) in &author_documents {
+        let path = cli.output_dir.join(format!("{stem}.md"));
+        if cli.dry_run {
+            report!(cli, "  Would write: {}", path.display());
+        } else {
+            write_output(
+                &mut archive,
+                &path,
+                &combine_documents(OutputFormat::Markdown, documents),
+            )?;
+        }
+    }
 
-        let toc = query_toc(&conn, &book.content_id)?;
-        let md = generate_markdown(book, &toc, &highlights);
+    if cli.index {
+        let path = cli.output_dir.join(INDEX_FILE);
+        if cli.dry_run {
+            report!(cli, "  Would write: {}", path.display());
+        } else {
+            write_output(&mut archive, &path, &generate_index(&index_entries))?;
+        }
+    }
 
-        let filename = format!("{}.md", sanitize_filename(&book.title));
-        let path = cli.output_dir.join(&filename);
-        fs::write(&path, &md)?;
+    let mut size_note = String::new();
+    if let (Some(archive), Some(path), false) = (archive, &cli.zip, cli.dry_run) {
+        let bytes = archive.finish()?.into_inner();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, &bytes)?;
+        size_note = format!(" ({})", format_size(bytes.len() as u64));
+    }
 
-        eprintln!(
-            "  Exported: {} ({} highlights)",
-            book.title,
-            highlights.len()
+    if cli.stats {
+        report!(
+            cli,
+            "Stats: {} highlights, {} words, {} with annotations",
+            total_stats.highlights,
+            total_stats.words,
+            total_stats.annotated
         );
-        exported += 1;
     }
 
-    eprintln!(
-        "Done. Exported {} books to {}",
+    if skipped > 0 {
+        status!(
+            cli,
+            "Skipped {skipped} books with fewer than {} highlights",
+            cli.min_highlights
+        );
+    }
+
+    if kept > 0 {
+        status!(cli, "Kept {kept} existing files (--skip-existing)");
+    }
+
+    if failed > 0 {
+        eprintln!("{failed} of {} books failed to export", exported + failed);
+    }
+
+    if cli.dry_run {
+        report!(
+            cli,
+            "Dry run, nothing written. Would export {} books to {}{limit_note}",
+            exported,
+            destination.display()
+        );
+        return finish(failed);
+    }
+
+    // Saving the state after a failure would skip the failed books' highlights
+    // on the next run
+    if cli.incremental && failed == 0 {
+        if let Some(ref newest) = newest_date {
+            write_state(&cli.output_dir, newest)?;
+        }
+    }
+
+    if let Some(ref path) = cli.report {
+        let report = RunReport {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            books_found: library.len(),
+            books_exported: exported,
+            books_skipped: skipped_books,
+            books_failed: failed_books,
+            highlights: total_stats.highlights,
+        };
+        let mut json = serde_json::to_string_pretty(&report)?;
+        json.push('\n');
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, json)?;
+    }
+
+    status!(
+        cli,
+        "Done. Exported {} books to {}{size_note}{limit_note}",
         exported,
-        cli.output_dir.display()
+        destination.display()
     );
+    finish(failed)
+}
+
+/// End the run, with `EXIT_PARTIAL_FAILURE` if any book failed.
+fn finish(failed: usize) -> anyhow::Result<()> {
+    if failed > 0 {
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
     Ok(())
 }
 
@@ -321,525 +1629,545 @@ fn main() -> anyhow::Result<()> {
 mod tests {
     use super::*;
 
-    // --- strip_suffix ---
-
-    #[test]
-    fn strip_suffix_removes_trailing_digits() {
-        assert_eq!(
-            strip_suffix("book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4-2"),
-            "book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"
-        );
-    }
+    // --- Cli ---
 
     #[test]
-    fn strip_suffix_removes_single_digit() {
-        assert_eq!(
-            strip_suffix("book.epub!OPS!xhtml/Cover.xhtml-1"),
-            "book.epub!OPS!xhtml/Cover.xhtml"
-        );
+    fn cli_db_path_from_argument() {
+        let cli =
+            Cli::try_parse_from(["kobo-highlights-exporter", "/mnt/KoboReader.sqlite"]).unwrap();
+        assert_eq!(cli.db_path, Some(PathBuf::from("/mnt/KoboReader.sqlite")));
     }
 
     #[test]
-    fn strip_suffix_no_suffix_unchanged() {
-        assert_eq!(
-            strip_suffix("book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"),
-            "book.epub!OPS!xhtml/Chapter01.xhtml#chapter01_4"
-        );
-    }
+    fn cli_db_path_falls_back_to_kobo_db_env() {
+        // Only this test touches KOBO_DB
+        std::env::set_var("KOBO_DB", "/env/KoboReader.sqlite");
+        let from_env = Cli::try_parse_from(["kobo-highlights-exporter"]).map(|cli| cli.db_path);
+        let explicit =
+            Cli::try_parse_from(["kobo-highlights-exporter", "/arg.sqlite"]).map(|cli| cli.db_path);
+        std::env::remove_var("KOBO_DB");
+        let missing = Cli::try_parse_from(["kobo-highlights-exporter"]);
 
-    #[test]
-    fn strip_suffix_dash_not_followed_by_digits() {
         assert_eq!(
-            strip_suffix("some-path/file.xhtml#section-abc"),
-            "some-path/file.xhtml#section-abc"
+            from_env.unwrap(),
+            Some(PathBuf::from("/env/KoboReader.sqlite"))
         );
+        assert_eq!(explicit.unwrap(), Some(PathBuf::from("/arg.sqlite")));
+        // Without a path the database is resolved from --device/--auto in main
+        assert_eq!(missing.unwrap().db_path, None);
     }
 
     #[test]
-    fn strip_suffix_empty_string() {
-        assert_eq!(strip_suffix(""), "");
+    fn cli_exclude_book_is_repeatable() {
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--exclude-book",
+            "manual",
+            "--exclude-book",
+            "Sample",
+            "db.sqlite",
+        ])
+        .unwrap();
+        assert_eq!(cli.exclude_book, ["manual", "Sample"]);
     }
 
-    // --- extract_depth ---
-
     #[test]
-    fn extract_depth_single_digit() {
-        assert_eq!(extract_depth("book.epub!xhtml/Cover.xhtml-1"), 1);
+    fn cli_stdout_conflicts_with_file_layout_flags() {
+        let cli =
+            Cli::try_parse_from(["kobo-highlights-exporter", "--stdout", "db.sqlite"]).unwrap();
+        assert!(cli.stdout);
+        for flag in ["--index", "--by-author", "--incremental"] {
+            assert!(Cli::try_parse_from([
+                "kobo-highlights-exporter",
+                "--stdout",
+                flag,
+                "db.sqlite"
+            ])
+            .is_err());
+        }
     }
 
     #[test]
-    fn extract_depth_multi_digit() {
-        assert_eq!(extract_depth("book.epub!xhtml/Chapter01.xhtml#ch01_4-2"), 2);
+    fn cli_quiet_conflicts_with_verbose() {
+        let cli = Cli::try_parse_from(["kobo-highlights-exporter", "-q", "db.sqlite"]).unwrap();
+        assert!(cli.quiet);
+        assert!(Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--quiet",
+            "--verbose",
+            "db.sqlite"
+        ])
+        .is_err());
     }
 
     #[test]
-    fn extract_depth_deep_level() {
-        assert_eq!(extract_depth("book.epub!Text/wahl.html#sigil_toc_id_6-4"), 4);
+    fn cli_device_conflicts_with_auto() {
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--device",
+            "/Volumes/KOBOeReader",
+        ])
+        .unwrap();
+        assert_eq!(cli.device, Some(PathBuf::from("/Volumes/KOBOeReader")));
+        assert!(
+            Cli::try_parse_from(["kobo-highlights-exporter", "--device", "/mnt", "--auto"])
+                .is_err()
+        );
     }
 
     #[test]
-    fn extract_depth_no_suffix_defaults_to_1() {
-        assert_eq!(extract_depth("book.epub!xhtml/Chapter01.xhtml#ch01_4"), 1);
+    fn cli_min_highlights_defaults_to_one() {
+        let cli = Cli::try_parse_from(["kobo-highlights-exporter", "db.sqlite"]).unwrap();
+        assert_eq!(cli.min_highlights, 1);
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "db.sqlite",
+            "--min-highlights",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(cli.min_highlights, 3);
     }
 
     #[test]
-    fn extract_depth_dash_not_digits() {
-        assert_eq!(extract_depth("some-path/file.xhtml#section-abc"), 1);
+    fn cli_uncategorized_label_accepts_empty() {
+        let cli = Cli::try_parse_from(["kobo-highlights-exporter", "db.sqlite"]).unwrap();
+        assert_eq!(cli.uncategorized_label, "Uncategorized");
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "db.sqlite",
+            "--uncategorized-label",
+            "",
+        ])
+        .unwrap();
+        assert_eq!(cli.uncategorized_label, "");
     }
 
-    // --- sanitize_filename ---
-
     #[test]
-    fn sanitize_filename_keeps_alphanumeric_spaces_dashes() {
-        assert_eq!(sanitize_filename("Hello World - 2024"), "Hello World - 2024");
+    fn cli_preview_conflicts_with_file_layout_flags() {
+        for flag in ["--index", "--by-author", "--separate-notes"] {
+            let result =
+                Cli::try_parse_from(["kobo-highlights-exporter", "--preview", "Dune", flag]);
+            assert!(result.is_err(), "{flag}");
+        }
     }
 
     #[test]
-    fn sanitize_filename_removes_special_chars() {
-        assert_eq!(sanitize_filename("Book: A «Story»!"), "Book A Story");
+    fn cli_zip_conflicts_with_single_file() {
+        let result = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--zip",
+            "notes.zip",
+            "--single-file",
+            "all.md",
+        ]);
+        assert!(result.is_err());
     }
 
     #[test]
-    fn sanitize_filename_trims_whitespace() {
-        assert_eq!(sanitize_filename("  Hello  "), "Hello");
+    fn cli_combine_by_author_conflicts_with_by_author() {
+        let result = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--combine-by-author",
+            "--by-author",
+        ]);
+        assert!(result.is_err());
     }
 
-    // --- format_highlight ---
+    // --- matches_date_filters ---
 
     #[test]
-    fn format_highlight_text_only() {
-        let h = Highlight {
-            text: "Some highlighted text".into(),
-            annotation: None,
-            chapter_content_id: String::new(),
-            date_created: None,
+    fn matches_date_filters_applies_both_dates() {
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--modified-since",
+            "2024-06-01",
+            "--added-since",
+            "2024-01-01",
+        ])
+        .unwrap();
+        let book = |last_read: &str, date_added: &str| Book {
+            last_read: Some(last_read.into()),
+            date_added: Some(date_added.into()),
+            ..Default::default()
         };
-        assert_eq!(format_highlight(&h), "> Some highlighted text\n");
+        assert!(matches_date_filters(
+            &cli,
+            &book("2024-06-02", "2024-02-01")
+        ));
+        assert!(!matches_date_filters(
+            &cli,
+            &book("2024-05-31", "2024-02-01")
+        ));
+        assert!(!matches_date_filters(
+            &cli,
+            &book("2024-06-02", "2023-12-31")
+        ));
     }
 
-    #[test]
-    fn format_highlight_with_annotation() {
-        let h = Highlight {
-            text: "Highlighted".into(),
-            annotation: Some("My note".into()),
-            chapter_content_id: String::new(),
-            date_created: None,
-        };
-        let result = format_highlight(&h);
-        assert!(result.contains("> Highlighted\n"));
-        assert!(result.contains("**Note:** My note"));
-    }
+    // --- select_books ---
 
     #[test]
-    fn format_highlight_with_date() {
-        let h = Highlight {
-            text: "Text".into(),
-            annotation: None,
-            chapter_content_id: String::new(),
-            date_created: Some("2024-01-15T10:30:00".into()),
+    fn select_books_limits_after_date_filters() {
+        let cli = Cli::try_parse_from([
+            "kobo-highlights-exporter",
+            "--modified-since",
+            "2024-06-01",
+            "--max-books",
+            "1",
+        ])
+        .unwrap();
+        let book = |title: &str, last_read: &str| Book {
+            title: title.into(),
+            last_read: Some(last_read.into()),
+            ..Default::default()
         };
-        let result = format_highlight(&h);
-        assert!(result.contains("*2024-01-15T10:30:00*"));
+        let mut books = vec![
+            book("Old", "2024-01-01"),
+            book("Recent", "2024-07-01"),
+            book("Newest", "2024-08-01"),
+        ];
+        let note = select_books(&cli, &mut books);
+        let titles: Vec<&str> = books.iter().map(|b| b.title.as_str()).collect();
+        assert_eq!(titles, ["Recent"]);
+        assert_eq!(note, " (--max-books: 1 of 2 books)");
     }
 
-    #[test]
-    fn format_highlight_multiline_text() {
-        let h = Highlight {
-            text: "Line one\nLine two".into(),
-            annotation: None,
-            chapter_content_id: String::new(),
-            date_created: None,
-        };
-        assert_eq!(format_highlight(&h), "> Line one\n> Line two\n");
-    }
+    // --- format_book_list ---
 
     #[test]
-    fn format_highlight_empty_annotation_skipped() {
-        let h = Highlight {
-            text: "Text".into(),
-            annotation: Some(String::new()),
-            chapter_content_id: String::new(),
-            date_created: None,
+    fn format_book_list_aligns_columns() {
+        let orchard = Book {
+            title: "The Paper Orchard".into(),
+            author: Some("Samir Hale".into()),
+            ..Default::default()
+        };
+        let untitled = Book {
+            title: "Notes".into(),
+            ..Default::default()
         };
-        assert!(!format_highlight(&h).contains("**Note:**"));
+        assert_eq!(
+            format_book_list(&[(&orchard, 12), (&untitled, 0)]),
+            "  #  Highlights  Title              Author\n\
+             \x20 1          12  The Paper Orchard  Samir Hale\n\
+             \x20 2           0  Notes\n"
+        );
     }
 
-    // --- assign_highlights ---
+    // --- find_kobo_devices ---
 
-    fn make_toc(entries: &[(&str, &str, u32)]) -> Vec<TocEntry> {
-        entries
-            .iter()
-            .map(|(title, match_id, depth)| TocEntry {
-                title: title.to_string(),
-                match_id: match_id.to_string(),
-                depth: *depth,
-            })
-            .collect()
-    }
-
-    fn make_highlight(text: &str, content_id: &str) -> Highlight {
-        Highlight {
-            text: text.into(),
-            annotation: None,
-            chapter_content_id: content_id.into(),
-            date_created: None,
-        }
+    fn make_device(path: &Path) {
+        fs::create_dir_all(path.join(".kobo")).unwrap();
+        fs::write(path.join(DEVICE_DB_PATH), "").unwrap();
     }
 
     #[test]
-    fn assign_highlights_exact_match() {
-        let toc = make_toc(&[
-            ("Chapter I", "book!ch01.xhtml#ch01", 1),
-            ("Section 1", "book!ch01.xhtml#ch01_1", 2),
+    fn find_kobo_devices_searches_two_levels_below_roots() {
+        let volumes = tempfile::tempdir().unwrap();
+        let media = tempfile::tempdir().unwrap();
+        make_device(&volumes.path().join("KOBOeReader"));
+        make_device(&media.path().join("alice").join("KOBOeReader"));
+        fs::create_dir_all(volumes.path().join("USB").join(".kobo")).unwrap();
+
+        let devices = find_kobo_devices(&[
+            volumes.path().to_path_buf(),
+            media.path().to_path_buf(),
+            PathBuf::from("/does/not/exist"),
         ]);
-        let highlights = vec![make_highlight("hello", "book!ch01.xhtml#ch01_1")];
 
-        let (assigned, uncategorized) = assign_highlights(&toc, &highlights);
-        assert_eq!(assigned.get(&1).unwrap().len(), 1);
-        assert!(uncategorized.is_empty());
+        let mut expected = vec![
+            volumes.path().join("KOBOeReader"),
+            media.path().join("alice").join("KOBOeReader"),
+        ];
+        expected.sort();
+        assert_eq!(devices, expected);
     }
 
     #[test]
-    fn assign_highlights_unmatched_goes_to_uncategorized() {
-        let toc = make_toc(&[("Chapter I", "book!ch01.xhtml#ch01", 1)]);
-        let highlights = vec![make_highlight("hello", "book!ch99.xhtml#unknown")];
-
-        let (assigned, uncategorized) = assign_highlights(&toc, &highlights);
-        assert!(assigned.is_empty());
-        assert_eq!(uncategorized.len(), 1);
+    fn find_kobo_devices_ignores_deeper_directories() {
+        let root = tempfile::tempdir().unwrap();
+        make_device(&root.path().join("a").join("b").join("c"));
+        assert!(find_kobo_devices(&[root.path().to_path_buf()]).is_empty());
     }
 
-    #[test]
-    fn assign_highlights_multiple_to_same_section() {
-        let toc = make_toc(&[("Section", "book!ch01.xhtml#sec1", 3)]);
-        let highlights = vec![
-            make_highlight("first", "book!ch01.xhtml#sec1"),
-            make_highlight("second", "book!ch01.xhtml#sec1"),
-        ];
+    // --- parse_date ---
 
-        let (assigned, _) = assign_highlights(&toc, &highlights);
-        assert_eq!(assigned.get(&0).unwrap().len(), 2);
+    #[test]
+    fn parse_date_accepts_iso_date() {
+        assert_eq!(parse_date("2024-06-01").unwrap(), "2024-06-01");
     }
 
-    // --- generate_markdown ---
-
     #[test]
-    fn generate_markdown_basic_structure() {
-        let book = Book {
-            content_id: "book1".into(),
-            title: "Test Book".into(),
-            author: Some("Author Name".into()),
-        };
-        let toc = make_toc(&[
-            ("Chapter I", "book!ch01.xhtml#ch01", 1),
-            ("Section 1", "book!ch01.xhtml#sec1", 2),
-        ]);
-        let highlights = vec![make_highlight("Important text", "book!ch01.xhtml#sec1")];
-
-        let md = generate_markdown(&book, &toc, &highlights);
-        assert!(md.starts_with("# Test Book\n"));
-        assert!(md.contains("**Author:** Author Name"));
-        assert!(md.contains("## Chapter I\n"));
-        assert!(md.contains("### Section 1\n"));
-        assert!(md.contains("> Important text\n"));
+    fn parse_date_rejects_malformed() {
+        assert!(parse_date("2024-6-1").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("01/06/2024").is_err());
+        assert!(parse_date("").is_err());
     }
 
     #[test]
-    fn generate_markdown_parent_chapter_emitted_for_subsection_highlights() {
-        let toc = make_toc(&[
-            ("KAPITEL I", "book!ch01.xhtml#ch01", 1),
-            ("1. Abschnitt", "book!ch01.xhtml#ch01_1", 2),
-            ("KAPITEL II", "book!ch02.xhtml#ch02", 1),
-            ("1. Abschnitt", "book!ch02.xhtml#ch02_1", 2),
-        ]);
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
-        };
-        // Only highlight in chapter I sub-section
-        let highlights = vec![make_highlight("text", "book!ch01.xhtml#ch01_1")];
-
-        let md = generate_markdown(&book, &toc, &highlights);
-        // Chapter I heading must appear even though only sub-section has highlights
-        assert!(md.contains("## KAPITEL I\n"));
-        assert!(md.contains("### 1. Abschnitt\n"));
-        // Chapter II should NOT appear (no highlights)
-        assert!(!md.contains("## KAPITEL II"));
+    fn parse_date_format_rejects_invalid_specifier() {
+        assert!(parse_date_format("%Y-%m-%d").is_ok());
+        assert!(parse_date_format("%Q").is_err());
     }
 
     #[test]
-    fn generate_markdown_multi_level_hierarchy() {
-        let toc = make_toc(&[
-            ("The Enchanted Forest", "book!forest.html#id_1", 1),
-            ("I. The Crystal Cave", "book!forest.html#id_2", 2),
-            ("1. The Hidden Door", "book!forest.html#id_3", 3),
-            ("a) The Silver Key", "book!forest.html#id_4", 4),
-            ("II. The Mountain Pass", "book!forest.html#id_5", 2),
-        ]);
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
-        };
-        let highlights = vec![make_highlight("deep text", "book!forest.html#id_4")];
-
-        let md = generate_markdown(&book, &toc, &highlights);
-        // All ancestors should be emitted (depth+1 = heading level)
-        assert!(md.contains("## The Enchanted Forest\n"));
-        assert!(md.contains("### I. The Crystal Cave\n"));
-        assert!(md.contains("#### 1. The Hidden Door\n"));
-        assert!(md.contains("##### a) The Silver Key\n"));
-        // Section II should NOT appear (no highlights)
-        assert!(!md.contains("II. The Mountain Pass"));
-    }
-
-    #[test]
-    fn generate_markdown_separate_files_hierarchy() {
-        // Each entry in its own file, depth from suffix
-        let toc = make_toc(&[
-            ("Part One: The Dawn", "book!_1h_1.xhtml", 2),
-            ("1. The Awakening", "book!_1h_2.xhtml", 3),
-            ("2. The First Light", "book!_1h_3.xhtml", 3),
-            ("Part Two: The Dusk", "book!_1h_7.xhtml", 2),
-            ("1. The Fading Star", "book!_1h_8.xhtml", 3),
-        ]);
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
-        };
-        let highlights = vec![make_highlight("text", "book!_1h_2.xhtml")];
-
-        let md = generate_markdown(&book, &toc, &highlights);
-        assert!(md.contains("### Part One: The Dawn\n"));
-        assert!(md.contains("#### 1. The Awakening\n"));
-        // Part Two should NOT appear
-        assert!(!md.contains("Part Two: The Dusk"));
-        // Other sections without highlights should not appear
-        assert!(!md.contains("2. The First Light"));
+    fn parse_date_format_rejects_time_zone() {
+        for pattern in ["%z", "%Z", "%:z", "%Y-%m-%d %z"] {
+            assert!(parse_date_format(pattern).is_err(), "{pattern}");
+        }
     }
 
     #[test]
-    fn generate_markdown_uncategorized_section() {
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
-        };
-        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
-        let highlights = vec![make_highlight("orphan", "book!unknown.xhtml#x")];
+    fn parse_color_rejects_unknown_names() {
+        assert_eq!(parse_color("Blue"), Ok(2));
+        assert!(parse_color("purple").is_err());
+    }
+
+    // --- database access ---
 
-        let md = generate_markdown(&book, &toc, &highlights);
-        assert!(md.contains("## Uncategorized\n"));
-        assert!(md.contains("> orphan\n"));
+    #[test]
+    fn temp_copy_is_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("KoboReader.sqlite");
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE content (ContentID TEXT);")
+            .unwrap();
+
+        let copy = TempCopy::new(&db_path).unwrap();
+        let copy_path = copy.0.clone();
+        assert!(open_database(&copy_path, true).is_ok());
+        drop(copy);
+        assert!(!copy_path.exists());
+        assert!(db_path.exists());
     }
 
     #[test]
-    fn generate_markdown_no_uncategorized_when_all_matched() {
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
+    fn open_database_reads_write_ahead_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("KoboReader.sqlite");
+        let writer = Connection::open(&db_path).unwrap();
+        writer
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA wal_autocheckpoint = 0;
+                 CREATE TABLE Bookmark (BookmarkID TEXT);
+                 INSERT INTO Bookmark VALUES ('recent');",
+            )
+            .unwrap();
+        // The writer stays open, so the row is only in the -wal file
+        assert!(wal_path(&db_path).exists());
+
+        let count = |conn: Connection| {
+            conn.query_row("SELECT COUNT(*) FROM Bookmark", [], |row| {
+                row.get::<_, i64>(0)
+            })
         };
-        let toc = make_toc(&[("Ch", "book!ch01.xhtml#ch01", 1)]);
-        let highlights = vec![make_highlight("matched", "book!ch01.xhtml#ch01")];
+        assert_eq!(count(open_database(&db_path, false).unwrap()).unwrap(), 1);
+        assert!(count(open_database(&db_path, true).unwrap()).is_err());
 
-        let md = generate_markdown(&book, &toc, &highlights);
-        assert!(!md.contains("Uncategorized"));
+        let copy = TempCopy::new(&db_path).unwrap();
+        assert_eq!(count(open_database(&copy.0, false).unwrap()).unwrap(), 1);
+        drop(writer);
     }
 
     #[test]
-    fn generate_markdown_highlight_with_annotation_and_date() {
-        let book = Book {
-            content_id: "b".into(),
-            title: "T".into(),
-            author: None,
-        };
-        let toc = make_toc(&[("Ch", "id", 1)]);
-        let highlights = vec![Highlight {
-            text: "highlighted".into(),
-            annotation: Some("my note".into()),
-            chapter_content_id: "id".into(),
-            date_created: Some("2024-06-01".into()),
-        }];
-
-        let md = generate_markdown(&book, &toc, &highlights);
-        assert!(md.contains("> highlighted\n"));
-        assert!(md.contains("**Note:** my note"));
-        assert!(md.contains("*2024-06-01*"));
-    }
-
-    // --- DB integration test with in-memory SQLite ---
-
-    fn create_test_db() -> Connection {
-        let conn = Connection::open_in_memory().unwrap();
-        conn.execute_batch(
-            "CREATE TABLE content (
-                ContentID TEXT NOT NULL,
-                ContentType TEXT NOT NULL,
-                BookID TEXT,
-                Title TEXT,
-                Attribution TEXT,
-                VolumeIndex INTEGER DEFAULT 0
+    fn database_uri_escapes_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = fs::canonicalize(dir.path()).unwrap();
+        let folder = base.join("My Kobo #1");
+        fs::create_dir(&folder).unwrap();
+        let db_path = folder.join("Kobo?Reader%.sqlite");
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE content (ContentID TEXT);")
+            .unwrap();
+
+        let uri = database_uri(&db_path, true);
+        assert!(
+            uri.ends_with("/My%20Kobo%20%231/Kobo%3FReader%25.sqlite?immutable=1"),
+            "{uri}"
+        );
+        assert!(uri.starts_with("file:"), "{uri}");
+        assert!(open_database(&db_path, true).is_ok());
+        if cfg!(unix) {
+            assert_eq!(
+                database_uri(
+                    Path::new("/mnt/KOBOeReader/.kobo/Kobo Reader.sqlite"),
+                    false
+                ),
+                "file:///mnt/KOBOeReader/.kobo/Kobo%20Reader.sqlite"
             );
-            CREATE TABLE Bookmark (
-                BookmarkID TEXT NOT NULL,
-                VolumeID TEXT NOT NULL,
-                ContentID TEXT NOT NULL,
-                Text TEXT,
-                Annotation TEXT,
-                DateCreated TEXT,
-                ChapterProgress REAL DEFAULT 0,
-                Hidden BOOL DEFAULT 0
-            );",
-        )
-        .unwrap();
-        conn
+        }
     }
 
+    #[cfg(unix)]
     #[test]
-    fn db_query_books() {
-        let conn = create_test_db();
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, Attribution)
-             VALUES ('book1', '6', NULL, 'Blue Lantern', 'Nora Finch')",
-            [],
-        )
-        .unwrap();
+    fn open_database_follows_symlink_to_write_ahead_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("KoboReader.sqlite");
+        let writer = Connection::open(&db_path).unwrap();
+        writer
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA wal_autocheckpoint = 0;
+                 CREATE TABLE Bookmark (BookmarkID TEXT);
+                 INSERT INTO Bookmark VALUES ('recent');",
+            )
+            .unwrap();
+        let link = dir.path().join("link.sqlite");
+        std::os::unix::fs::symlink(&db_path, &link).unwrap();
+
+        // Only found when the -wal file next to the target is noticed
+        let conn = open_database(&link, false).unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM Bookmark", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        drop(writer);
+    }
 
-        let books = query_books(&conn).unwrap();
-        assert_eq!(books.len(), 1);
-        assert_eq!(books[0].title, "Blue Lantern");
-        assert_eq!(books[0].author.as_deref(), Some("Nora Finch"));
+    #[test]
+    fn is_locked_detects_busy_database() {
+        let busy = rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            None,
+        );
+        assert!(is_locked(&busy));
+        assert!(!is_locked(&rusqlite::Error::InvalidQuery));
     }
 
+    // --- writing output ---
+
     #[test]
-    fn db_query_books_skips_chapters() {
-        let conn = create_test_db();
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title)
-             VALUES ('book1', '6', NULL, 'Book')",
-            [],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title)
-             VALUES ('ch1', '9', 'book1', 'Chapter file')",
-            [],
+    fn write_output_adds_files_to_archive() {
+        use std::io::Read;
+        let mut archive = Some(ZipWriter::new(Cursor::new(Vec::new())));
+        write_output(
+            &mut archive,
+            Path::new("highlights/Author/Book.md"),
+            "# Book\n",
         )
         .unwrap();
-
-        let books = query_books(&conn).unwrap();
-        assert_eq!(books.len(), 1);
+        write_output(&mut archive, Path::new("highlights/Ünïcode.md"), "").unwrap();
+        let bytes = archive.unwrap().finish().unwrap().into_inner();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(zip.len(), 2);
+        let mut contents = String::new();
+        zip.by_name("highlights/Author/Book.md")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "# Book\n");
+        assert!(zip.by_name("highlights/Ünïcode.md").is_ok());
     }
 
     #[test]
-    fn db_query_toc_hierarchy() {
-        let conn = create_test_db();
-        // Chapter file (type 9) — should be ignored
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
-             VALUES ('book!Chapter01.xhtml', '9', 'book1', 'Chapter01.xhtml', 0)",
-            [],
-        )
-        .unwrap();
-        // Chapter heading (type 899, depth 2 = part level)
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
-             VALUES ('book!Chapter01.xhtml#ch01-2', '899', 'book1', 'I. KAPITEL', 0)",
-            [],
-        )
-        .unwrap();
-        // Sub-section (type 899, depth 3 = section level)
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
-             VALUES ('book!Chapter01.xhtml#ch01_1-3', '899', 'book1', '1. Section', 1)",
-            [],
-        )
-        .unwrap();
+    fn write_book_output_skips_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Book.md");
+        fs::write(&path, "my edits").unwrap();
 
-        let toc = query_toc(&conn, "book1").unwrap();
-        assert_eq!(toc.len(), 2);
+        assert!(!write_book_output(&mut None, &path, "generated", true).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "my edits");
 
-        assert_eq!(toc[0].title, "I. KAPITEL");
-        assert_eq!(toc[0].depth, 2);
-        assert_eq!(toc[0].match_id, "book!Chapter01.xhtml#ch01");
+        assert!(write_book_output(&mut None, &path, "generated", false).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "generated");
 
-        assert_eq!(toc[1].title, "1. Section");
-        assert_eq!(toc[1].depth, 3);
-        assert_eq!(toc[1].match_id, "book!Chapter01.xhtml#ch01_1");
+        let new_path = dir.path().join("New.md");
+        assert!(write_book_output(&mut None, &new_path, "generated", true).unwrap());
+        assert!(new_path.exists());
     }
 
     #[test]
-    fn db_query_highlights() {
-        let conn = create_test_db();
-        conn.execute(
-            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, Annotation, DateCreated, ChapterProgress)
-             VALUES ('bm1', 'book1', 'book!ch01.xhtml#sec1', 'highlighted text', 'my note', '2024-01-15', 0.5)",
-            [],
-        )
-        .unwrap();
-        // Dogear bookmark (no text) — should be excluded
-        conn.execute(
-            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, ChapterProgress)
-             VALUES ('bm2', 'book1', 'book!ch01.xhtml#sec2', NULL, 0.8)",
-            [],
-        )
-        .unwrap();
+    fn create_export_database_refuses_to_replace_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("KoboReader.sqlite");
+        fs::write(&db_path, "kobo").unwrap();
+
+        let same = dir.path().join(".").join("KoboReader.sqlite");
+        assert!(create_export_database(&same, &db_path).is_err());
+        assert_eq!(fs::read_to_string(&db_path).unwrap(), "kobo");
+        assert!(create_export_database(dir.path(), &db_path).is_err());
+
+        let export = dir.path().join("export.sqlite");
+        fs::write(&export, "old export").unwrap();
+        assert!(create_export_database(&export, &db_path).is_ok());
+    }
 
-        let highlights = query_highlights(&conn, "book1").unwrap();
-        assert_eq!(highlights.len(), 1);
-        assert_eq!(highlights[0].text, "highlighted text");
-        assert_eq!(highlights[0].annotation.as_deref(), Some("my note"));
+    #[test]
+    fn run_report_lists_skipped_and_failed_books() {
+        let report = RunReport {
+            timestamp: "2024-06-01T10:00:00Z".into(),
+            books_found: 3,
+            books_exported: 1,
+            books_skipped: vec![SkippedBook {
+                title: "Manual",
+                reason: "no highlights",
+            }],
+            books_failed: vec![FailedBook {
+                title: "Broken",
+                error: "database disk image is malformed".into(),
+            }],
+            highlights: 12,
+        };
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["books_found"], 3);
+        assert_eq!(json["books_skipped"][0]["reason"], "no highlights");
+        assert_eq!(json["books_failed"][0]["title"], "Broken");
+        assert_eq!(json["highlights"], 12);
     }
 
+    // --- archive_name ---
+
     #[test]
-    fn db_end_to_end() {
-        let conn = create_test_db();
+    fn archive_name_uses_slashes_without_root() {
+        assert_eq!(
+            archive_name(Path::new("/home/me/notes/Author/Book.md")),
+            "home/me/notes/Author/Book.md"
+        );
+        assert_eq!(
+            archive_name(Path::new("./highlights/Book.md")),
+            "highlights/Book.md"
+        );
+    }
 
-        // Book
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, Attribution)
-             VALUES ('book1', '6', NULL, 'The Paper Orchard', 'Samir Hale')",
-            [],
-        )
-        .unwrap();
+    // --- sqlite_path ---
 
-        // TOC entries (899 only matter)
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
-             VALUES ('book1!ch01.xhtml#ch01-1', '899', 'book1', 'I. Chapter Seven', 0)",
-            [],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO content (ContentID, ContentType, BookID, Title, VolumeIndex)
-             VALUES ('book1!ch01.xhtml#ch01_1-2', '899', 'book1', '1. Abschnitt', 1)",
-            [],
-        )
-        .unwrap();
+    #[test]
+    fn sqlite_path_adds_missing_extension() {
+        assert_eq!(
+            sqlite_path(Path::new("highlights")),
+            Path::new("highlights.sqlite")
+        );
+        assert_eq!(
+            sqlite_path(Path::new("out/notes.db")),
+            Path::new("out/notes.db")
+        );
+    }
 
-        // Highlight matching the sub-section
-        conn.execute(
-            "INSERT INTO Bookmark (BookmarkID, VolumeID, ContentID, Text, ChapterProgress)
-             VALUES ('bm1', 'book1', 'book1!ch01.xhtml#ch01_1', 'A curious passage about seasons', 0.1)",
-            [],
-        )
-        .unwrap();
+    // --- format_size ---
 
-        let books = query_books(&conn).unwrap();
-        assert_eq!(books.len(), 1);
+    #[test]
+    fn format_size_picks_unit() {
+        assert_eq!(format_size(812), "812 bytes");
+        assert_eq!(format_size(14_540), "14.2 KB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MB");
+    }
+
+    // --- incremental state ---
 
-        let toc = query_toc(&conn, &books[0].content_id).unwrap();
-        let highlights = query_highlights(&conn, &books[0].content_id).unwrap();
-        let md = generate_markdown(&books[0], &toc, &highlights);
+    #[test]
+    fn state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_state(dir.path()).unwrap(), None);
 
-        assert!(md.contains("# The Paper Orchard\n"));
-        assert!(md.contains("**Author:** Samir Hale"));
-        assert!(md.contains("## I. Chapter Seven\n"));
-        assert!(md.contains("### 1. Abschnitt\n"));
-        assert!(md.contains("> A curious passage about seasons\n"));
-        assert!(!md.contains("Uncategorized"));
+        write_state(dir.path(), "2024-06-01T10:00:00").unwrap();
+        assert_eq!(
+            read_state(dir.path()).unwrap().as_deref(),
+            Some("2024-06-01T10:00:00")
+        );
     }
 }